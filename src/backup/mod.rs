@@ -0,0 +1,509 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cancellation::{CancellationToken, Cancelled};
+use crate::index::lock::{Lock, LockKind};
+use crate::progress::{Event, NoProgress, Observer};
+use crate::repository::Repository;
+use anyhow::Result;
+use anyhow::*;
+use fail::fail_point;
+use pipeline::Next;
+
+mod pipeline;
+mod stream;
+
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// decides how often the index is persisted while a backup is running,
+/// a checkpoint is made as soon as any of the set limits is reached
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointPolicy {
+    pub files: Option<u64>,
+    pub bytes: Option<u64>,
+    pub interval: Option<Duration>,
+}
+
+impl CheckpointPolicy {
+    pub fn never() -> Self {
+        CheckpointPolicy {
+            files: None,
+            bytes: None,
+            interval: None,
+        }
+    }
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        CheckpointPolicy {
+            files: Some(1000),
+            bytes: Some(1024 * 1024 * 1024),
+            interval: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+/// sizes of the thread pools and queues of the backup pipeline
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Concurrency {
+    /// threads walking the source directory, each lists one directory at a time
+    pub scanners: usize,
+    /// threads reading and hashing file contents, long files are also written into the repository by these
+    pub workers: usize,
+    /// threads copying new contents into the repository
+    pub writers: usize,
+    /// how many hashed files can wait for a writer
+    pub write_queue: usize,
+}
+
+impl Concurrency {
+    pub fn sequential() -> Self {
+        Concurrency {
+            scanners: 1,
+            workers: 1,
+            writers: 1,
+            write_queue: 1,
+        }
+    }
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        Concurrency {
+            scanners: 2,
+            workers: cpus,
+            writers: 2,
+            write_queue: cpus * 4,
+        }
+    }
+}
+
+struct CheckpointTracker {
+    policy: CheckpointPolicy,
+    files: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl CheckpointTracker {
+    fn new(policy: CheckpointPolicy) -> Self {
+        CheckpointTracker {
+            policy,
+            files: 0,
+            bytes: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+
+    fn due(&self) -> bool {
+        self.policy.files.map_or(false, |files| self.files >= files)
+            || self.policy.bytes.map_or(false, |bytes| self.bytes >= bytes)
+            || self
+                .policy
+                .interval
+                .map_or(false, |interval| self.since.elapsed() >= interval)
+    }
+
+    fn reset(&mut self) {
+        self.files = 0;
+        self.bytes = 0;
+        self.since = Instant::now();
+    }
+}
+
+pub struct Engine<'a> {
+    source_path: &'a Path,
+    repository: &'a mut Repository,
+    checkpoint_policy: CheckpointPolicy,
+    cancellation: CancellationToken,
+    concurrency: Concurrency,
+    observer: Arc<dyn Observer>,
+    /// keeps `prune` out while contents are written that the index does not know of yet
+    _operation_lock: Lock,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(source_path: &'a Path, repository: &'a mut Repository) -> Result<Self> {
+        let mut ancestors = vec![];
+        let mut current = Some(source_path.to_path_buf());
+        while let Some(path) = current {
+            ancestors.push(path.to_path_buf());
+            current = path.parent().map(|p| p.to_path_buf());
+        }
+        if ancestors.into_iter().any(|a| a == repository.path()) {
+            return Err(anyhow!("source same as repository"));
+        }
+        let operation_lock = repository.operation_lock(LockKind::Shared)?;
+        Ok(Engine {
+            source_path,
+            repository,
+            checkpoint_policy: CheckpointPolicy::default(),
+            cancellation: CancellationToken::new(),
+            concurrency: Concurrency::default(),
+            observer: Arc::new(NoProgress),
+            _operation_lock: operation_lock,
+        })
+    }
+
+    pub fn with_checkpoint_policy(mut self, checkpoint_policy: CheckpointPolicy) -> Self {
+        self.checkpoint_policy = checkpoint_policy;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: Concurrency) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// stores all files under the source path, resuming after the last checkpoint
+    /// if a previous backup of the same source path did not finish.
+    /// Files are processed on a pool of threads but added to the index in the sorted walk order.
+    /// When cancelled, checkpoints the progress made so far and fails with `Cancelled`,
+    /// otherwise finishes with a snapshot of the source path, see `Repository::snapshot`
+    pub fn backup(&mut self) -> Result<()> {
+        let resume_after = self.repository.checkpoint_for(self.source_path);
+        if let Some(resume_after) = &resume_after {
+            log::info!(
+                "resuming unfinished backup of {} after {}",
+                self.source_path.to_string_lossy(),
+                resume_after.to_string_lossy()
+            );
+        }
+
+        let data_store = self.repository.data_store();
+        let source_path = self.source_path;
+        let repository = &mut *self.repository;
+        let cancellation = &self.cancellation;
+        let observer = self.observer.as_ref();
+        let mut tracker = CheckpointTracker::new(self.checkpoint_policy);
+        let mut last_stored: Option<PathBuf> = None;
+        let mut stored_paths = vec![];
+        pipeline::run(
+            source_path,
+            resume_after.as_deref(),
+            &data_store,
+            self.concurrency,
+            observer,
+            |results| loop {
+                if cancellation.is_cancelled() {
+                    if let Some(last_stored) = &last_stored {
+                        repository.checkpoint(source_path, last_stored)?;
+                    }
+                    return Err(Cancelled.into());
+                }
+                let stored = match results.next(CANCELLATION_POLL_INTERVAL)? {
+                    Next::Finished => return Ok(()),
+                    Next::Pending => continue,
+                    Next::Ready(Result::Ok(stored)) => stored,
+                    Next::Ready(Err(failure)) => {
+                        let path = failure.path.to_string_lossy().to_string();
+                        observer.on_event(&Event::Error {
+                            path: path.clone(),
+                            message: format!("{:#}", failure.error),
+                        });
+                        return Err(failure.error.context(format!("cannot back up {}", path)));
+                    }
+                };
+                repository.remember(&stored)?;
+                let path = stored.source_path.to_string_lossy().to_string();
+                if stored.deduplicated {
+                    observer.on_event(&Event::DeduplicationHit {
+                        path: path.clone(),
+                        bytes: stored.metadata.size,
+                    });
+                }
+                observer.on_event(&Event::FileFinished {
+                    path,
+                    bytes: stored.metadata.size,
+                });
+                tracker.record(stored.metadata.size);
+                if tracker.due() {
+                    repository.checkpoint(source_path, &stored.source_path)?;
+                    tracker.reset();
+                }
+                stored_paths.push(stored.source_path.clone());
+                last_stored = Some(stored.source_path);
+                fail_point!("backup-after-store", |e: Option<String>| Err(anyhow!(e.unwrap())));
+            },
+        )?;
+        self.repository.finish_checkpoint(self.source_path);
+        self.repository.save_index()?;
+        // files stored by the interrupted backup being resumed are part of this one, the newest versions
+        // of paths it went past also include files removed before it got to them, or since
+        if let Some(resume_after) = &resume_after {
+            for item in self.repository.newest_items() {
+                let path = PathBuf::from(item?.original_source_path());
+                if path.starts_with(self.source_path) && path <= *resume_after && fs::symlink_metadata(&path).is_ok() {
+                    stored_paths.push(path);
+                }
+            }
+        }
+        self.repository.snapshot(self.source_path, &stored_paths)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod must {
+    #[cfg(feature = "failpoints")]
+    use super::CheckpointPolicy;
+    use super::{Concurrency, Engine};
+    use crate::cancellation::{CancellationToken, Cancelled};
+    use crate::progress::{Event, Observer};
+    use crate::repository::Repository;
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    #[cfg(feature = "failpoints")]
+    use std::{fs, path::Path};
+    use tempfile::tempdir;
+    #[cfg(feature = "failpoints")]
+    use two_rusty_forks::rusty_fork_test;
+
+    #[test]
+    fn skip_files_up_to_checkpoint_when_resuming() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "a")?;
+        source.write_text_to_file("b", "b")?;
+        source.write_text_to_file("c", "c")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        repository.checkpoint(source.path(), &source.file_path("b")?)?;
+
+        Engine::new(source.path(), &mut repository)?.backup()?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        assert!(repository.newest_item_by_source_path(&source.file_path("a")?)?.is_none());
+        assert!(repository.newest_item_by_source_path(&source.file_path("b")?)?.is_none());
+        assert!(repository.newest_item_by_source_path(&source.file_path("c")?)?.is_some());
+        assert_eq!(repository.checkpoint_for(source.path()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_in_walk_order_with_many_threads() -> Result<()> {
+        let source = TestSource::new()?;
+        let names = ["a/a", "a/b/a", "a/b/b", "a/c", "b", "c/a", "c/b"];
+        for name in names {
+            source.write_text_to_file(name, name)?;
+        }
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        repository.checkpoint(source.path(), &source.file_path("a/b/a")?)?;
+        let concurrency = Concurrency {
+            scanners: 4,
+            workers: 4,
+            writers: 4,
+            write_queue: 1,
+        };
+
+        Engine::new(source.path(), &mut repository)?
+            .with_concurrency(concurrency)
+            .backup()?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let stored = names
+            .into_iter()
+            .filter(|name| {
+                repository
+                    .newest_item_by_source_path(&source.file_path(name).unwrap())
+                    .unwrap()
+                    .is_some()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(stored, vec!["a/b/b", "a/c", "b", "c/a", "c/b"]);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl Observer for RecordingObserver {
+        fn on_event(&self, event: &Event) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn report_progress_and_deduplication_hits() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "same contents")?;
+        source.write_text_to_file("b", "same contents")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let observer = Arc::new(RecordingObserver::default());
+
+        Engine::new(source.path(), &mut repository)?
+            .with_concurrency(Concurrency::sequential())
+            .with_observer(observer.clone())
+            .backup()?;
+
+        let events = observer.events.lock().unwrap();
+        let b = source.file_path("b")?.to_string_lossy().to_string();
+        assert!(events.contains(&Event::ScanFinished { files: 2, bytes: 26 }));
+        assert!(events.contains(&Event::DeduplicationHit {
+            path: b.clone(),
+            bytes: 13
+        }));
+        let finished = events.iter().filter(|e| matches!(e, Event::FileFinished { .. })).count();
+        assert_eq!(finished, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn read_files_once_whether_buffered_or_not() -> Result<()> {
+        let source = TestSource::new()?;
+        let long = (0..2 * 1024 * 1024 + 1).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        source.write_bytes_to_file("a/long", &long)?;
+        source.write_bytes_to_file("b/long", &long)?;
+        source.write_text_to_file("short", "short")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let observer = Arc::new(RecordingObserver::default());
+
+        Engine::new(source.path(), &mut repository)?
+            .with_concurrency(Concurrency::sequential())
+            .with_observer(observer.clone())
+            .backup()?;
+
+        let events = observer.events.lock().unwrap();
+        let read = events
+            .iter()
+            .map(|e| match e {
+                Event::BytesProcessed { bytes } => *bytes,
+                _ => 0,
+            })
+            .sum::<u64>();
+        assert_eq!(read, 2 * long.len() as u64 + 5);
+        assert!(events.contains(&Event::DeduplicationHit {
+            path: source.file_path("b/long")?.to_string_lossy().to_string(),
+            bytes: long.len() as u64
+        }));
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let mut stored = vec![];
+        repository
+            .newest_item_by_source_path(&source.file_path("b/long")?)?
+            .unwrap()
+            .reader()?
+            .read_to_end(&mut stored)?;
+        assert_eq!(stored, long);
+        Ok(())
+    }
+
+    #[test]
+    fn stop_without_storing_anything_when_cancelled() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "a")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let result = Engine::new(source.path(), &mut repository)?
+            .with_cancellation(cancellation)
+            .backup();
+
+        assert!(result.unwrap_err().is::<Cancelled>());
+        let repository = Repository::open(repository_path.path(), secret)?;
+        assert!(repository.newest_item_by_source_path(&source.file_path("a")?)?.is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "failpoints")]
+    rusty_fork_test! {
+        #[test]
+        fn resume_backup_interrupted_after_checkpoint() {
+            let source = TestSource::new().unwrap();
+            for name in ["a", "b", "c", "d"] {
+                source.write_text_to_file(name, name).unwrap();
+            }
+            let repository_path = tempdir().unwrap();
+            let secret = "some secret";
+            Repository::init(repository_path.path(), secret).unwrap();
+
+            fail::cfg("backup-after-store", "2*off->return(interrupted)").unwrap();
+            {
+                let mut repository = Repository::open(repository_path.path(), secret).unwrap();
+                let policy = CheckpointPolicy { files: Some(1), ..CheckpointPolicy::never() };
+                let mut engine = Engine::new(source.path(), &mut repository).unwrap().with_checkpoint_policy(policy);
+                assert!(engine.backup().is_err());
+            }
+            fail::cfg("backup-after-store", "off").unwrap();
+
+            let mut repository = Repository::open(repository_path.path(), secret).unwrap();
+            assert_eq!(repository.checkpoint_for(source.path()), Some(source.file_path("c").unwrap()));
+            assert!(repository.newest_item_by_source_path(&source.file_path("c").unwrap()).unwrap().is_some());
+            assert!(repository.newest_item_by_source_path(&source.file_path("d").unwrap()).unwrap().is_none());
+
+            Engine::new(source.path(), &mut repository).unwrap().backup().unwrap();
+
+            let repository = Repository::open(repository_path.path(), secret).unwrap();
+            assert!(repository.newest_item_by_source_path(&source.file_path("d").unwrap()).unwrap().is_some());
+            assert_eq!(repository.checkpoint_for(source.path()), None);
+        }
+
+        #[test]
+        fn leave_files_removed_before_resuming_out_of_snapshot() {
+            let source = TestSource::new().unwrap();
+            for name in ["a", "b", "c", "d"] {
+                source.write_text_to_file(name, name).unwrap();
+            }
+            let repository_path = tempdir().unwrap();
+            let secret = "some secret";
+            Repository::init(repository_path.path(), secret).unwrap();
+            let mut repository = Repository::open(repository_path.path(), secret).unwrap();
+            Engine::new(source.path(), &mut repository).unwrap().backup().unwrap();
+            fs::remove_file(source.file_path("a").unwrap()).unwrap();
+
+            fail::cfg("backup-after-store", "1*off->return(interrupted)").unwrap();
+            {
+                let policy = CheckpointPolicy { files: Some(1), ..CheckpointPolicy::never() };
+                let mut engine = Engine::new(source.path(), &mut repository).unwrap().with_checkpoint_policy(policy);
+                assert!(engine.backup().is_err());
+            }
+            fail::cfg("backup-after-store", "off").unwrap();
+            assert_eq!(repository.checkpoint_for(source.path()), Some(source.file_path("c").unwrap()));
+            Engine::new(source.path(), &mut repository).unwrap().backup().unwrap();
+
+            let snapshots = repository.snapshots().unwrap();
+            let listed = repository.list_snapshot_directory(&snapshots[1], Path::new("")).unwrap();
+            let names = listed.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            assert_eq!(snapshots.len(), 2);
+            assert_eq!(names, vec!["b", "c", "d"]);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+
+use crate::progress::{Event, Observer};
+use crate::repository::{
+    data::{DataStore, StoredData},
+    ItemId,
+};
+
+use super::Concurrency;
+
+/// position of an entry in the sorted walk: its index among its siblings at every level below the source path,
+/// so that keys sort in the same order a sequential sorted walk would visit the entries
+type Key = Vec<usize>;
+
+/// files up to this long are read into memory once, hashed and handed over to a writer as they are,
+/// longer ones are hashed while they are written into the repository
+const BUFFERED_FILE_LENGTH: u64 = 1024 * 1024;
+
+/// how long scanners wait for a directory to list before checking whether the walk is over
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Scanned {
+    key: Key,
+    path: PathBuf,
+    length: u64,
+}
+
+struct Hashed {
+    key: Key,
+    path: PathBuf,
+    id: ItemId,
+    contents: Vec<u8>,
+}
+
+/// file that could not be backed up
+pub struct Failure {
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+/// what an entry of a listed directory turned out to be
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Entry {
+    File,
+    Directory,
+    /// neither a file nor a directory, or stored before the checkpoint being resumed from
+    Skipped,
+}
+
+enum Message {
+    Stored(Key, Result<StoredData, Failure>),
+    Listed(Key, Result<Vec<Entry>, Failure>),
+}
+
+pub enum Next {
+    Ready(Result<StoredData, Failure>),
+    Pending,
+    Finished,
+}
+
+/// directory being handed out by `OrderedResults`
+struct Frame {
+    key: Key,
+    entries: Vec<Entry>,
+    next: usize,
+}
+
+/// hands out stored files in the same order a sequential sorted walk would visit them,
+/// no matter in which order the workers finished
+pub struct OrderedResults {
+    receiver: Receiver<Message>,
+    /// directories entered, starting with one that holds just the source path
+    frames: Vec<Frame>,
+    stored: HashMap<Key, Result<StoredData, Failure>>,
+    listed: HashMap<Key, Result<Vec<Entry>, Failure>>,
+}
+
+impl OrderedResults {
+    pub fn next(&mut self, timeout: Duration) -> Result<Next> {
+        loop {
+            let frame = match self.frames.last_mut() {
+                Some(frame) => frame,
+                None => return Ok(Next::Finished),
+            };
+            let entry = match frame.entries.get(frame.next) {
+                Some(entry) => *entry,
+                None => {
+                    self.frames.pop();
+                    continue;
+                }
+            };
+            let mut key = frame.key.clone();
+            key.push(frame.next);
+            match entry {
+                Entry::Skipped => {
+                    frame.next += 1;
+                    continue;
+                }
+                Entry::File => {
+                    if let Some(result) = self.stored.remove(&key) {
+                        frame.next += 1;
+                        return Ok(Next::Ready(result));
+                    }
+                }
+                Entry::Directory => match self.listed.remove(&key) {
+                    Some(Ok(entries)) => {
+                        frame.next += 1;
+                        self.frames.push(Frame { key, entries, next: 0 });
+                        continue;
+                    }
+                    Some(Err(failure)) => {
+                        frame.next += 1;
+                        return Ok(Next::Ready(Err(failure)));
+                    }
+                    None => {}
+                },
+            }
+            match self.receiver.recv_timeout(timeout) {
+                Ok(Message::Stored(key, result)) => {
+                    self.stored.insert(key, result);
+                }
+                Ok(Message::Listed(key, listed)) => {
+                    self.listed.insert(key, listed);
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(Next::Pending),
+                Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("backup pipeline stopped unexpectedly")),
+            }
+        }
+    }
+}
+
+/// walks, hashes and stores all files under `source_path` on a pool of threads while `collect` consumes the results.
+/// Stops all threads once `collect` returns
+pub fn run<T, F>(
+    source_path: &Path,
+    resume_after: Option<&Path>,
+    data_store: &DataStore,
+    concurrency: Concurrency,
+    observer: &dyn Observer,
+    collect: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut OrderedResults) -> Result<T>,
+{
+    let (directories_sender, directories_receiver) = unbounded::<(Key, PathBuf)>();
+    let (scanned_sender, scanned_receiver) = bounded::<Scanned>(concurrency.workers.max(1) * 2);
+    let (hashed_sender, hashed_receiver) = bounded::<Hashed>(concurrency.write_queue);
+    let (results_sender, results_receiver) = unbounded::<Message>();
+    let scanners = concurrency.scanners.max(1);
+    let unlisted_directories = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let finished_scanners = AtomicUsize::new(0);
+    let scanned_files = AtomicU64::new(0);
+    let scanned_bytes = AtomicU64::new(0);
+    let walk = Walk {
+        resume_after,
+        directories: directories_sender,
+        unlisted_directories: &unlisted_directories,
+        scanned: scanned_sender,
+        results: results_sender.clone(),
+        files: &scanned_files,
+        bytes: &scanned_bytes,
+    };
+    let source_entry = if source_path.is_dir() {
+        walk.queue_directory(vec![0], source_path.to_path_buf())?
+    } else {
+        walk.queue(vec![0], source_path.to_path_buf())?
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..scanners {
+            let walk = walk.clone();
+            let directories_receiver = directories_receiver.clone();
+            let stopped = &stopped;
+            let finished_scanners = &finished_scanners;
+            scope.spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    match directories_receiver.recv_timeout(SCAN_POLL_INTERVAL) {
+                        Ok((key, path)) => {
+                            let listed = walk.list(key, &path);
+                            walk.unlisted_directories.fetch_sub(1, Ordering::SeqCst);
+                            if listed.is_err() {
+                                stopped.store(true, Ordering::SeqCst);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if walk.unlisted_directories.load(Ordering::SeqCst) == 0 {
+                                break;
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                if finished_scanners.fetch_add(1, Ordering::SeqCst) + 1 == scanners {
+                    observer.on_event(&Event::ScanFinished {
+                        files: walk.files.load(Ordering::SeqCst),
+                        bytes: walk.bytes.load(Ordering::SeqCst),
+                    });
+                }
+            });
+        }
+        for _ in 0..concurrency.workers.max(1) {
+            let scanned_receiver = scanned_receiver.clone();
+            let hashed_sender = hashed_sender.clone();
+            let results_sender = results_sender.clone();
+            scope.spawn(move || {
+                for scanned in scanned_receiver {
+                    observer.on_event(&Event::FileStarted {
+                        path: scanned.path.to_string_lossy().to_string(),
+                    });
+                    let on_bytes_read = |bytes| observer.on_event(&Event::BytesProcessed { bytes });
+                    let sent = if scanned.length <= BUFFERED_FILE_LENGTH {
+                        match data_store.read(&scanned.path, on_bytes_read) {
+                            Ok((id, contents)) => hashed_sender
+                                .send(Hashed {
+                                    key: scanned.key,
+                                    path: scanned.path,
+                                    id,
+                                    contents,
+                                })
+                                .is_ok(),
+                            Err(error) => {
+                                let failure = Failure {
+                                    path: scanned.path,
+                                    error,
+                                };
+                                results_sender.send(Message::Stored(scanned.key, Err(failure))).is_ok()
+                            }
+                        }
+                    } else {
+                        let result = data_store
+                            .store_with_progress(&scanned.path, on_bytes_read)
+                            .map_err(|error| Failure {
+                                path: scanned.path.clone(),
+                                error,
+                            });
+                        results_sender.send(Message::Stored(scanned.key, result)).is_ok()
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            });
+        }
+        for _ in 0..concurrency.writers.max(1) {
+            let hashed_receiver = hashed_receiver.clone();
+            let results_sender = results_sender.clone();
+            scope.spawn(move || {
+                for hashed in hashed_receiver {
+                    let result = data_store
+                        .put(&hashed.path, hashed.id, &hashed.contents)
+                        .map_err(|error| Failure {
+                            path: hashed.path.clone(),
+                            error,
+                        });
+                    if results_sender.send(Message::Stored(hashed.key, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // only the threads hold the channel ends from here on, so that they hang up on each other once done
+        drop((
+            walk,
+            directories_receiver,
+            scanned_receiver,
+            hashed_sender,
+            hashed_receiver,
+            results_sender,
+        ));
+
+        let mut results = OrderedResults {
+            receiver: results_receiver,
+            frames: vec![Frame {
+                key: vec![],
+                entries: vec![source_entry],
+                next: 0,
+            }],
+            stored: Default::default(),
+            listed: Default::default(),
+        };
+        let collected = collect(&mut results);
+        stopped.store(true, Ordering::SeqCst);
+        collected
+    })
+}
+
+/// what the threads walking the source path share, each of them lists one directory at a time
+#[derive(Clone)]
+struct Walk<'a> {
+    resume_after: Option<&'a Path>,
+    directories: Sender<(Key, PathBuf)>,
+    /// directories queued or being listed, the walk is over once there are none
+    unlisted_directories: &'a AtomicUsize,
+    scanned: Sender<Scanned>,
+    results: Sender<Message>,
+    files: &'a AtomicU64,
+    bytes: &'a AtomicU64,
+}
+
+impl<'a> Walk<'a> {
+    /// queues everything in the directory at `path`, then tells what its entries turned out to be
+    fn list(&self, key: Key, path: &Path) -> Result<()> {
+        let paths =
+            fs::read_dir(path).and_then(|entries| entries.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>());
+        let listed = match paths {
+            Ok(mut paths) => {
+                paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+                let mut entries = Vec::with_capacity(paths.len());
+                for (index, path) in paths.into_iter().enumerate() {
+                    let mut key = key.clone();
+                    key.push(index);
+                    entries.push(self.queue(key, path)?);
+                }
+                Ok(entries)
+            }
+            Err(e) => Err(Failure {
+                path: path.to_path_buf(),
+                error: e.into(),
+            }),
+        };
+        self.results.send(Message::Listed(key, listed))?;
+        Ok(())
+    }
+
+    /// hands the entry at `path` over to be hashed or listed, symbolic links are followed to files but not to directories
+    fn queue(&self, key: Key, path: PathBuf) -> Result<Entry> {
+        // subtrees that were completely stored before the checkpoint are not descended into
+        let not_stored_yet = match self.resume_after {
+            None => true,
+            Some(resume_after) => path.as_path() > resume_after || (resume_after.starts_with(&path) && path != resume_after),
+        };
+        if !not_stored_yet {
+            return Ok(Entry::Skipped);
+        }
+        if path.symlink_metadata().map_or(false, |metadata| metadata.is_dir()) {
+            return self.queue_directory(key, path);
+        }
+        match path.metadata() {
+            Ok(metadata) if !metadata.is_file() => return Ok(Entry::Skipped),
+            Ok(metadata) => {
+                self.bytes.fetch_add(metadata.len(), Ordering::SeqCst);
+                self.scanned.send(Scanned {
+                    key,
+                    path,
+                    length: metadata.len(),
+                })?
+            }
+            Err(e) => self
+                .results
+                .send(Message::Stored(key, Err(Failure { path, error: e.into() })))?,
+        }
+        self.files.fetch_add(1, Ordering::SeqCst);
+        Ok(Entry::File)
+    }
+
+    fn queue_directory(&self, key: Key, path: PathBuf) -> Result<Entry> {
+        self.unlisted_directories.fetch_add(1, Ordering::SeqCst);
+        self.directories.send((key, path))?;
+        Ok(Entry::Directory)
+    }
+}
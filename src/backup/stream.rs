@@ -0,0 +1,286 @@
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Engine;
+use crate::cancellation::Cancelled;
+use crate::progress::Event;
+use crate::repository::{data::StoredData, metadata::Metadata};
+use anyhow::Result;
+use anyhow::*;
+
+/// standard output of a running command, fails at the end of the stream if the command did not succeed.
+/// The command is killed if the output is dropped before its end
+struct CommandOutput {
+    child: Child,
+    stdout: ChildStdout,
+    status: Option<ExitStatus>,
+}
+
+impl CommandOutput {
+    fn new(child: Child, stdout: ChildStdout) -> Self {
+        CommandOutput {
+            child,
+            stdout,
+            status: None,
+        }
+    }
+}
+
+impl Read for CommandOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let status = match self.status {
+                Some(status) => status,
+                None => *self.status.insert(self.child.wait()?),
+            };
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("command failed with {}", status),
+                ));
+            }
+        }
+        io::Result::Ok(read)
+    }
+}
+
+impl Drop for CommandOutput {
+    fn drop(&mut self) {
+        if self.status.is_some() {
+            return;
+        }
+        if let Err(e) = self.child.kill() {
+            log::warn!("cannot kill command {}: {}", self.child.id(), e);
+        }
+        if let Err(e) = self.child.wait() {
+            log::warn!("cannot wait for command {}: {}", self.child.id(), e);
+        }
+    }
+}
+
+impl<'a> Engine<'a> {
+    /// stores everything `reader` gives as a single file named after the source path,
+    /// for data that only exists as a stream, e.g. stdin
+    pub fn backup_stream<R: Read>(&mut self, reader: R) -> Result<()> {
+        let metadata = Metadata {
+            modified: Some(SystemTime::now()),
+            ..Metadata::default()
+        };
+        self.store_stream(reader, self.source_path, metadata)?;
+        self.repository.save_index()
+    }
+
+    /// runs `command` and stores its standard output like `backup_stream`,
+    /// nothing is stored if the command fails
+    pub fn backup_command_output(&mut self, command: &mut Command) -> Result<()> {
+        let mut child = command.stdout(Stdio::piped()).spawn()?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("cannot read output of {:?}", command))?;
+        self.backup_stream(CommandOutput::new(child, stdout))
+            .with_context(|| format!("cannot back up output of {:?}", command))
+    }
+
+    /// stores every regular file in a tar stream under the source path,
+    /// keeping the modification times and permissions from the archive.
+    /// When cancelled, keeps the files stored so far and fails with `Cancelled`
+    pub fn backup_tar<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            if self.cancellation.is_cancelled() {
+                self.repository.save_index()?;
+                return Err(Cancelled.into());
+            }
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let entry_path = entry.path()?.to_path_buf();
+            let path = match path_in_archive(&entry_path) {
+                Some(path) => self.source_path.join(path),
+                None => {
+                    log::warn!("skipping {} that points outside the archive", entry_path.to_string_lossy());
+                    continue;
+                }
+            };
+            let metadata = Metadata {
+                modified: Some(UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?)),
+                mode: Some(entry.header().mode()?),
+                ..Metadata::default()
+            };
+            self.store_stream(entry, &path, metadata)?;
+        }
+        self.repository.save_index()
+    }
+
+    fn store_stream<R: Read>(&mut self, reader: R, path: &Path, metadata: Metadata) -> Result<StoredData> {
+        let path_name = path.to_string_lossy().to_string();
+        self.observer.on_event(&Event::FileStarted { path: path_name.clone() });
+        let stored = match self.repository.data_store().store_reader(reader, path, metadata) {
+            Result::Ok(stored) => stored,
+            Err(e) => {
+                self.observer.on_event(&Event::Error {
+                    path: path_name,
+                    message: format!("{:#}", e),
+                });
+                return Err(e);
+            }
+        };
+        self.repository.remember(&stored)?;
+        let bytes = stored.metadata.size;
+        self.observer.on_event(&Event::BytesProcessed { bytes });
+        if stored.deduplicated {
+            self.observer.on_event(&Event::DeduplicationHit {
+                path: path_name.clone(),
+                bytes,
+            });
+        }
+        self.observer.on_event(&Event::FileFinished { path: path_name, bytes });
+        Ok(stored)
+    }
+}
+
+/// entry path relative to the archive root, `None` if it would escape it
+fn path_in_archive(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(relative)
+}
+
+#[cfg(test)]
+mod must {
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    use super::super::Engine;
+    use super::CommandOutput;
+    use crate::repository::Repository;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn stored_contents(repository: &Repository, path: &str) -> Result<Option<String>> {
+        match repository.newest_item_by_source_path(Path::new(path))? {
+            None => Ok(None),
+            Some(item) => {
+                let mut contents = String::new();
+                item.reader()?.read_to_string(&mut contents)?;
+                Ok(Some(contents))
+            }
+        }
+    }
+
+    #[test]
+    fn version_stream_under_its_name() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let name = Path::new("/dumps/db.sql");
+
+        Engine::new(name, &mut repository)?.backup_stream("first dump".as_bytes())?;
+        Engine::new(name, &mut repository)?.backup_stream("second dump".as_bytes())?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let item = repository.newest_item_by_source_path(name)?.unwrap();
+        assert_eq!(item.version().to_string(), "2");
+        assert_eq!(item.metadata().size, 11);
+        assert_eq!(stored_contents(&repository, "/dumps/db.sql")?.unwrap(), "second dump");
+        Ok(())
+    }
+
+    #[test]
+    fn store_nothing_when_command_fails() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let name = Path::new("/dumps/db.sql");
+
+        let result =
+            Engine::new(name, &mut repository)?.backup_command_output(Command::new("sh").args(["-c", "echo partial; exit 3"]));
+
+        assert!(result.is_err());
+        assert!(repository.newest_item_by_source_path(name)?.is_none());
+        assert_eq!(repository.data_weight()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn kill_command_when_its_output_is_dropped_early() -> Result<()> {
+        let mut child = Command::new("sh")
+            .args(["-c", "echo started; sleep 30"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let mut output = CommandOutput::new(child, stdout);
+        output.read_exact(&mut [0; 8])?;
+        let started = Instant::now();
+
+        drop(output);
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn fail_at_the_end_of_output_of_failed_command() -> Result<()> {
+        let mut child = Command::new("sh")
+            .args(["-c", "echo partial; exit 3"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let mut contents = String::new();
+
+        let result = CommandOutput::new(child, stdout).read_to_string(&mut contents);
+
+        assert!(result.is_err());
+        assert_eq!(contents, "partial\n");
+        Ok(())
+    }
+
+    #[test]
+    fn store_files_from_tar_stream_with_their_metadata() -> Result<()> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, contents, mode) in [("etc/a", "a", 0o600), ("etc/b", "b", 0o755), ("../escape", "c", 0o644)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_mtime(1_000_000);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes())?;
+        }
+        let archive = builder.into_inner()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+
+        Engine::new(Path::new("/appliance"), &mut repository)?.backup_tar(&archive[..])?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let items = repository
+            .newest_items()
+            .map(|item| item.map(|item| (item.original_source_path().to_string(), item.metadata().mode)))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(items.len(), 2);
+        assert!(items.contains(&("/appliance/etc/a".to_string(), Some(0o600))));
+        assert!(items.contains(&("/appliance/etc/b".to_string(), Some(0o755))));
+        assert_eq!(stored_contents(&repository, "/appliance/etc/b")?.unwrap(), "b");
+        Ok(())
+    }
+}
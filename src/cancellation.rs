@@ -0,0 +1,67 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// shared flag that engines check between files to stop early and leave the repository consistent
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// error returned by engines that stopped because their cancellation token was triggered
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// cancels the token on SIGINT or SIGTERM,
+    /// a second signal received while already cancelled terminates the process right away
+    pub fn cancel_on_signals(&self) -> Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(signal, 1, self.cancelled.clone())?;
+            signal_hook::flag::register(signal, self.cancelled.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod must {
+    use super::CancellationToken;
+
+    #[test]
+    fn be_cancelled_in_all_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        clone.cancel();
+
+        assert!(token.is_cancelled());
+    }
+}
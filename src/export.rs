@@ -0,0 +1,304 @@
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::cancellation::{CancellationToken, Cancelled};
+use crate::progress::{Event, NoProgress, Observer};
+use crate::repository::{item::RepositoryItem, Repository};
+use crate::restore::{
+    destination::Destination,
+    selector::{self, Selector},
+};
+use anyhow::Result;
+use anyhow::*;
+use time::OffsetDateTime;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const DEFAULT_MODE: u32 = 0o644;
+const PERMISSION_BITS: u32 = 0o7777;
+
+/// archive formats files can be exported as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    /// tar compressed with zstd
+    TarZstd,
+    /// zip archives need a seekable output, see `Engine::export`
+    Zip,
+}
+
+/// writes newest versions of backed up files into an archive, straight from the repository
+pub struct Engine<'a> {
+    repository: &'a Repository,
+    format: Format,
+    selectors: Vec<Selector>,
+    destination: Destination,
+    cancellation: CancellationToken,
+    observer: Arc<dyn Observer>,
+}
+
+impl Format {
+    /// guesses the format from the extension of an output file name
+    pub fn from_file_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy();
+        if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Format::TarZstd)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tar" => Ok(Format::Tar),
+            "tar.zst" => Ok(Format::TarZstd),
+            "zip" => Ok(Format::Zip),
+            _ => Err(anyhow!("unknown archive format '{}', expected one of: tar, tar.zst, zip", s)),
+        }
+    }
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(repository: &'a Repository, format: Format) -> Result<Self> {
+        Ok(Engine {
+            repository,
+            format,
+            selectors: vec![],
+            destination: Destination::under(Path::new("")),
+            cancellation: CancellationToken::new(),
+            observer: Arc::new(NoProgress),
+        })
+    }
+
+    /// restricts the export to files matching any of the selectors, all files are exported when there are none
+    pub fn with_selectors(mut self, selectors: Vec<Selector>) -> Self {
+        self.selectors = selectors;
+        self
+    }
+
+    /// drops `prefix` from original paths before using them as paths in the archive
+    pub fn with_stripped_prefix(mut self, prefix: &Path) -> Self {
+        self.destination = self.destination.with_stripped_prefix(prefix);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// writes the archive in any of the formats, returns the writer once the archive is complete
+    pub fn export<W: Write + Seek>(&self, writer: W) -> Result<W> {
+        match self.format {
+            Format::Zip => self.write_zip(writer),
+            _ => self.export_stream(writer),
+        }
+    }
+
+    /// writes the archive to a writer that cannot seek, e.g. stdout, which rules out zip
+    pub fn export_stream<W: Write>(&self, writer: W) -> Result<W> {
+        match self.format {
+            Format::Tar => self.write_tar(writer),
+            Format::TarZstd => {
+                let encoder = zstd::Encoder::new(writer, 0)?;
+                Ok(self.write_tar(encoder)?.finish()?)
+            }
+            Format::Zip => Err(anyhow!("zip archives can only be written to a file")),
+        }
+    }
+
+    fn write_tar<W: Write>(&self, writer: W) -> Result<W> {
+        let mut builder = tar::Builder::new(writer);
+        self.for_each_entry(|name, item, size| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(size);
+            header.set_mode(mode(item));
+            header.set_mtime(modified(item)?.map_or(0, |modified| modified.unix_timestamp().max(0) as u64));
+            builder.append_data(&mut header, name, item.reader()?)?;
+            Ok(())
+        })?;
+        Ok(builder.into_inner()?)
+    }
+
+    fn write_zip<W: Write + Seek>(&self, writer: W) -> Result<W> {
+        let mut zip = ZipWriter::new(writer);
+        self.for_each_entry(|name, item, size| {
+            let mut options = FileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .unix_permissions(mode(item))
+                .large_file(size >= u32::MAX as u64);
+            if let Some(modified) = modified(item)? {
+                if let Result::Ok(modified) = zip::DateTime::try_from(modified) {
+                    options = options.last_modified_time(modified);
+                }
+            }
+            zip.start_file(name.to_string_lossy(), options)?;
+            io::copy(&mut item.reader()?, &mut zip)?;
+            Ok(())
+        })?;
+        Ok(zip.finish()?)
+    }
+
+    /// calls `write_entry` with the path in the archive, the item and its size for every selected item,
+    /// in path order so that the same repository always gives the same archive
+    fn for_each_entry<F>(&self, mut write_entry: F) -> Result<()>
+    where
+        F: FnMut(&Path, &RepositoryItem, u64) -> Result<()>,
+    {
+        let mut entries: Vec<(PathBuf, RepositoryItem)> = vec![];
+        for item in self.repository.newest_items() {
+            let item = item?;
+            if selector::selected(&self.selectors, item.original_source_path()) {
+                entries.push((self.destination.resolve(item.original_source_path())?, item));
+            }
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let sizes = entries.iter().map(|(_, item)| item.size()).collect::<Result<Vec<_>>>()?;
+        self.observer.on_event(&Event::ScanFinished {
+            files: entries.len() as u64,
+            bytes: sizes.iter().sum(),
+        });
+
+        for ((name, item), bytes) in entries.iter().zip(sizes) {
+            if self.cancellation.is_cancelled() {
+                return Err(Cancelled.into());
+            }
+            let path = item.original_source_path().to_string();
+            self.observer.on_event(&Event::FileStarted { path: path.clone() });
+            if let Err(e) = write_entry(name, item, bytes) {
+                self.observer.on_event(&Event::Error {
+                    path,
+                    message: format!("{:#}", e),
+                });
+                return Err(e.context(item.to_string()));
+            }
+            self.observer.on_event(&Event::BytesProcessed { bytes });
+            self.observer.on_event(&Event::FileFinished { path, bytes });
+        }
+        Ok(())
+    }
+}
+
+fn mode(item: &RepositoryItem) -> u32 {
+    item.metadata().mode.map_or(DEFAULT_MODE, |mode| mode & PERMISSION_BITS)
+}
+
+fn modified(item: &RepositoryItem) -> Result<Option<OffsetDateTime>> {
+    match item.metadata().modified {
+        None => Ok(None),
+        Some(modified) => {
+            let since_epoch = modified.duration_since(UNIX_EPOCH)?;
+            Ok(Some(OffsetDateTime::from_unix_timestamp(since_epoch.as_secs() as i64)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::io::{Cursor, Read};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::{Engine, Format};
+    use crate::repository::Repository;
+    use crate::restore::selector::Selector;
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn backed_up_source(repository_path: &Path, secret: &str) -> Result<TestSource> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "first")?;
+        source.write_text_to_file("dir/b", "second")?;
+        Repository::init(repository_path, secret)?;
+        let mut repository = Repository::open(repository_path, secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        Ok(source)
+    }
+
+    #[test]
+    fn stream_selected_files_as_compressed_tar() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        let source = backed_up_source(repository_path.path(), secret)?;
+        let repository = Repository::open(repository_path.path(), secret)?;
+
+        let compressed = Engine::new(&repository, Format::TarZstd)?
+            .with_selectors(vec![Selector::prefix(&source.file_path("dir")?)])
+            .with_stripped_prefix(source.path())
+            .export_stream(vec![])?;
+
+        let mut archive = tar::Archive::new(zstd::Decoder::new(&compressed[..])?);
+        let mut entries = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            let mode = entry.header().mode()?;
+            entries.push((entry.path()?.to_string_lossy().to_string(), contents, mode));
+        }
+        let original_mode = source.file_path("dir/b")?.metadata()?.permissions().mode();
+        assert_eq!(
+            entries,
+            vec![("dir/b".to_string(), "second".to_string(), original_mode & 0o7777)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_all_files_as_zip() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        let source = backed_up_source(repository_path.path(), secret)?;
+        let repository = Repository::open(repository_path.path(), secret)?;
+
+        let written = Engine::new(&repository, Format::Zip)?
+            .with_stripped_prefix(source.path())
+            .export(Cursor::new(vec![]))?;
+
+        let mut archive = zip::ZipArchive::new(written)?;
+        let mut entries = vec![];
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            entries.push((file.name().to_string(), contents));
+        }
+        assert_eq!(
+            entries,
+            vec![
+                ("a".to_string(), "first".to_string()),
+                ("dir/b".to_string(), "second".to_string())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_stream_zip() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        backed_up_source(repository_path.path(), secret)?;
+        let repository = Repository::open(repository_path.path(), secret)?;
+
+        assert!(Engine::new(&repository, Format::Zip)?.export_stream(vec![]).is_err());
+        Ok(())
+    }
+}
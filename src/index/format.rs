@@ -0,0 +1,291 @@
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use anyhow::Result;
+use anyhow::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::index::{item::IndexItem, paged::MemoryBudget, Index};
+use crate::repository::{metadata::Metadata, ItemId};
+use crate::version::{Version, VersionVector};
+
+/// starts everything written in the binary format, JSON starts with `{`
+const MAGIC: &[u8; 4] = b"BKIX";
+/// segments, trees and snapshots
+const FORMAT_VERSION: u8 = 1;
+/// index files as streamed by `write_index`
+const STREAM_FORMAT_VERSION: u8 = 2;
+
+/// how an index file is encoded, before encryption
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// written by older versions, still read but only ever written anew in the binary format
+    Json,
+    Binary,
+}
+
+/// starts an index streamed by `write_index`, followed by a `PathRecord` for every path in order
+/// and `None` after the last one
+#[derive(Serialize, Deserialize)]
+struct StreamHeader {
+    conflicting: Vec<IndexItem>,
+    checkpoints: Vec<(String, String)>,
+    version: Version,
+    fence: u64,
+}
+
+/// versions of a path, with the path stored as what it does not share with the path of the record before
+#[derive(Serialize, Deserialize)]
+struct PathRecord {
+    shared: u32,
+    suffix: String,
+    history: Vec<StreamedItem>,
+    /// position of the newest version in `history`
+    newest: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StreamedItem {
+    relative_path: String,
+    id: ItemId,
+    version: Version,
+    metadata: Metadata,
+    clock: VersionVector,
+    stored: Option<SystemTime>,
+}
+
+struct RecordWriter<W: Write> {
+    writer: W,
+    previous: String,
+}
+
+/// the binary format, versioned so that a later change to it is told apart from corruption
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(FORMAT_VERSION);
+    bincode::serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+/// streams the index one path at a time, in path order, so that it never has to be in memory whole
+pub fn write_index<W: Write>(index: &Index, mut writer: W) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[STREAM_FORMAT_VERSION])?;
+    let header = StreamHeader {
+        conflicting: index.conflicting_items_by_source_path.values().flatten().cloned().collect(),
+        checkpoints: index
+            .checkpoints
+            .iter()
+            .map(|(backup_source_path, last_stored_path)| (backup_source_path.clone(), last_stored_path.clone()))
+            .collect(),
+        version: index.version,
+        fence: index.fence,
+    };
+    bincode::serialize_into(&mut writer, &header)?;
+
+    let mut records = RecordWriter {
+        writer,
+        previous: String::new(),
+    };
+    let mut newest_items = index.newest_items_by_source_path.iter();
+    let mut newest = newest_items.next().transpose()?;
+    for entry in index.history_by_source_path.iter() {
+        let (path, mut history) = entry?;
+        // paths with a newest version and no history, which only indexes from before history could have
+        while let Some((newest_path, item)) = newest.take() {
+            if newest_path >= path {
+                newest = Some((newest_path, item));
+                break;
+            }
+            records.write(&newest_path, &[item], Some(0))?;
+            newest = newest_items.next().transpose()?;
+        }
+        let position = match newest.take() {
+            Some((newest_path, item)) if newest_path == path => {
+                newest = newest_items.next().transpose()?;
+                let position = match history.iter().position(|version| *version == item) {
+                    Some(position) => position,
+                    None => {
+                        history.push(item);
+                        history.len() - 1
+                    }
+                };
+                Some(u32::try_from(position)?)
+            }
+            other => {
+                newest = other;
+                None
+            }
+        };
+        records.write(&path, &history, position)?;
+    }
+    while let Some((newest_path, item)) = newest {
+        records.write(&newest_path, &[item], Some(0))?;
+        newest = newest_items.next().transpose()?;
+    }
+    bincode::serialize_into(&mut records.writer, &None::<PathRecord>)?;
+    Ok(())
+}
+
+/// reads what `write_index` wrote, one path at a time, into maps that keep to `memory_budget`
+pub fn read_index<R: Read>(mut reader: R, memory_budget: &MemoryBudget) -> Result<Index> {
+    let mut magic = [0; MAGIC.len() + 1];
+    reader.read_exact(&mut magic)?;
+    if &magic[..MAGIC.len()] != MAGIC {
+        return Err(anyhow!("index does not start with the binary format marker"));
+    }
+    match magic[MAGIC.len()] {
+        STREAM_FORMAT_VERSION => {}
+        version => {
+            return Err(anyhow!(
+                "index format version {} is not supported, written by a newer version?",
+                version
+            ))
+        }
+    }
+    let header: StreamHeader = bincode::deserialize_from(&mut reader)?;
+    let mut index = Index::with_memory_budget(memory_budget)?;
+    for item in header.conflicting {
+        index
+            .conflicting_items_by_source_path
+            .entry(item.original_source_path().to_string())
+            .or_default()
+            .push(item);
+    }
+    index.checkpoints = header.checkpoints.into_iter().collect();
+    index.version = header.version;
+    index.fence = header.fence;
+
+    let mut path = String::new();
+    while let Some(record) = bincode::deserialize_from::<_, Option<PathRecord>>(&mut reader)? {
+        let shared = record.shared as usize;
+        if !path.is_char_boundary(shared) {
+            return Err(anyhow!("index path shares {} bytes with the one before it: {}", shared, path));
+        }
+        path.truncate(shared);
+        path.push_str(&record.suffix);
+        let mut history = vec![];
+        for streamed in record.history {
+            let mut item = IndexItem::from(
+                path.clone(),
+                streamed.relative_path,
+                streamed.id,
+                streamed.version,
+                streamed.metadata,
+            )
+            .with_clock(streamed.clock);
+            if let Some(stored) = streamed.stored {
+                item = item.with_stored(stored);
+            }
+            index.items_by_file_id.insert(item.id(), item.clone())?;
+            history.push(item);
+        }
+        if let Some(position) = record.newest {
+            let newest = history
+                .get(position as usize)
+                .cloned()
+                .ok_or_else(|| anyhow!("index refers to unknown version {} of {}", position, path))?;
+            index.newest_items_by_source_path.insert(path.clone(), newest)?;
+        }
+        index.history_by_source_path.insert(path.clone(), history)?;
+    }
+    Ok(index)
+}
+
+/// reads either format, JSON being what older versions wrote
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, IndexFormat)> {
+    match binary_part(bytes)? {
+        Some(binary) => Ok((bincode::deserialize(binary)?, IndexFormat::Binary)),
+        None => Ok((serde_json::from_slice(bytes)?, IndexFormat::Json)),
+    }
+}
+
+fn binary_part(bytes: &[u8]) -> Result<Option<&[u8]>> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    match bytes.get(MAGIC.len()) {
+        Some(&FORMAT_VERSION) => Ok(Some(&bytes[MAGIC.len() + 1..])),
+        Some(version) => Err(anyhow!(
+            "index format version {} is not supported, written by a newer version?",
+            version
+        )),
+        None => Err(anyhow!("index ends before its format version")),
+    }
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn write(&mut self, path: &str, history: &[IndexItem], newest: Option<u32>) -> Result<()> {
+        let mut shared = self.previous.bytes().zip(path.bytes()).take_while(|(a, b)| a == b).count();
+        while !path.is_char_boundary(shared) {
+            shared -= 1;
+        }
+        let record = PathRecord {
+            shared: u32::try_from(shared)?,
+            suffix: path[shared..].to_string(),
+            history: history
+                .iter()
+                .map(|item| StreamedItem {
+                    relative_path: item.relative_path().to_string(),
+                    id: item.id(),
+                    version: item.version(),
+                    metadata: item.metadata(),
+                    clock: item.clock().clone(),
+                    stored: item.stored(),
+                })
+                .collect(),
+            newest,
+        };
+        bincode::serialize_into(&mut self.writer, &Some(record))?;
+        self.previous = path.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::path::Path;
+
+    use super::{read_index, write_index};
+    use crate::index::{paged::UNBOUNDED, Index};
+    use crate::repository::{metadata::Metadata, ItemId};
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn read_back_what_it_wrote_in_less_space_than_json() -> Result<()> {
+        let mut index = Index::new()?;
+        for i in 0..100u8 {
+            let path = Path::new("/home/someone/some/deeply/nested/directory").join(format!("file {}", i % 10));
+            index.remember(
+                "some writer",
+                &path,
+                &format!("data/{}", i),
+                ItemId::from(&[i; 64][..]),
+                Metadata::default(),
+            )?;
+        }
+        index.remember_checkpoint(Path::new("/home/someone"), Path::new("/home/someone/some"));
+
+        let mut binary = vec![];
+        write_index(&index, &mut binary)?;
+        let decoded = read_index(&binary[..], &UNBOUNDED)?;
+
+        assert_eq!(decoded.newest_items_by_source_path, index.newest_items_by_source_path);
+        assert_eq!(decoded.items_by_file_id, index.items_by_file_id);
+        assert_eq!(decoded.history_by_source_path, index.history_by_source_path);
+        assert_eq!(decoded.checkpoints, index.checkpoints);
+        assert!(binary.len() * 3 < serde_json::to_vec(&index)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_format_versions_from_the_future() -> Result<()> {
+        let mut binary = vec![];
+        write_index(&Index::new()?, &mut binary)?;
+        binary[4] += 1;
+
+        assert!(read_index(&binary[..], &UNBOUNDED).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    cmp::min,
+    io::{self, Read, Write},
+};
+
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+/// starts index files encrypted in frames, files encrypted whole start with random bytes instead
+pub const MAGIC: &[u8; 8] = b"BKIXFRMS";
+/// bytes of the nonce that come from the file, the rest is the number of the frame
+pub const NONCE_PREFIX_LENGTH: usize = 16;
+
+const FRAME_LENGTH: usize = 1024 * 1024;
+const TAG_LENGTH: usize = 16;
+
+/// encrypts what is written to it in frames of their own, so that a reader never needs more than one frame in memory.
+/// Each frame is authenticated along with whether it is the last one and the name of the file,
+/// which makes a file cut short or put in place of another fail to read
+pub struct FrameWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    file_name: Vec<u8>,
+    frame: Vec<u8>,
+    written: u64,
+}
+
+pub struct FrameReader<R: Read> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LENGTH],
+    file_name: Vec<u8>,
+    frame: Vec<u8>,
+    position: usize,
+    read: u64,
+    finished: bool,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// `nonce_prefix` has to be different for every file written with the same cipher
+    pub fn new(inner: W, cipher: XChaCha20Poly1305, nonce_prefix: [u8; NONCE_PREFIX_LENGTH], file_name: &[u8]) -> Self {
+        FrameWriter {
+            inner,
+            cipher,
+            nonce_prefix,
+            file_name: file_name.to_vec(),
+            frame: Vec::with_capacity(FRAME_LENGTH),
+            written: 0,
+        }
+    }
+
+    /// writes out the last frame, which has to be done for the file to be read back
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frame(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_frame(&mut self, last: bool) -> io::Result<()> {
+        let payload = Payload {
+            msg: &self.frame,
+            aad: &associated_data(last, &self.file_name),
+        };
+        let encrypted = self
+            .cipher
+            .encrypt(&nonce(&self.nonce_prefix, self.written), payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let length = u32::try_from(encrypted.len()).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.inner.write_all(&length.to_le_bytes())?;
+        self.inner.write_all(&[last as u8])?;
+        self.inner.write_all(&encrypted)?;
+        self.frame.clear();
+        self.written += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let length = min(bytes.len(), FRAME_LENGTH - self.frame.len());
+        self.frame.extend_from_slice(&bytes[..length]);
+        if self.frame.len() == FRAME_LENGTH {
+            self.write_frame(false)?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R, cipher: XChaCha20Poly1305, nonce_prefix: [u8; NONCE_PREFIX_LENGTH], file_name: &[u8]) -> Self {
+        FrameReader {
+            inner,
+            cipher,
+            nonce_prefix,
+            file_name: file_name.to_vec(),
+            frame: vec![],
+            position: 0,
+            read: 0,
+            finished: false,
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        let mut header = [0; 5];
+        self.inner.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "index ends before its last frame"),
+            _ => e,
+        })?;
+        let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if length > FRAME_LENGTH + TAG_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("index frame of {} bytes is longer than any written", length),
+            ));
+        }
+        let last = header[4] == 1;
+        let mut encrypted = vec![0; length];
+        self.inner.read_exact(&mut encrypted)?;
+        let payload = Payload {
+            msg: &encrypted,
+            aad: &associated_data(last, &self.file_name),
+        };
+        self.frame = self
+            .cipher
+            .decrypt(&nonce(&self.nonce_prefix, self.read), payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("cannot decrypt index frame: {}", e)))?;
+        self.position = 0;
+        self.read += 1;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.frame.len() {
+                let length = min(buffer.len(), self.frame.len() - self.position);
+                buffer[..length].copy_from_slice(&self.frame[self.position..self.position + length]);
+                self.position += length;
+                return Ok(length);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_frame()?;
+        }
+    }
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_LENGTH], frame: u64) -> XNonce {
+    let mut nonce = [0; 24];
+    nonce[..NONCE_PREFIX_LENGTH].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LENGTH..].copy_from_slice(&frame.to_le_bytes());
+    *XNonce::from_slice(&nonce)
+}
+
+fn associated_data(last: bool, file_name: &[u8]) -> Vec<u8> {
+    let mut data = vec![last as u8];
+    data.extend_from_slice(file_name);
+    data
+}
+
+#[cfg(test)]
+mod must {
+    use std::io::{Read, Write};
+
+    use super::{FrameReader, FrameWriter, FRAME_LENGTH};
+    use anyhow::Result;
+    use chacha20poly1305::aead::NewAead;
+    use chacha20poly1305::{Key, XChaCha20Poly1305};
+
+    fn cipher() -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&[7; 32]))
+    }
+
+    #[test]
+    fn read_back_frames_and_notice_them_cut_short() -> Result<()> {
+        let contents = (0..FRAME_LENGTH * 2 + 10).map(|i| i as u8).collect::<Vec<_>>();
+        let mut writer = FrameWriter::new(vec![], cipher(), [1; 16], b"index");
+        writer.write_all(&contents)?;
+        let encrypted = writer.finish()?;
+
+        let mut read = vec![];
+        FrameReader::new(&encrypted[..], cipher(), [1; 16], b"index").read_to_end(&mut read)?;
+        let last_frame = encrypted.len() - 10 - 16 - 5;
+        let mut cut_short = FrameReader::new(&encrypted[..last_frame], cipher(), [1; 16], b"index");
+        let mut renamed = FrameReader::new(&encrypted[..], cipher(), [1; 16], b"other");
+
+        assert!(read == contents);
+        assert!(cut_short.read_to_end(&mut vec![]).is_err());
+        assert!(renamed.read_to_end(&mut vec![]).is_err());
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
 use std::{
-    collections::HashMap,
     fs::{self, File},
-    io::Read,
+    io::{self, BufReader, BufWriter, Read},
     os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use chacha20poly1305::aead::{Aead, NewAead};
@@ -11,156 +11,471 @@ use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce}; // Or `XChaCha20Poly1305
 
 use uuid::Uuid;
 
-use crate::index::item::IndexItem;
-use crate::index::{lock, Index};
-use crate::io::error_correcting_encoder;
-use crate::repository::ItemId;
+use crate::index::frames::{self, FrameReader, FrameWriter, NONCE_PREFIX_LENGTH};
+use crate::index::{format, lock, paged::MemoryBudget, Index, IndexFormat, Segment};
+use crate::io::error_correcting_encoder::{DecodingReader, EncodingWriter, Layout};
+use crate::repository::OPERATION_LOCKS_DIR_NAME;
 use anyhow::Result;
 use anyhow::*;
-use lock::Lock;
+use fail::fail_point;
+use lock::{Lock, LockLost};
 use nix::unistd::getpid;
 use std::{cmp::max, io::Write};
 
+/// index files are named after the token of the lock they were written under, followed by a dash,
+/// apart from the one written by older versions that counts as written under token 0
+const INDEX_FILE_NAME: &str = "index";
+const SEGMENTS_DIR_NAME: &str = "index_segments";
+const SEGMENT_EXTENSION: &str = "segment";
+/// segments past which a save compacts the index, so that loading does not slow down with every backup
+const MAX_SEGMENTS: usize = 64;
+/// how long a save waits to compact, leaving it to a later one when someone else holds the lock
+const AUTO_COMPACTION_TIMEOUT_MILLIS: u16 = 100;
+
 impl Index {
-    pub fn load(repository_path: &Path, secret: &[u8]) -> Result<Self> {
+    /// loads the index keeping to `memory_budget`, see `Index::with_memory_budget`.
+    /// Index files are protected by error correction laid out as `layout`, the same for the whole repository
+    pub fn load(repository_path: &Path, secret: &[u8], layout: Layout, memory_budget: &MemoryBudget) -> Result<Self> {
         if !repository_path.exists() {
             let mut index = Index::new()?;
-            index.save(repository_path, secret)?;
+            index.save(repository_path, secret, layout, memory_budget)?;
         }
-        let lock = Lock::lock(repository_path)?;
-        let index_file_path = &Index::index_file_path_for_repository_path(repository_path)?;
-        let index = Index::load_from_file(index_file_path, secret)?;
-        lock.release()?;
+        let (index, _) = Index::load_counting_corrections(repository_path, secret, layout, memory_budget)?;
         log::debug!(
-            "[{}] loaded index from {}, version: {}; {} items",
+            "[{}] loaded index from {}, version: {}; paged: {}",
             getpid(),
-            index_file_path.to_string_lossy(),
+            repository_path.to_string_lossy(),
             index.version,
-            index.newest_items_by_source_path.len()
+            index.is_paged()
         );
         Ok(index)
     }
 
-    pub fn save(&mut self, repository_path: &Path, secret: &[u8]) -> Result<()> {
-        let lock_id = Uuid::new_v4();
-        let lock = Lock::lock(repository_path)?;
+    /// loads the index straight from disk, also returning how many bytes error correction had to fix
+    pub fn load_counting_corrections(
+        repository_path: &Path,
+        secret: &[u8],
+        layout: Layout,
+        memory_budget: &MemoryBudget,
+    ) -> Result<(Self, usize)> {
+        let lock = Lock::shared(repository_path)?;
+        let loaded = Index::load_with_segments(repository_path, secret, layout, memory_budget);
+        lock.release()?;
+        let (index, corrections, _) = loaded?;
+        Ok((index, corrections))
+    }
 
-        let index_file_path = &Index::index_file_path_for_repository_path(repository_path)?;
-        if index_file_path.exists() {
-            let index = Index::load_from_file(&Index::index_file_path_for_repository_path(repository_path)?, secret)?;
-            self.merge_items_by_file_id(index.items_by_file_id);
-            self.merge_newest_items(index.newest_items_by_source_path);
-            self.version = max(self.version, index.version);
+    /// writes what changed since the index was loaded as a new segment, without waiting on any lock,
+    /// so that backups running at the same time each add their own.
+    /// The very first save writes the index file itself, and a save that finds more than `MAX_SEGMENTS`
+    /// compacts them when it can get the lock right away, see `Index::compact`
+    pub fn save(&mut self, repository_path: &Path, secret: &[u8], layout: Layout, memory_budget: &MemoryBudget) -> Result<()> {
+        if Index::index_file_paths(repository_path)?.is_empty() {
+            let lock = Lock::lock(repository_path)?;
+            // another process may have written it while this one waited on the lock
+            if Index::index_file_paths(repository_path)?.is_empty() {
+                self.version = self.version.next();
+                self.write_index_file(repository_path, secret, layout, &lock)?;
+                self.unsaved = Segment::default();
+                return lock.release();
+            }
+            lock.release()?;
+        }
+        if self.unsaved == Segment::default() {
+            return Ok(());
+        }
+        // a prune that started since this instance was loaded may have removed contents its items refer to,
+        // and once it is done with the index it ignores the segment anyway, see `Index::compact_fencing`
+        if Index::operation_token(repository_path)? > self.token {
+            return Err(LockLost.into());
         }
         self.version = self.version.next();
-        self.write_index_to_file(index_file_path, secret)?;
-        lock.release()?;
+
+        self.unsaved.version = self.version;
+        self.unsaved.token = self.token;
+        let segments_path = repository_path.join(SEGMENTS_DIR_NAME);
+        fs::create_dir_all(&segments_path)?;
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let segment_path = segments_path.join(format!("{:020}-{}.{}", created, Uuid::new_v4(), SEGMENT_EXTENSION));
+        Index::write_encrypted(&segment_path, &format::encode(&self.unsaved)?, secret, layout, None)?;
         log::debug!(
-            "[{}] saved index version {} with lock id {} to {}; {} items",
+            "[{}] saved index version {} to {}; {} new items",
             getpid(),
             self.version,
-            lock_id,
-            index_file_path.to_string_lossy(),
-            self.newest_items_by_source_path.len()
+            segment_path.to_string_lossy(),
+            self.unsaved.items.len()
         );
+        self.unsaved = Segment::default();
+
+        if Index::segment_paths(repository_path)?.len() > MAX_SEGMENTS {
+            match Lock::lock_with_timeout(repository_path, AUTO_COMPACTION_TIMEOUT_MILLIS) {
+                Result::Ok(lock) => {
+                    // the segment is saved already, a compaction that fails leaves it for the next one
+                    if let Err(e) = Index::compact_holding(lock, repository_path, secret, layout, memory_budget, 0) {
+                        log::warn!("cannot compact index in {}: {:#}", repository_path.to_string_lossy(), e);
+                    }
+                }
+                Err(e) => log::debug!("[{}] leaving index compaction for later: {}", getpid(), e),
+            }
+        }
         Ok(())
     }
 
-    fn write_index_to_file(&mut self, index_file_path: &Path, secret: &[u8]) -> Result<()> {
-        let parent = index_file_path.parent();
-        match parent {
-            None => Err(anyhow!(format!(
-                "cannot get parent for {}",
-                index_file_path.to_string_lossy()
-            ))),
-            Some(parent) => Ok(fs::create_dir_all(parent)),
-        }??;
+    /// combines all segments into a new index file and removes them along with older index files, returns how many there were.
+    /// Refuses with `LockLost` when the lock was taken over while compacting, e.g. because this process was suspended for too long
+    pub fn compact(repository_path: &Path, secret: &[u8], layout: Layout, memory_budget: &MemoryBudget) -> Result<usize> {
+        let lock = Lock::lock(repository_path)?;
+        Index::compact_holding(lock, repository_path, secret, layout, memory_budget, 0)
+    }
 
-        let serialised = serde_json::to_string_pretty(&self)?;
+    /// compacts the index like `Index::compact`, from then on ignoring segments saved under an operation lock token older than `fence`.
+    /// Called by `Repository::prune` holding the exclusive operation lock with that token, as segments saved later under an older one
+    /// come from a backup whose lock it broke, and may refer to contents it removes
+    pub(crate) fn compact_fencing(
+        repository_path: &Path,
+        secret: &[u8],
+        layout: Layout,
+        memory_budget: &MemoryBudget,
+        fence: u64,
+    ) -> Result<usize> {
+        let lock = Lock::lock(repository_path)?;
+        Index::compact_holding(lock, repository_path, secret, layout, memory_budget, fence)
+    }
 
-        let bytes = serialised.as_bytes();
+    fn compact_holding(
+        lock: Lock,
+        repository_path: &Path,
+        secret: &[u8],
+        layout: Layout,
+        memory_budget: &MemoryBudget,
+        fence: u64,
+    ) -> Result<usize> {
+        let (mut index, _, segments) = Index::load_with_segments(repository_path, secret, layout, memory_budget)?;
+        index.version = index.version.next();
+        index.fence = max(index.fence, fence);
+        fail_point!("compact-index-before-write");
+        index.write_index_file(repository_path, secret, layout, &lock)?;
+        // segments written in the meantime are not among these and stay for the next compaction.
+        // Should the lock have been broken since the check before writing, the compaction that broke it
+        // read these segments or the index file that has them, and wrote a newer one that is left alone
+        for segment in &segments {
+            fs::remove_file(segment)?;
+        }
+        for (token, path) in Index::index_file_paths(repository_path)? {
+            if token < lock.token() {
+                fs::remove_file(path)?;
+            }
+        }
+        log::debug!(
+            "[{}] compacted {} segments into index version {} with lock token {}",
+            getpid(),
+            segments.len(),
+            index.version,
+            lock.token()
+        );
+        lock.release()?;
+        Ok(segments.len())
+    }
 
-        let mut hash = [0; 32];
-        blake::hash(256, secret, &mut hash)?;
-        let key = Key::from_slice(&hash);
-        let cipher = XChaCha20Poly1305::new(key);
+    /// the index file with all segments applied in the order they were written,
+    /// along with the bytes error correction had to fix and the paths of the segments
+    fn load_with_segments(
+        repository_path: &Path,
+        secret: &[u8],
+        layout: Layout,
+        memory_budget: &MemoryBudget,
+    ) -> Result<(Self, usize, Vec<PathBuf>)> {
+        // read first, so that a prune starting while the index is read makes saves refuse rather than go through
+        let token = Index::operation_token(repository_path)?;
+        let index_file_path = &Index::index_file_path_for_repository_path(repository_path)?;
+        let (mut index, mut corrections) = Index::read_index_file(index_file_path, secret, layout, memory_budget)
+            .with_context(|| format!("cannot read index from: {}", index_file_path.to_string_lossy()))?;
+        index.token = token;
+        index.backfill_history()?;
+        let segments = Index::segment_paths(repository_path)?;
+        for segment_path in &segments {
+            let (decrypted, segment_corrections) = Index::read_encrypted(segment_path, secret, layout)?;
+            let (segment, _) = format::decode::<Segment>(&decrypted)
+                .with_context(|| format!("cannot read index segment from: {}", segment_path.to_string_lossy()))?;
+            corrections += segment_corrections;
+            if segment.token < index.fence {
+                log::warn!(
+                    "ignoring index segment {} saved under operation lock token {}, older than {}",
+                    segment_path.to_string_lossy(),
+                    segment.token,
+                    index.fence
+                );
+                continue;
+            }
+            index.apply(segment)?;
+        }
+        Ok((index, corrections, segments))
+    }
 
-        blake::hash(256, index_file_path.as_os_str().as_bytes(), &mut hash)?;
-        let nonce = XNonce::from_slice(&hash[0..(192 / 8)]);
+    /// streams index files written in frames, those written as JSON by older versions are encrypted whole
+    /// and have to be read whole before they can be moved into maps that keep to `memory_budget`
+    fn read_index_file(path: &Path, secret: &[u8], layout: Layout, memory_budget: &MemoryBudget) -> Result<(Self, usize)> {
+        let file = BufReader::new(File::open(path)?);
+        let mut reader = DecodingReader::with_layout(file, layout);
+        let mut start = vec![];
+        (&mut reader).take(frames::MAGIC.len() as u64).read_to_end(&mut start)?;
+        if start == frames::MAGIC {
+            let index = format::read_index(Index::frame_reader(&mut reader, path, secret)?, memory_budget)?;
+            return Ok((index, reader.corrections()));
+        }
 
-        let encrypted = cipher.encrypt(nonce, bytes.as_ref()).map_err(|e| anyhow!("{}", e))?;
-        let encoded = error_correcting_encoder::encode(&encrypted)?;
+        let mut encrypted = start;
+        reader.read_to_end(&mut encrypted)?;
+        let decrypted = Index::decrypt(path, &encrypted, secret)?;
+        let index: Index = serde_json::from_slice(&decrypted)?;
+        Ok((index.within_memory_budget(memory_budget)?, reader.corrections()))
+    }
 
-        {
-            let mut file = File::create(index_file_path)?;
-            file.write_all(&encoded).context("writing index to disk")?;
-            file.flush()?;
+    /// format the index file is in, segments are always combined into the binary format when compacting
+    pub fn format(repository_path: &Path, secret: &[u8], layout: Layout) -> Result<IndexFormat> {
+        let lock = Lock::shared(repository_path)?;
+        let index_file_path = &Index::index_file_path_for_repository_path(repository_path)?;
+        let read = Index::read_format(index_file_path, secret, layout);
+        lock.release()?;
+        read
+    }
+
+    fn read_format(path: &Path, secret: &[u8], layout: Layout) -> Result<IndexFormat> {
+        let file = BufReader::new(File::open(path)?);
+        let mut reader = DecodingReader::with_layout(file, layout);
+        let mut start = vec![];
+        (&mut reader).take(frames::MAGIC.len() as u64).read_to_end(&mut start)?;
+        if start == frames::MAGIC {
+            return Ok(IndexFormat::Binary);
         }
+        // only JSON was ever encrypted whole, decrypting tells it from damage
+        Index::read_encrypted(path, secret, layout)?;
+        Ok(IndexFormat::Json)
+    }
 
-        let readback = {
-            let mut file = File::open(index_file_path)?;
-            let mut readback = vec![];
-            file.read_to_end(&mut readback)?;
-            readback
-        };
+    /// greatest token issued to an operation lock, see `Repository::prune`
+    fn operation_token(repository_path: &Path) -> Result<u64> {
+        Lock::current_token(&repository_path.join(OPERATION_LOCKS_DIR_NAME))
+    }
 
-        if readback != encoded {
-            Err(anyhow!("index readback incorrect"))
-        } else {
-            Ok(())
+    /// index files with the tokens they were written under, the current one last
+    fn index_file_paths(repository_path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let mut index_files = vec![];
+        if !repository_path.exists() {
+            return Ok(index_files);
+        }
+        for entry in fs::read_dir(repository_path)? {
+            let path = entry?.path();
+            let name = Index::file_name(&path);
+            let token = match name.strip_prefix(INDEX_FILE_NAME) {
+                Some("") => Some(0),
+                Some(suffix) => suffix.strip_prefix('-').and_then(|token| token.parse().ok()),
+                None => None,
+            };
+            if let Some(token) = token {
+                index_files.push((token, path));
+            }
+        }
+        index_files.sort();
+        Ok(index_files)
+    }
+
+    fn segment_paths(repository_path: &Path) -> Result<Vec<PathBuf>> {
+        let segments_path = repository_path.join(SEGMENTS_DIR_NAME);
+        if !segments_path.exists() {
+            return Ok(vec![]);
+        }
+        let mut segments = vec![];
+        for entry in fs::read_dir(segments_path)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |extension| extension == SEGMENT_EXTENSION) {
+                segments.push(path);
+            }
+        }
+        // names start with the time they were written at
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// applying a segment again is harmless, which is what makes a compaction interrupted before removing segments safe
+    fn apply(&mut self, segment: Segment) -> Result<()> {
+        for item in segment.items {
+            self.items_by_file_id.insert(item.id(), item.clone())?;
+            self.add_to_history(item.clone())?;
+            self.add_head(item)?;
+        }
+        for (source_path, checkpoint) in segment.checkpoints {
+            match checkpoint {
+                Some(checkpoint) => self.checkpoints.insert(source_path, checkpoint),
+                None => self.checkpoints.remove(&source_path),
+            };
         }
+        self.version = max(self.version, segment.version);
+        Ok(())
     }
 
-    fn load_from_file(index_file_path: &Path, secret: &[u8]) -> Result<Self> {
-        let mut file = File::open(index_file_path)?;
-        let mut encoded = vec![];
-        file.read_to_end(&mut encoded)?;
+    /// streams the index into frames encrypted one at a time, under a nonce prefix of their own
+    /// as a file is written anew by every compaction, named after the token of `lock`
+    fn write_index_file(&self, repository_path: &Path, secret: &[u8], layout: Layout, lock: &Lock) -> Result<()> {
+        let index_file_path = &repository_path.join(format!("{}-{:020}", INDEX_FILE_NAME, lock.token()));
+        let file_name = Index::file_name(index_file_path);
+        Index::write_in_place(index_file_path, Some(lock), |temporary| {
+            {
+                let file = BufWriter::new(File::create(temporary)?);
+                let mut writer = EncodingWriter::with_layout(file, layout);
+                let nonce_prefix: [u8; NONCE_PREFIX_LENGTH] = rand::random();
+                writer.write_all(frames::MAGIC)?;
+                writer.write_all(&nonce_prefix)?;
+                let mut frames = FrameWriter::new(&mut writer, Index::cipher(secret)?, nonce_prefix, file_name.as_bytes());
+                format::write_index(self, &mut frames).context("writing index to disk")?;
+                frames.finish()?;
+                writer.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            }
+
+            // reading every frame back checks it all, without having to hold the index in memory twice
+            let file = BufReader::new(File::open(temporary)?);
+            let mut reader = DecodingReader::with_layout(file, layout);
+            let mut magic = [0; frames::MAGIC.len()];
+            reader.read_exact(&mut magic)?;
+            let mut frames = Index::frame_reader(&mut reader, index_file_path, secret)?;
+            io::copy(&mut frames, &mut io::sink()).context("index readback incorrect")?;
+            Ok(())
+        })
+    }
 
-        let decoded = error_correcting_encoder::decode(&encoded)?;
+    fn frame_reader<'a, R: Read>(reader: &'a mut R, path: &Path, secret: &[u8]) -> Result<FrameReader<&'a mut R>> {
+        let nonce_prefix = Index::nonce_prefix(reader)?;
+        Ok(FrameReader::new(
+            reader,
+            Index::cipher(secret)?,
+            nonce_prefix,
+            Index::file_name(path).as_bytes(),
+        ))
+    }
 
+    fn nonce_prefix<R: Read>(reader: &mut R) -> Result<[u8; NONCE_PREFIX_LENGTH]> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LENGTH];
+        reader.read_exact(&mut nonce_prefix)?;
+        Ok(nonce_prefix)
+    }
+
+    fn file_name(path: &Path) -> String {
+        path.file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_string())
+    }
+
+    pub(crate) fn write_encrypted(path: &Path, bytes: &[u8], secret: &[u8], layout: Layout, lock: Option<&Lock>) -> Result<()> {
+        let cipher = Index::cipher(secret)?;
+        // the nonce comes from the final path, the temporary one only holds the bytes until they are renamed
         let mut hash = [0; 32];
-        blake::hash(256, secret, &mut hash)?;
-        let key = Key::from_slice(&hash);
-        let cipher = XChaCha20Poly1305::new(key);
-        blake::hash(256, index_file_path.as_os_str().as_bytes(), &mut hash)?;
+        blake::hash(256, path.as_os_str().as_bytes(), &mut hash)?;
         let nonce = XNonce::from_slice(&hash[0..(192 / 8)]);
 
-        let decrypted = cipher.decrypt(nonce, decoded.as_ref()).map_err(|e| anyhow!("{}", e))?;
-        let index_text = String::from_utf8(decrypted)?;
+        let encrypted = cipher.encrypt(nonce, bytes).map_err(|e| anyhow!("{}", e))?;
+        Index::write_in_place(path, lock, |temporary| Index::write_encoded(temporary, &encrypted, layout))
+    }
 
-        let index: Index = serde_json::from_str(&index_text)
-            .context(format!("cannot read index from: {}", index_file_path.to_string_lossy()))?;
-        Ok(index)
+    /// writes to a temporary file with `write` first, which reads it back, and renames it into place once done,
+    /// making sure the lock is still held right before when there is one
+    fn write_in_place<F>(path: &Path, lock: Option<&Lock>, write: F) -> Result<()>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let parent = path.parent();
+        match parent {
+            None => Err(anyhow!(format!("cannot get parent for {}", path.to_string_lossy()))),
+            Some(parent) => Ok(fs::create_dir_all(parent)),
+        }??;
+
+        let temporary = path.with_file_name(format!("{}.{}.tmp", Index::file_name(path), Uuid::new_v4()));
+        let written = write(&temporary)
+            .and_then(|_| lock.map_or(Ok(()), |lock| lock.ensure_held()))
+            .and_then(|_| Ok(fs::rename(&temporary, path)?));
+        if written.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        written
     }
 
-    fn merge_newest_items(&mut self, old_newest_items: HashMap<String, IndexItem>) {
-        for (source_path, old_newest_item) in old_newest_items {
-            if let Some(new_newest_item) = self.newest_items_by_source_path.get(&source_path) {
-                if old_newest_item.version() > new_newest_item.version() {
-                    self.newest_items_by_source_path.insert(source_path, old_newest_item);
-                }
-            } else {
-                self.newest_items_by_source_path.insert(source_path, old_newest_item);
-            }
+    fn write_encoded(path: &Path, encrypted: &[u8], layout: Layout) -> Result<()> {
+        {
+            let file = BufWriter::new(File::create(path)?);
+            let mut writer = EncodingWriter::with_layout(file, layout);
+            writer.write_all(encrypted).context("writing index to disk")?;
+            writer.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+
+        let (readback, _) = Index::read_decoded(path, layout)?;
+        if readback != encrypted {
+            Err(anyhow!("index readback incorrect"))
+        } else {
+            Ok(())
         }
     }
 
-    fn merge_items_by_file_id(&mut self, old_items_by_file_id: HashMap<ItemId, IndexItem>) {
-        self.items_by_file_id.extend(old_items_by_file_id);
+    /// contents of an index file as they were before encryption, along with how many bytes had to be corrected
+    pub(crate) fn read_encrypted(path: &Path, secret: &[u8], layout: Layout) -> Result<(Vec<u8>, usize)> {
+        let (decoded, corrections) = Index::read_decoded(path, layout)?;
+        Ok((Index::decrypt(path, &decoded, secret)?, corrections))
     }
 
-    fn index_file_path_for_repository_path(path: &Path) -> Result<PathBuf> {
-        Ok(path.join("index"))
+    /// reads an index file undoing error correction on the way, returns the encrypted contents
+    /// and how many bytes had to be corrected
+    fn read_decoded(path: &Path, layout: Layout) -> Result<(Vec<u8>, usize)> {
+        let file = BufReader::new(File::open(path)?);
+        let mut reader = DecodingReader::with_layout(file, layout);
+        let mut decoded = vec![];
+        reader
+            .read_to_end(&mut decoded)
+            .context(format!("cannot decode index from: {}", path.to_string_lossy()))?;
+        Ok((decoded, reader.corrections()))
+    }
+
+    fn decrypt(path: &Path, decoded: &[u8], secret: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Index::cipher(secret)?;
+        let mut hash = [0; 32];
+        blake::hash(256, path.as_os_str().as_bytes(), &mut hash)?;
+        let nonce = XNonce::from_slice(&hash[0..(192 / 8)]);
+
+        cipher.decrypt(nonce, decoded).map_err(|e| anyhow!("{}", e))
+    }
+
+    fn cipher(secret: &[u8]) -> Result<XChaCha20Poly1305> {
+        let mut hash = [0; 32];
+        blake::hash(256, secret, &mut hash)?;
+        let key = Key::from_slice(&hash);
+        Ok(XChaCha20Poly1305::new(key))
+    }
+
+    /// the current index file, the one older versions wrote when there is none
+    pub(crate) fn index_file_path_for_repository_path(path: &Path) -> Result<PathBuf> {
+        Ok(Index::index_file_paths(path)?
+            .pop()
+            .map_or_else(|| path.join(INDEX_FILE_NAME), |(_, path)| path))
     }
 }
 
 #[cfg(test)]
 mod must {
-    use crate::index::Index;
+    use std::path::Path;
+
+    use std::{fs, thread};
+
+    use super::{MAX_SEGMENTS, SEGMENTS_DIR_NAME, SEGMENT_EXTENSION};
+    use crate::index::{
+        format,
+        lock::{Lock, LockLost},
+        paged::{MemoryBudget, UNBOUNDED},
+        Index, IndexFormat,
+    };
+    use crate::io::error_correcting_encoder::Layout;
+    use crate::repository::{metadata::Metadata, ItemId, OPERATION_LOCKS_DIR_NAME};
     use anyhow::Result;
     use pretty_assertions::assert_eq;
     use tempfile::tempdir;
 
+    const LAYOUT: Layout = Layout::CONTIGUOUS;
+
     #[test]
     fn have_version_increased_when_saved() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -168,7 +483,7 @@ mod must {
         let old_version = index.version;
 
         let secret = b"some secret";
-        index.save(temp_dir.path(), secret)?;
+        index.save(temp_dir.path(), secret, LAYOUT, &UNBOUNDED)?;
 
         let new_version = index.version;
 
@@ -183,11 +498,280 @@ mod must {
         let mut original = Index::new()?;
 
         let secret = b"some secret";
-        original.save(repository_path.path(), secret)?;
-        let loaded = Index::load(repository_path.path(), secret)?;
+        original.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
 
         assert_eq!(original, loaded);
 
         Ok(())
     }
+
+    #[test]
+    fn keep_saves_of_instances_loaded_at_the_same_time() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        Index::new()?.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let mut first = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let mut second = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        first.remember(
+            "some writer",
+            Path::new("/first"),
+            "a",
+            ItemId::from(&[1u8; 32][..]),
+            Metadata::default(),
+        )?;
+        second.remember(
+            "some writer",
+            Path::new("/second"),
+            "b",
+            ItemId::from(&[2u8; 32][..]),
+            Metadata::default(),
+        )?;
+        first.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        second.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert!(loaded.newest_item_by_source_path(Path::new("/first"))?.is_some());
+        assert!(loaded.newest_item_by_source_path(Path::new("/second"))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn combine_segments_when_compacted() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let source_path = Path::new("/some/source");
+        let mut index = Index::new()?;
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        index.remember(
+            "some writer",
+            source_path,
+            "a",
+            ItemId::from(&[1u8; 32][..]),
+            Metadata::default(),
+        )?;
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        index.remember_checkpoint(source_path, source_path);
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let before = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let compacted = Index::compact(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let after = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert_eq!(compacted, 2);
+        assert_eq!(Index::segment_paths(repository_path.path())?.len(), 0);
+        assert_eq!(Index::index_file_paths(repository_path.path())?.len(), 1);
+        assert_eq!(after.newest_items_by_source_path, before.newest_items_by_source_path);
+        assert_eq!(after.checkpoint(source_path), Some(source_path.to_path_buf()));
+        Ok(())
+    }
+
+    #[test]
+    fn compact_once_there_are_too_many_segments() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let mut index = Index::new()?;
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        for i in 0..=MAX_SEGMENTS {
+            index.remember(
+                "some writer",
+                &Path::new("/some/source").join(i.to_string()),
+                "a",
+                ItemId::from(&i.to_le_bytes()[..]),
+                Metadata::default(),
+            )?;
+            index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        }
+
+        assert!(Index::segment_paths(repository_path.path())?.len() < MAX_SEGMENTS);
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert_eq!(loaded.newest_items().count(), MAX_SEGMENTS + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_first_saves_made_at_the_same_time() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let writers = (0..4u8)
+            .map(|i| {
+                let repository_path = repository_path.path().to_path_buf();
+                thread::spawn(move || -> Result<()> {
+                    let mut index = Index::new()?;
+                    index.remember(
+                        "some writer",
+                        &Path::new("/some/source").join(i.to_string()),
+                        "a",
+                        ItemId::from(&[i; 32][..]),
+                        Metadata::default(),
+                    )?;
+                    index.save(&repository_path, secret, LAYOUT, &UNBOUNDED)
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert_eq!(loaded.newest_items().count(), 4);
+        Ok(())
+    }
+
+    /// as written by versions before segments and history, with ids in base64 and items without metadata
+    const JSON_INDEX: &str = r#"{
+  "newest_items_by_source_path": {
+    "/some/file": {
+      "relative_path": "data/02",
+      "original_source_path": "/some/file",
+      "id": "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=",
+      "version": 2
+    },
+    "/some/other file": {
+      "relative_path": "data/03",
+      "original_source_path": "/some/other file",
+      "id": "AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=",
+      "version": 1
+    }
+  },
+  "items_by_file_id": {
+    "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=": {
+      "relative_path": "data/01",
+      "original_source_path": "/some/file",
+      "id": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+      "version": 1
+    },
+    "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=": {
+      "relative_path": "data/02",
+      "original_source_path": "/some/file",
+      "id": "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=",
+      "version": 2
+    },
+    "AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=": {
+      "relative_path": "data/03",
+      "original_source_path": "/some/other file",
+      "id": "AwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwM=",
+      "version": 1
+    }
+  },
+  "version": 7
+}"#;
+
+    #[test]
+    fn migrate_json_index_when_compacted() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let index_file_path = Index::index_file_path_for_repository_path(repository_path.path())?;
+        Index::write_encrypted(&index_file_path, JSON_INDEX.as_bytes(), secret, LAYOUT, None)?;
+        let file = Path::new("/some/file");
+
+        let json = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let json_format = Index::format(repository_path.path(), secret, LAYOUT)?;
+        Index::compact(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let binary = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert_eq!(json_format, IndexFormat::Json);
+        assert_eq!(Index::format(repository_path.path(), secret, LAYOUT)?, IndexFormat::Binary);
+        let history =
+            |index: &Index| -> Result<Vec<ItemId>> { Ok(index.history(file)?.iter().map(|item| item.id()).collect()) };
+        let ids = vec![ItemId::from(&[1u8; 32][..]), ItemId::from(&[2u8; 32][..])];
+        assert_eq!(history(&json)?, ids);
+        assert_eq!(history(&binary)?, ids);
+        assert_eq!(binary.history(Path::new("/some/other file"))?.len(), 1);
+        assert_eq!(
+            binary.newest_item_by_source_path(file)?.map(|item| item.id()),
+            Some(ids[1].clone())
+        );
+        assert_eq!(binary.newest_items_by_source_path, json.newest_items_by_source_path);
+        assert_eq!(binary.items_by_file_id, json.items_by_file_id);
+        Ok(())
+    }
+
+    #[test]
+    fn page_index_over_its_memory_budget_from_disk() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let mut index = Index::new()?;
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        for i in 0..2_000u32 {
+            index.remember(
+                "some writer",
+                &Path::new("/some/source").join(format!("file {}", i % 1_000)),
+                &format!("data/{}", i),
+                ItemId::from(&i.to_le_bytes()[..]),
+                Metadata::default(),
+            )?;
+        }
+        index.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        Index::compact(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let whole = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let spill_directory = tempdir()?;
+        let memory_budget = MemoryBudget::new(64 * 1024).with_spill_directory(spill_directory.path());
+        let paged = Index::load(repository_path.path(), secret, LAYOUT, &memory_budget)?;
+
+        assert!(!whole.is_paged());
+        assert!(paged.is_paged());
+        assert_eq!(paged, whole);
+        let path = Path::new("/some/source/file 7");
+        assert_eq!(
+            paged.newest_item_by_source_path(path)?,
+            whole.newest_item_by_source_path(path)?
+        );
+        assert_eq!(paged.history(path)?.len(), 2);
+        let id = ItemId::from(&1_007u32.to_le_bytes()[..]);
+        assert_eq!(paged.item_by_id(&id)?, whole.item_by_id(&id)?);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_saves_of_instances_loaded_before_fence() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let path = Path::new("/some/file");
+        Index::new()?.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let mut stale = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        stale.remember("some writer", path, "a", ItemId::from(&[1u8; 32][..]), Metadata::default())?;
+
+        let operation_locks_path = repository_path.path().join(OPERATION_LOCKS_DIR_NAME);
+        fs::create_dir_all(&operation_locks_path)?;
+        let operation_lock = Lock::lock(&operation_locks_path)?;
+        Index::compact_fencing(repository_path.path(), secret, LAYOUT, &UNBOUNDED, operation_lock.token())?;
+        operation_lock.release()?;
+        let refused = stale.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED);
+        // as if it checked the token right before the fence was set and wrote the segment right after
+        let segment_path = repository_path
+            .path()
+            .join(SEGMENTS_DIR_NAME)
+            .join(format!("stale.{}", SEGMENT_EXTENSION));
+        Index::write_encrypted(&segment_path, &format::encode(&stale.unsaved)?, secret, LAYOUT, None)?;
+
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert!(refused.unwrap_err().downcast_ref::<LockLost>().is_some());
+        assert_eq!(loaded.newest_item_by_source_path(path)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn not_bring_back_finished_checkpoints_when_merging() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = b"some secret";
+        let source_path = Path::new("/some/source");
+        let mut original = Index::new()?;
+        original.remember_checkpoint(source_path, &source_path.join("some file"));
+        original.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let mut finishing = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        finishing.forget_checkpoint(source_path);
+        finishing.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        let mut unrelated = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        unrelated.save(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+
+        let loaded = Index::load(repository_path.path(), secret, LAYOUT, &UNBOUNDED)?;
+        assert_eq!(loaded.checkpoint(source_path), None);
+
+        Ok(())
+    }
 }
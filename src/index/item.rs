@@ -1,7 +1,9 @@
+use std::time::SystemTime;
+
 use serde::{Deserialize, Serialize};
 
-use crate::repository::{item::RepositoryItem, ItemId};
-use crate::version::Version;
+use crate::repository::{item::RepositoryItem, metadata::Metadata, ItemId};
+use crate::version::{Causality, Version, VersionVector};
 
 #[derive(Clone, Debug, PartialOrd, PartialEq, Ord, Eq, Serialize, Deserialize)]
 pub struct IndexItem {
@@ -9,24 +11,64 @@ pub struct IndexItem {
     original_source_path: String,
     id: ItemId,
     version: Version,
+    #[serde(default)]
+    metadata: Metadata,
+    /// empty for items stored before there were version vectors
+    #[serde(default)]
+    clock: VersionVector,
+    /// when this version was backed up, not known for items stored before there was version history
+    #[serde(default)]
+    stored: Option<SystemTime>,
 }
 
 impl IndexItem {
-    pub fn from(original_source_path: String, relative_path: String, id: ItemId, version: Version) -> IndexItem {
+    pub fn from(
+        original_source_path: String,
+        relative_path: String,
+        id: ItemId,
+        version: Version,
+        metadata: Metadata,
+    ) -> IndexItem {
         IndexItem {
             relative_path,
             original_source_path,
             id,
             version,
+            metadata,
+            clock: VersionVector::default(),
+            stored: None,
         }
     }
 
-    pub fn next_version(&self, id: ItemId, relative_path: String) -> IndexItem {
-        IndexItem {
-            original_source_path: self.original_source_path.clone(),
-            version: self.version.next(),
-            relative_path,
-            id,
+    pub fn with_stored(mut self, stored: SystemTime) -> Self {
+        self.stored = Some(stored);
+        self
+    }
+
+    pub fn stored(&self) -> Option<SystemTime> {
+        self.stored
+    }
+
+    pub fn with_clock(mut self, clock: VersionVector) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &VersionVector {
+        &self.clock
+    }
+
+    /// whether this item was made before or after `other`, or without knowing of it.
+    /// Items with the same clock but different contents were made by the same writer at the same time,
+    /// e.g. by two processes on one host, and only their version numbers can tell them apart
+    pub fn causality(&self, other: &IndexItem) -> Causality {
+        match self.clock.compare(&other.clock) {
+            Causality::Same if self.id != other.id => match self.version.cmp(&other.version) {
+                std::cmp::Ordering::Less => Causality::Before,
+                std::cmp::Ordering::Equal => Causality::Concurrent,
+                std::cmp::Ordering::Greater => Causality::After,
+            },
+            causality => causality,
         }
     }
 
@@ -38,6 +80,10 @@ impl IndexItem {
         self.id.clone()
     }
 
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+
     pub fn relative_path(&self) -> &str {
         &self.relative_path
     }
@@ -54,6 +100,9 @@ impl From<RepositoryItem> for IndexItem {
             original_source_path: i.original_source_path().to_string(),
             id: i.id().clone(),
             version: *i.version(),
+            metadata: *i.metadata(),
+            clock: VersionVector::default(),
+            stored: i.stored(),
         }
     }
 }
@@ -1,24 +1,98 @@
 use anyhow::Result;
 use anyhow::*;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use fail::fail_point;
+use nix::{
+    errno::Errno,
+    sys::signal::kill,
+    unistd::{gethostname, Pid},
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{remove_file, File},
-    io::Write,
+    fs::{self, remove_file, File, OpenOptions},
+    io::{self, Write},
     path::{Path, PathBuf},
-    time::Instant,
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use uuid::Uuid;
-use walkdir::WalkDir;
 
 use rand::{rngs::OsRng, RngCore};
 use std::{thread, time};
 
 pub struct Lock {
     path: PathBuf,
+    index_directory: PathBuf,
+    token: u64,
+    heartbeat: Option<Heartbeat>,
+}
+
+/// the lock was broken while held, e.g. because its owner was suspended for longer than the heartbeat expiry,
+/// and whatever it protects may have changed since
+#[derive(Debug)]
+pub struct LockLost;
+
+/// shared locks are held alongside each other, an exclusive lock is held alone.
+/// A waiting exclusive lock keeps new shared ones out, so that writers are not starved by a stream of readers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockKind {
+    Shared,
+    /// also assumed for locks written before there were kinds
+    #[default]
+    Exclusive,
+}
+
+/// who holds a lock, kept as JSON in the lock file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub id: Uuid,
+    #[serde(default)]
+    pub kind: LockKind,
+    pub hostname: String,
+    pub pid: u32,
+    /// start time of the process in clock ticks since boot where known, tells the owner from a process that got its pid later
+    pub process_start: Option<u64>,
+    /// seconds since the unix epoch, refreshed for as long as the lock is held
+    pub heartbeat: u64,
+    /// fencing token the lock was acquired with, 0 for locks written before there were tokens
+    #[serde(default)]
+    pub token: u64,
+}
+
+/// a lock file found in a repository
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockEntry {
+    pub path: PathBuf,
+    /// `None` when the lock file cannot be read, e.g. when it was written by an older version
+    pub owner: Option<LockOwner>,
+    /// time of the last heartbeat, or of the last change to the lock file when there is no owner to tell
+    pub heartbeat: SystemTime,
+    /// the owner is gone or stopped sending heartbeats, the lock can be broken
+    pub stale: bool,
+}
+
+/// keeps refreshing the heartbeat in a lock file until dropped
+struct Heartbeat {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
 }
 
 const MAX_TIMEOUT_MILLIS: u16 = 8192;
 const FILE_EXTENSION: &str = ".lock";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// a lock without a heartbeat for this long belongs to a process that is gone or suspended
+const HEARTBEAT_EXPIRY: Duration = Duration::from_secs(30);
+/// counter of exclusive locks ever acquired in a directory, only ever goes up
+const FENCING_TOKEN_FILE_NAME: &str = "fencing_token";
+
+/// what a lock being acquired should do about the locks already there
+enum Contention {
+    None,
+    /// only shared locks are in the way, an exclusive lock waits for them to go keeping its lock file
+    SharedOnly,
+    Exclusive,
+}
 
 impl Lock {
     #[allow(clippy::self_named_constructors)]
@@ -27,19 +101,72 @@ impl Lock {
     }
 
     pub fn lock_with_timeout(index_directory: &Path, max_timeout_millis: u16) -> Result<Self> {
+        Lock::acquire(index_directory, LockKind::Exclusive, max_timeout_millis)
+    }
+
+    pub fn shared(index_directory: &Path) -> Result<Self> {
+        Lock::acquire(index_directory, LockKind::Shared, MAX_TIMEOUT_MILLIS)
+    }
+
+    pub fn acquire(index_directory: &Path, kind: LockKind, max_timeout_millis: u16) -> Result<Self> {
         let mut buffer = [0u8; 16];
         OsRng.fill_bytes(&mut buffer);
         let id = Uuid::from_bytes(buffer);
-        Lock::wait_to_have_sole_lock(id, index_directory, max_timeout_millis)?;
+        Lock::wait_to_hold_lock(id, kind, index_directory, max_timeout_millis)?;
         let path = Lock::lock_file_path(index_directory, id)?;
-        Ok(Lock { path })
+        let mut lock = Lock {
+            path: path.clone(),
+            index_directory: index_directory.to_path_buf(),
+            token: 0,
+            heartbeat: None,
+        };
+        lock.token = match kind {
+            LockKind::Exclusive => Lock::issue_token(index_directory)?,
+            LockKind::Shared => Lock::current_token(index_directory)?,
+        };
+        write_owner(&path, &LockOwner::current(id, kind, lock.token))?;
+        lock.heartbeat = Some(Heartbeat::start(id, kind, lock.token, path));
+        Ok(lock)
+    }
+
+    /// fencing token of this lock, every exclusive lock gets a greater one than all before it
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// fails with `LockLost` if the lock was broken or an exclusive lock was acquired after it,
+    /// for checking right before writing anything the lock protects
+    pub fn ensure_held(&self) -> Result<()> {
+        if !self.path.exists() || Lock::current_token(&self.index_directory)? > self.token {
+            return Err(LockLost.into());
+        }
+        Ok(())
     }
 
-    pub fn release(self) -> Result<()> {
+    pub fn release(mut self) -> Result<()> {
+        self.heartbeat.take();
         self.delete_lock_file()?;
         Ok(())
     }
 
+    /// all lock files in the repository, whoever holds them
+    pub fn list(index_directory: &Path) -> Result<Vec<LockEntry>> {
+        let mut locks = vec![];
+        for entry in fs::read_dir(index_directory)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(FILE_EXTENSION) {
+                match LockEntry::read(&path) {
+                    Result::Ok(lock) => locks.push(lock),
+                    // released in the meantime
+                    Err(_) if !path.exists() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        locks.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(locks)
+    }
+
     fn delete_lock_file(&self) -> Result<()> {
         if self.path.exists() {
             remove_file(&self.path)?;
@@ -47,58 +174,91 @@ impl Lock {
         Ok(())
     }
 
-    fn wait_to_have_sole_lock(lock_id: Uuid, index_directory: &Path, max_timeout_millis: u16) -> Result<()> {
+    fn wait_to_hold_lock(lock_id: Uuid, kind: LockKind, index_directory: &Path, max_timeout_millis: u16) -> Result<()> {
         let start_time = Instant::now();
-        let _ = Lock::create_lock_file(lock_id, index_directory);
-        while !Lock::sole_lock(lock_id, index_directory)? {
-            let path = Lock::lock_file_path(index_directory, lock_id)?;
-            if path.exists() {
-                remove_file(path)?;
+        let path = Lock::lock_file_path(index_directory, lock_id)?;
+        loop {
+            if !path.exists() {
+                // timeout will take care of permanent errors
+                let _ = Lock::create_lock_file(lock_id, kind, index_directory);
+            }
+            match Lock::contention(&path, kind, index_directory)? {
+                Contention::None => return Ok(()),
+                Contention::SharedOnly if kind == LockKind::Exclusive => {}
+                _ => {
+                    if path.exists() {
+                        remove_file(&path)?;
+                    }
+                }
             }
+            Lock::break_stale_locks(index_directory);
             let sleep_duration = time::Duration::from_millis((OsRng.next_u32() % 64).into());
             thread::sleep(sleep_duration);
 
-            // timeout will take care of permanent errors
-            let _ = Lock::create_lock_file(lock_id, index_directory);
-
             if start_time.elapsed().as_millis() > max_timeout_millis.into() {
+                let _ = remove_file(&path);
                 return Err(anyhow!("timed out waiting on lock"));
             }
         }
-        Ok(())
     }
 
-    fn sole_lock(lock_id: Uuid, index_directory: &Path) -> Result<bool> {
-        let my_lock_file_path = Lock::lock_file_path(index_directory, lock_id)?;
-
-        let all_locks_count =
-            Lock::count_files(|e| e.file_name().to_string_lossy().ends_with(FILE_EXTENSION), index_directory)?;
-        if all_locks_count != 1 {
-            return Ok(false);
-        }
-        let my_locks_count = Lock::count_files(|e| e.path() == my_lock_file_path, index_directory)?;
-        if my_locks_count != 1 {
-            return Ok(false);
+    /// removes locks left behind by processes that crashed or were suspended, errors are left for the timeout to report
+    fn break_stale_locks(index_directory: &Path) {
+        for lock in Lock::list(index_directory).unwrap_or_default() {
+            if lock.stale {
+                log::warn!("breaking stale lock {}", lock);
+                let _ = lock.remove();
+            }
         }
-        Ok(true)
     }
 
-    fn count_files<P>(predicate: P, directory: &Path) -> Result<usize>
-    where
-        P: Fn(&walkdir::DirEntry) -> bool,
-    {
-        let walker = WalkDir::new(directory);
-        let matching = walker.into_iter().filter_map(|e| e.ok()).filter(predicate);
-        Ok(matching.count())
+    /// what stands in the way of the lock at `my_lock_file_path`, which has to be there to be held at all
+    fn contention(my_lock_file_path: &Path, kind: LockKind, index_directory: &Path) -> Result<Contention> {
+        let locks = Lock::list(index_directory)?;
+        if !locks.iter().any(|lock| lock.path == my_lock_file_path) {
+            return Ok(Contention::Exclusive);
+        }
+        let others = locks.iter().filter(|lock| lock.path != my_lock_file_path);
+        let mut contention = Contention::None;
+        for other in others {
+            match other.owner.as_ref().map_or(LockKind::Exclusive, |owner| owner.kind) {
+                LockKind::Exclusive => return Ok(Contention::Exclusive),
+                LockKind::Shared if kind == LockKind::Exclusive => contention = Contention::SharedOnly,
+                LockKind::Shared => {}
+            }
+        }
+        Ok(contention)
     }
 
-    fn create_lock_file(lock_id: Uuid, index_directory: &Path) -> Result<()> {
+    fn create_lock_file(lock_id: Uuid, kind: LockKind, index_directory: &Path) -> Result<()> {
         let lock_file_path = Lock::lock_file_path(index_directory, lock_id)?;
         fail_point!("create-lock-file", |e: Option<String>| Err(anyhow!(e.unwrap())));
         let mut file = File::create(lock_file_path)?;
-        let lock_id_text = lock_id.as_hyphenated().to_string();
-        let lock_id_bytes = lock_id_text.as_bytes();
-        Ok(file.write_all(lock_id_bytes)?)
+        let owner = serde_json::to_vec(&LockOwner::current(lock_id, kind, 0))?;
+        Ok(file.write_all(&owner)?)
+    }
+
+    /// greatest token issued in `index_directory` so far, 0 before the first exclusive lock
+    pub(crate) fn current_token(index_directory: &Path) -> Result<u64> {
+        let path = index_directory.join(FENCING_TOKEN_FILE_NAME);
+        if !path.exists() {
+            return Ok(0);
+        }
+        let token = fs::read_to_string(&path)?;
+        token
+            .trim()
+            .parse()
+            .with_context(|| format!("cannot read fencing token from {}", path.to_string_lossy()))
+    }
+
+    /// only called while holding an exclusive lock, so nobody else is issuing tokens at the same time
+    fn issue_token(index_directory: &Path) -> Result<u64> {
+        let token = Lock::current_token(index_directory)? + 1;
+        let path = index_directory.join(FENCING_TOKEN_FILE_NAME);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, token.to_string())?;
+        fs::rename(&temporary, &path)?;
+        Ok(token)
     }
 
     fn lock_file_path(path: &Path, lock_id: Uuid) -> Result<PathBuf> {
@@ -109,15 +269,176 @@ impl Lock {
 
 impl Drop for Lock {
     fn drop(&mut self) {
+        self.heartbeat.take();
         let _ = self.delete_lock_file();
     }
 }
 
+impl LockOwner {
+    fn current(id: Uuid, kind: LockKind, token: u64) -> Self {
+        let pid = std::process::id();
+        LockOwner {
+            id,
+            kind,
+            hostname: hostname(),
+            pid,
+            process_start: process_start(pid),
+            heartbeat: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()),
+            token,
+        }
+    }
+
+    fn is_gone(&self) -> bool {
+        if self.hostname != hostname() {
+            return false;
+        }
+        let pid = Pid::from_raw(self.pid as i32);
+        if let Err(Errno::ESRCH) = kill(pid, None) {
+            return true;
+        }
+        self.process_start.is_some() && process_start(self.pid) != self.process_start
+    }
+}
+
+impl LockEntry {
+    fn read(path: &Path) -> Result<Self> {
+        let owner = serde_json::from_slice::<LockOwner>(&fs::read(path)?).ok();
+        let heartbeat = match &owner {
+            Some(owner) => UNIX_EPOCH + Duration::from_secs(owner.heartbeat),
+            None => path.metadata()?.modified()?,
+        };
+        let expired = SystemTime::now()
+            .duration_since(heartbeat)
+            .map_or(false, |age| age > heartbeat_expiry());
+        let stale = expired || owner.as_ref().map_or(false, |owner| owner.is_gone());
+        Ok(LockEntry {
+            path: path.to_path_buf(),
+            owner,
+            heartbeat,
+            stale,
+        })
+    }
+
+    /// breaks the lock, whether it is stale or not
+    pub fn remove(&self) -> Result<()> {
+        remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for LockEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let age = SystemTime::now().duration_since(self.heartbeat).unwrap_or_default().as_secs();
+        match &self.owner {
+            Some(owner) => write!(
+                f,
+                "{} {} lock held by pid {} on {}, last heartbeat {}s ago",
+                owner.id, owner.kind, owner.pid, owner.hostname, age
+            )?,
+            None => write!(f, "{} of unknown owner, changed {}s ago", self.path.to_string_lossy(), age)?,
+        }
+        if self.stale {
+            write!(f, ", stale")?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::fmt::Display for LockKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockKind::Shared => write!(f, "shared"),
+            LockKind::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+impl std::fmt::Display for LockLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lock was broken and taken over while held")
+    }
+}
+
+impl std::error::Error for LockLost {}
+
+impl Heartbeat {
+    fn start(lock_id: Uuid, kind: LockKind, token: u64, path: PathBuf) -> Self {
+        let (stop, stopped) = crossbeam_channel::bounded(0);
+        let thread = thread::spawn(move || Heartbeat::beat(lock_id, kind, token, &path, stopped));
+        Heartbeat {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    fn beat(lock_id: Uuid, kind: LockKind, token: u64, path: &Path, stopped: Receiver<()>) {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT_INTERVAL) {
+            match refresh_owner(path, &LockOwner::current(lock_id, kind, token)) {
+                io::Result::Ok(()) => {}
+                // a lock that was broken stays broken, `ensure_held` tells its holder it was lost
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+                Err(e) => log::warn!("cannot refresh lock {}: {}", path.to_string_lossy(), e),
+            }
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// replaces the lock file atomically, so that it is never seen half written
+fn write_owner(path: &Path, owner: &LockOwner) -> Result<()> {
+    let temporary = path.with_extension("lock.tmp");
+    fs::write(&temporary, serde_json::to_vec(owner)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// rewrites the lock file where it is, never creating it, so that a lock broken in the meantime is not brought back.
+/// Readers may see it empty for a moment, which they take for a fresh lock of an unknown owner
+fn refresh_owner(path: &Path, owner: &LockOwner) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
+    file.write_all(&serde_json::to_vec(owner)?)
+}
+
+/// `HEARTBEAT_EXPIRY`, which tests suspending a process can shorten to a number of seconds
+fn heartbeat_expiry() -> Duration {
+    fail_point!("lock-heartbeat-expiry", |seconds: Option<String>| {
+        Duration::from_secs(seconds.and_then(|seconds| seconds.parse().ok()).unwrap_or_default())
+    });
+    HEARTBEAT_EXPIRY
+}
+
+pub(crate) fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    gethostname(&mut buffer).map_or_else(|_| String::new(), |name| name.to_string_lossy().to_string())
+}
+
+/// start time of a process in clock ticks since boot, only known on linux
+fn process_start(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses can contain spaces, the fields after it cannot
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
 #[cfg(test)]
 mod must {
-    use super::Lock;
+    use std::{
+        fs, thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{hostname, Lock, LockKind, LockLost, LockOwner};
     use anyhow::Result;
     use pretty_assertions::assert_eq;
+    use uuid::Uuid;
 
     use tempfile::tempdir;
     #[cfg(feature = "failpoints")]
@@ -132,7 +453,163 @@ mod must {
         }
         let entries = temp_dir.path().read_dir()?.count();
 
-        assert_eq!(entries, initial_number_of_entries);
+        // the fencing token counter stays behind
+        assert_eq!(entries, initial_number_of_entries + 1);
+        assert!(Lock::list(temp_dir.path())?.is_empty());
+        Ok(())
+    }
+
+    fn seconds_ago(seconds: u64) -> Result<u64> {
+        Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() - seconds)
+    }
+
+    fn write_foreign_lock(directory: &std::path::Path, hostname: String, pid: u32, heartbeat: u64) -> Result<()> {
+        let owner = LockOwner {
+            id: Uuid::new_v4(),
+            kind: LockKind::Exclusive,
+            hostname,
+            pid,
+            process_start: None,
+            heartbeat,
+            token: 0,
+        };
+        fs::write(directory.join(format!("{}.lock", owner.id)), serde_json::to_vec(&owner)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn break_lock_of_process_that_is_gone() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let mut child = std::process::Command::new("true").spawn()?;
+        child.wait()?;
+        write_foreign_lock(temp_dir.path(), hostname(), child.id(), seconds_ago(0)?)?;
+
+        let lock = Lock::lock_with_timeout(temp_dir.path(), 1000)?;
+
+        assert_eq!(Lock::list(temp_dir.path())?.len(), 1);
+        lock.release()
+    }
+
+    #[test]
+    fn break_lock_with_expired_heartbeat() -> Result<()> {
+        let temp_dir = tempdir()?;
+        write_foreign_lock(temp_dir.path(), "some other host".to_string(), 1, seconds_ago(60)?)?;
+
+        assert!(Lock::list(temp_dir.path())?[0].stale);
+        Lock::lock_with_timeout(temp_dir.path(), 1000)?.release()
+    }
+
+    #[test]
+    fn wait_on_live_lock_of_another_host() -> Result<()> {
+        let temp_dir = tempdir()?;
+        write_foreign_lock(temp_dir.path(), "some other host".to_string(), 1, seconds_ago(0)?)?;
+
+        assert!(Lock::lock_with_timeout(temp_dir.path(), 200).is_err());
+        let locks = Lock::list(temp_dir.path())?;
+        assert_eq!(locks.len(), 1);
+        assert!(!locks[0].stale);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_heartbeat_fresh_while_held() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let lock = Lock::lock(temp_dir.path())?;
+        let heartbeat = |directory| -> Result<u64> { Ok(Lock::list(directory)?[0].owner.clone().unwrap().heartbeat) };
+        let first = heartbeat(temp_dir.path())?;
+
+        thread::sleep(Duration::from_millis(1500));
+
+        let locks = Lock::list(temp_dir.path())?;
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner.as_ref().unwrap().pid, std::process::id());
+        assert!(!locks[0].stale);
+        assert!(heartbeat(temp_dir.path())? > first);
+        lock.release()
+    }
+
+    #[test]
+    fn issue_greater_token_to_each_exclusive_lock() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let first = Lock::lock(temp_dir.path())?;
+        first.release()?;
+
+        let second = Lock::lock(temp_dir.path())?;
+
+        assert_eq!(second.token(), 2);
+        assert_eq!(Lock::list(temp_dir.path())?[0].owner.as_ref().unwrap().token, 2);
+        second.release()?;
+        assert_eq!(Lock::shared(temp_dir.path())?.token(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn know_it_was_lost_once_broken_and_taken_over() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let suspended = Lock::lock(temp_dir.path())?;
+        suspended.ensure_held()?;
+
+        Lock::list(temp_dir.path())?[0].remove()?;
+        let newer = Lock::lock(temp_dir.path())?;
+
+        let lost = suspended.ensure_held().unwrap_err();
+        assert!(lost.downcast_ref::<LockLost>().is_some());
+        newer.ensure_held()?;
+        newer.release()
+    }
+
+    #[test]
+    fn not_bring_back_lock_broken_while_held() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let lock = Lock::lock(temp_dir.path())?;
+
+        Lock::list(temp_dir.path())?[0].remove()?;
+        thread::sleep(Duration::from_millis(1500));
+
+        assert!(Lock::list(temp_dir.path())?.is_empty());
+        let lost = lock.ensure_held().unwrap_err();
+        assert!(lost.downcast_ref::<LockLost>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn let_shared_locks_be_held_together() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let first = Lock::shared(temp_dir.path())?;
+
+        let second = Lock::acquire(temp_dir.path(), LockKind::Shared, 200)?;
+
+        assert_eq!(Lock::list(temp_dir.path())?.len(), 2);
+        first.release()?;
+        second.release()
+    }
+
+    #[test]
+    fn hold_exclusive_lock_alone() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let shared = Lock::shared(temp_dir.path())?;
+        assert!(Lock::lock_with_timeout(temp_dir.path(), 200).is_err());
+        shared.release()?;
+
+        let exclusive = Lock::lock(temp_dir.path())?;
+
+        assert!(Lock::acquire(temp_dir.path(), LockKind::Shared, 200).is_err());
+        assert!(Lock::lock_with_timeout(temp_dir.path(), 200).is_err());
+        assert_eq!(Lock::list(temp_dir.path())?.len(), 1);
+        exclusive.release()
+    }
+
+    #[test]
+    fn keep_new_shared_locks_out_while_exclusive_lock_waits() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let directory = temp_dir.path().to_path_buf();
+        let shared = Lock::shared(temp_dir.path())?;
+        let waiting = thread::spawn(move || Lock::acquire(&directory, LockKind::Exclusive, 5000).map(|_| ()));
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(Lock::acquire(temp_dir.path(), LockKind::Shared, 300).is_err());
+        shared.release()?;
+        waiting.join().unwrap()?;
         Ok(())
     }
 
@@ -1,80 +1,291 @@
 use std::collections::HashMap;
-use std::{collections::hash_map::Iter, path::Path};
+use std::{
+    fmt::{self, Debug, Formatter},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::index::item::IndexItem;
-use crate::repository::ItemId;
-use crate::version::Version;
+use crate::index::paged::{MemoryBudget, PagedIter, PagedMap};
+use crate::repository::{metadata::Metadata, ItemId};
+use crate::version::{Causality, Version, VersionVector};
 use anyhow::Result;
 
+pub use format::IndexFormat;
+
+pub(crate) mod format;
+mod frames;
 mod io;
 pub mod item;
-mod lock;
+pub mod lock;
+pub(crate) mod paged;
 
+/// the maps that grow with the number of files backed up are paged out to disk past the memory budget,
+/// see `Index::with_memory_budget`
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Index {
-    newest_items_by_source_path: HashMap<String, IndexItem>,
-    items_by_file_id: HashMap<ItemId, IndexItem>,
+    newest_items_by_source_path: PagedMap<String, IndexItem>,
+    /// versions made by writers that did not know of the newest version of their path, nor of each other,
+    /// kept until the path is backed up again
+    #[serde(default)]
+    conflicting_items_by_source_path: HashMap<String, Vec<IndexItem>>,
+    items_by_file_id: PagedMap<ItemId, IndexItem>,
+    /// every version of every path, oldest first, apart from versions stored before there was version history
+    /// that share contents with another path, see `Index::backfill_history`
+    #[serde(default)]
+    history_by_source_path: PagedMap<String, Vec<IndexItem>>,
+    /// last path stored by an unfinished backup, keyed by the backup source path
+    #[serde(default)]
+    checkpoints: HashMap<String, String>,
+    /// changes made by this instance since it was loaded or last saved, written as a segment by `Index::save`
+    #[serde(skip)]
+    unsaved: Segment,
+    version: Version,
+    /// segments saved under an older operation lock token than this are ignored, set by `Repository::prune`
+    /// so that a backup resumed after its lock was broken cannot add contents that were removed in the meantime
+    #[serde(default)]
+    fence: u64,
+    /// operation lock token when this instance was loaded, which its segments are saved under
+    #[serde(skip)]
+    token: u64,
+}
+
+/// changes made by a single index save, kept in a file of its own until combined with the others by `Index::compact`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Segment {
+    /// in the order they were remembered
+    items: Vec<IndexItem>,
+    /// `None` for checkpoints that were forgotten
+    checkpoints: HashMap<String, Option<String>>,
     version: Version,
+    /// operation lock token of the index that saved it, see `Index::fence`
+    #[serde(default)]
+    token: u64,
 }
 
 impl Index {
     pub fn new() -> Result<Self> {
+        Index::with_memory_budget(&paged::UNBOUNDED)
+    }
+
+    /// an empty index that keeps to `memory_budget`, paging the rest of the items from disk
+    pub fn with_memory_budget(memory_budget: &MemoryBudget) -> Result<Self> {
         Ok(Index {
-            newest_items_by_source_path: Default::default(),
-            items_by_file_id: Default::default(),
+            newest_items_by_source_path: PagedMap::new(memory_budget.share(4)),
+            conflicting_items_by_source_path: Default::default(),
+            items_by_file_id: PagedMap::new(memory_budget.share(4)),
+            history_by_source_path: PagedMap::new(memory_budget.share(2)),
+            checkpoints: Default::default(),
+            unsaved: Default::default(),
             version: Version::default(),
+            fence: 0,
+            token: 0,
         })
     }
 
-    pub fn remember(&mut self, original_source_path: &Path, relative_path: &str, id: ItemId) {
-        let item = if let Some(old) = self
-            .newest_items_by_source_path
-            .get(&original_source_path.to_string_lossy().to_string())
-        {
-            old.next_version(id, relative_path.to_string())
-        } else {
-            IndexItem::from(
-                original_source_path.to_string_lossy().to_string(),
-                relative_path.to_string(),
-                id,
-                Version::default(),
-            )
-        };
+    /// the same index in maps that keep to `memory_budget`, for indexes that had to be read whole
+    fn within_memory_budget(self, memory_budget: &MemoryBudget) -> Result<Self> {
+        if memory_budget.is_unbounded() {
+            return Ok(self);
+        }
+        let mut index = Index::with_memory_budget(memory_budget)?;
+        for entry in self.newest_items_by_source_path.iter() {
+            let (path, item) = entry?;
+            index.newest_items_by_source_path.insert(path, item)?;
+        }
+        for entry in self.items_by_file_id.iter() {
+            let (id, item) = entry?;
+            index.items_by_file_id.insert(id, item)?;
+        }
+        for entry in self.history_by_source_path.iter() {
+            let (path, history) = entry?;
+            index.history_by_source_path.insert(path, history)?;
+        }
+        index.conflicting_items_by_source_path = self.conflicting_items_by_source_path;
+        index.checkpoints = self.checkpoints;
+        index.version = self.version;
+        index.fence = self.fence;
+        index.token = self.token;
+        Ok(index)
+    }
 
-        self.items_by_file_id.insert(item.id(), item.clone());
-        self.newest_items_by_source_path
-            .insert(original_source_path.to_string_lossy().to_string(), item);
+    /// whether some of the index is read from disk as needed rather than held in memory
+    pub fn is_paged(&self) -> bool {
+        self.newest_items_by_source_path.is_paged()
+            || self.items_by_file_id.is_paged()
+            || self.history_by_source_path.is_paged()
     }
 
-    pub fn newest_item_by_source_path(&self, path: &Path) -> Result<Option<IndexItem>> {
+    /// records a new version of `original_source_path` made by `writer`, superseding all versions known so far,
+    /// conflicting ones included
+    pub fn remember(
+        &mut self,
+        writer: &str,
+        original_source_path: &Path,
+        relative_path: &str,
+        id: ItemId,
+        metadata: Metadata,
+    ) -> Result<()> {
+        let source_path = original_source_path.to_string_lossy().to_string();
+        let known = self.heads(&source_path)?;
+        let version = known.iter().map(|item| item.version().next()).max().unwrap_or_default();
+        let clock = VersionVector::next(writer, known.iter().map(|item| item.clock()));
+        let item = IndexItem::from(source_path, relative_path.to_string(), id, version, metadata)
+            .with_clock(clock)
+            .with_stored(SystemTime::now());
+
+        self.items_by_file_id.insert(item.id(), item.clone())?;
+        self.add_to_history(item.clone())?;
+        self.add_head(item.clone())?;
+        self.unsaved.items.push(item);
+        Ok(())
+    }
+
+    /// every version of `path`, oldest first
+    pub fn history(&self, path: &Path) -> Result<Vec<IndexItem>> {
         Ok(self
-            .newest_items_by_source_path
+            .history_by_source_path
+            .get(&path.to_string_lossy().to_string())?
+            .unwrap_or_default())
+    }
+
+    /// versions of `path` that conflict with its newest one, see `Index::add_head`
+    pub fn conflicting_items(&self, path: &Path) -> Vec<IndexItem> {
+        self.conflicting_items_by_source_path
             .get(&path.to_string_lossy().to_string())
-            .cloned())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// source paths with conflicting versions
+    pub fn conflicting_paths(&self) -> impl Iterator<Item = &str> {
+        self.conflicting_items_by_source_path.keys().map(|path| path.as_str())
+    }
+
+    /// adding an item that is already there changes nothing, so that segments can be applied again
+    fn add_to_history(&mut self, item: IndexItem) -> Result<()> {
+        let source_path = item.original_source_path().to_string();
+        let mut history = self.history_by_source_path.get(&source_path)?.unwrap_or_default();
+        if !history.contains(&item) {
+            history.push(item);
+            history.sort_by_key(|item| (item.version(), item.stored(), item.id()));
+            self.history_by_source_path.insert(source_path, history)?;
+        }
+        Ok(())
+    }
+
+    /// indexes written before there was version history only know each content id once,
+    /// so this recovers the history of each path as far as it can.
+    /// Those indexes are read whole anyway, so the items are gathered in memory
+    fn backfill_history(&mut self) -> Result<()> {
+        if !self.history_by_source_path.is_empty() {
+            return Ok(());
+        }
+        let items = self
+            .items_by_file_id
+            .iter()
+            .map(|entry| entry.map(|(_, item)| item))
+            .collect::<Result<Vec<_>>>()?;
+        for item in items {
+            self.add_to_history(item)?;
+        }
+        Ok(())
+    }
+
+    /// newest version of a path along with the versions conflicting with it
+    fn heads(&self, source_path: &str) -> Result<Vec<IndexItem>> {
+        let mut heads = self
+            .conflicting_items_by_source_path
+            .get(source_path)
+            .cloned()
+            .unwrap_or_default();
+        heads.extend(self.newest_items_by_source_path.get(&source_path.to_string())?);
+        Ok(heads)
+    }
+
+    /// keeps `item` as a newest version of its path unless a version made after it is known,
+    /// dropping versions it was made after. Versions made without knowing of each other are all kept,
+    /// the one with the greatest version number becomes the newest and the others conflicting,
+    /// so that the outcome does not depend on the order items were added in
+    fn add_head(&mut self, item: IndexItem) -> Result<()> {
+        let source_path = item.original_source_path().to_string();
+        let mut heads = self.heads(&source_path)?;
+        let superseded = heads
+            .iter()
+            .any(|head| matches!(item.causality(head), Causality::Before | Causality::Same));
+        if superseded {
+            return Ok(());
+        }
+        heads.retain(|head| item.causality(head) != Causality::After);
+        heads.push(item);
+        heads.sort_by_key(|head| (head.version(), head.id()));
+        if let Some(newest) = heads.pop() {
+            self.newest_items_by_source_path.insert(source_path.clone(), newest)?;
+        }
+        if heads.is_empty() {
+            self.conflicting_items_by_source_path.remove(&source_path);
+        } else {
+            self.conflicting_items_by_source_path.insert(source_path, heads);
+        }
+        Ok(())
+    }
+
+    pub fn newest_item_by_source_path(&self, path: &Path) -> Result<Option<IndexItem>> {
+        self.newest_items_by_source_path.get(&path.to_string_lossy().to_string())
     }
 
     pub fn item_by_id(&self, id: &ItemId) -> Result<Option<IndexItem>> {
-        Ok(self.items_by_file_id.get(id).cloned())
+        self.items_by_file_id.get(id)
+    }
+
+    /// every stored version, keyed by content id, in order of the ids
+    pub fn items_by_id(&self) -> impl Iterator<Item = Result<(ItemId, IndexItem)>> + '_ {
+        self.items_by_file_id.iter()
     }
 
-    pub fn newest_items(&self) -> IndexItemIterator {
+    pub fn newest_items(&self) -> IndexItemIterator<'_> {
         IndexItemIterator {
             iterator: self.newest_items_by_source_path.iter(),
         }
     }
+
+    pub fn remember_checkpoint(&mut self, backup_source_path: &Path, last_stored_path: &Path) {
+        let key = backup_source_path.to_string_lossy().to_string();
+        let last_stored_path = last_stored_path.to_string_lossy().to_string();
+        self.checkpoints.insert(key.clone(), last_stored_path.clone());
+        self.unsaved.checkpoints.insert(key, Some(last_stored_path));
+    }
+
+    pub fn forget_checkpoint(&mut self, backup_source_path: &Path) {
+        let key = backup_source_path.to_string_lossy().to_string();
+        self.checkpoints.remove(&key);
+        self.unsaved.checkpoints.insert(key, None);
+    }
+
+    pub fn checkpoint(&self, backup_source_path: &Path) -> Option<PathBuf> {
+        self.checkpoints
+            .get(&backup_source_path.to_string_lossy().to_string())
+            .map(PathBuf::from)
+    }
 }
 
-#[derive(Debug)]
+/// newest items in path order, failing if the part of the index paged out to disk cannot be read
 pub struct IndexItemIterator<'a> {
-    iterator: Iter<'a, String, IndexItem>,
+    iterator: PagedIter<'a, String, IndexItem>,
 }
 
 impl<'a> Iterator for IndexItemIterator<'a> {
-    type Item = IndexItem;
+    type Item = Result<IndexItem>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iterator.next().map(|i| i.1.clone())
+        self.iterator.next().map(|entry| entry.map(|(_, item)| item))
+    }
+}
+
+impl<'a> Debug for IndexItemIterator<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexItemIterator").finish_non_exhaustive()
     }
 }
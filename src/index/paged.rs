@@ -0,0 +1,601 @@
+use std::{
+    collections::{btree_map, BTreeMap, HashMap},
+    fmt::{self, Debug, Formatter},
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    vec,
+};
+
+use anyhow::Result;
+use anyhow::*;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{de::DeserializeOwned, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
+use tempfile::TempDir;
+
+/// budget of a map that never goes to disk
+pub const UNBOUNDED: MemoryBudget = MemoryBudget {
+    bytes: usize::MAX,
+    spill_directory: None,
+};
+
+/// encoded bytes of entries read and written together
+const PAGE_LENGTH: u64 = 64 * 1024;
+/// runs are all merged into one once there are more of them, so that a lookup reads at most this many pages
+const MAX_RUNS: usize = 8;
+/// what an entry takes in memory on top of its encoded bytes
+const ENTRY_OVERHEAD: usize = 64;
+
+/// bytes of entries held in memory and the directory the rest is paged out to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    bytes: usize,
+    spill_directory: Option<PathBuf>,
+}
+
+/// a sorted map holding its entries in memory until they take more bytes than its budget,
+/// then writing them to disk as a run of sorted, encrypted pages of which only the first keys stay in memory.
+/// Entries are never removed, an entry inserted later shadows one with the same key in older runs
+pub struct PagedMap<K, V> {
+    hot: BTreeMap<K, V>,
+    hot_bytes: usize,
+    budget: usize,
+    spill_directory: Option<PathBuf>,
+    /// oldest first
+    runs: Vec<Run<K>>,
+    spill: Option<Spill>,
+    /// the page of each run read last, keyed by run id. Keys are mostly looked up in order,
+    /// so the next lookup tends to land on the same pages
+    cached: Mutex<HashMap<u64, CachedPage<K, V>>>,
+}
+
+struct Run<K> {
+    id: u64,
+    path: PathBuf,
+    pages: Vec<PageRef<K>>,
+    /// encrypted bytes of all pages
+    length: u64,
+}
+
+struct PageRef<K> {
+    first: K,
+    offset: u64,
+    length: u64,
+}
+
+struct CachedPage<K, V> {
+    page: usize,
+    entries: Arc<Vec<(K, V)>>,
+}
+
+/// a map's own directory in its spill directory, where its runs are written, removed along with it.
+/// They are encrypted with a key that only ever lives in memory, so that paths do not end up on disk in the clear
+struct Spill {
+    directory: TempDir,
+    cipher: XChaCha20Poly1305,
+    next_run: u64,
+}
+
+/// entries in key order
+pub struct PagedIter<'a, K, V> {
+    /// newest first, on equal keys the entry of the first one wins
+    cursors: Vec<Cursor<'a, K, V>>,
+    spill: Option<&'a Spill>,
+    started: bool,
+    failed: bool,
+}
+
+struct Cursor<'a, K, V> {
+    head: Option<(K, V)>,
+    source: Source<'a, K, V>,
+}
+
+enum Source<'a, K, V> {
+    Memory(btree_map::Iter<'a, K, V>),
+    Run {
+        run: &'a Run<K>,
+        next_page: usize,
+        entries: vec::IntoIter<(K, V)>,
+    },
+}
+
+impl<K, V> PagedMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    /// `UNBOUNDED` keeps everything in memory
+    pub fn new(budget: MemoryBudget) -> Self {
+        PagedMap {
+            hot: BTreeMap::new(),
+            hot_bytes: 0,
+            budget: budget.bytes,
+            spill_directory: budget.spill_directory,
+            runs: vec![],
+            spill: None,
+            cached: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hot.is_empty() && self.runs.is_empty()
+    }
+
+    /// whether some of the entries are on disk
+    pub fn is_paged(&self) -> bool {
+        !self.runs.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        if let Some(value) = self.hot.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        for run in self.runs.iter().rev() {
+            let page = run.pages.partition_point(|page| page.first <= *key);
+            if page == 0 {
+                continue;
+            }
+            let entries = self.page(run, page - 1)?;
+            if let Result::Ok(position) = entries.binary_search_by(|(candidate, _)| candidate.cmp(key)) {
+                return Ok(Some(entries[position].1.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let replaced = match self.hot.get(&key) {
+            Some(old) => entry_size(&key, old)?,
+            None => 0,
+        };
+        let added = entry_size(&key, &value)?;
+        self.hot.insert(key, value);
+        self.hot_bytes = self.hot_bytes.saturating_sub(replaced).saturating_add(added);
+        // a page is read into memory whole anyway, so at least that much is held whatever the budget
+        if self.hot_bytes > self.budget.max(PAGE_LENGTH as usize) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> PagedIter<'_, K, V> {
+        let mut cursors = vec![Cursor::new(Source::Memory(self.hot.iter()))];
+        cursors.extend(self.runs.iter().rev().map(Cursor::of_run));
+        PagedIter {
+            cursors,
+            spill: self.spill.as_ref(),
+            started: false,
+            failed: false,
+        }
+    }
+
+    /// writes the entries held in memory out as a new run
+    fn spill(&mut self) -> Result<()> {
+        if self.spill.is_none() {
+            let directory = self
+                .spill_directory
+                .as_ref()
+                .ok_or_else(|| anyhow!("no directory to page the index out to"))?;
+            self.spill = Some(Spill::new(directory)?);
+        }
+        let spill = self.spill.as_mut().ok_or_else(|| anyhow!("no spill directory"))?;
+        let id = spill.next_id();
+        let entries = mem::take(&mut self.hot);
+        self.hot_bytes = 0;
+        let run = spill.write_run(id, entries.into_iter().map(Ok))?;
+        log::debug!(
+            "paged {} pages of index entries out to {}",
+            run.pages.len(),
+            run.path.to_string_lossy()
+        );
+        self.runs.push(run);
+        // runs at least halve in size from the oldest to the newest, so that an entry is only rewritten
+        // a logarithmic number of times before it ends up in a run big enough to stay put
+        while let [.., older, newer] = &self.runs[..] {
+            if older.length > newer.length * 2 {
+                break;
+            }
+            self.merge_runs(self.runs.len() - 2)?;
+        }
+        if self.runs.len() > MAX_RUNS {
+            self.merge_runs(0)?;
+        }
+        Ok(())
+    }
+
+    /// merges the runs from `first` on into one
+    fn merge_runs(&mut self, first: usize) -> Result<()> {
+        let spill = self.spill.as_mut().ok_or_else(|| anyhow!("no spill directory"))?;
+        let id = spill.next_id();
+        let runs = self.runs.split_off(first);
+        let spill = &*spill;
+        let merged = spill.write_run(
+            id,
+            PagedIter {
+                cursors: runs.iter().rev().map(Cursor::<K, V>::of_run).collect(),
+                spill: Some(spill),
+                started: false,
+                failed: false,
+            },
+        )?;
+        for run in &runs {
+            fs::remove_file(&run.path)?;
+        }
+        self.runs.push(merged);
+        let mut cached = self.cached.lock().map_err(|_| anyhow!("page cache poisoned"))?;
+        cached.retain(|run, _| self.runs.iter().any(|kept| kept.id == *run));
+        Ok(())
+    }
+
+    fn page(&self, run: &Run<K>, page: usize) -> Result<Arc<Vec<(K, V)>>> {
+        let mut cached = self.cached.lock().map_err(|_| anyhow!("page cache poisoned"))?;
+        if let Some(cached) = cached.get(&run.id) {
+            if cached.page == page {
+                return Ok(cached.entries.clone());
+            }
+        }
+        let spill = self.spill.as_ref().ok_or_else(|| anyhow!("no spill directory"))?;
+        let entries = Arc::new(spill.read_page(run, page)?);
+        cached.insert(
+            run.id,
+            CachedPage {
+                page,
+                entries: entries.clone(),
+            },
+        );
+        Ok(entries)
+    }
+}
+
+fn entry_size<K: Serialize, V: Serialize>(key: &K, value: &V) -> Result<usize> {
+    let encoded = bincode::serialized_size(&(key, value))?;
+    Ok(usize::try_from(encoded)?.saturating_add(ENTRY_OVERHEAD))
+}
+
+impl MemoryBudget {
+    /// keeps about `bytes` in memory, paging the rest out to the directory set with `with_spill_directory`
+    pub fn new(bytes: usize) -> Self {
+        MemoryBudget {
+            bytes,
+            spill_directory: None,
+        }
+    }
+
+    pub fn with_spill_directory(mut self, directory: &Path) -> Self {
+        self.spill_directory = Some(directory.to_path_buf());
+        self
+    }
+
+    /// pages out to `directory` unless a spill directory was set already
+    pub(crate) fn with_default_spill_directory(self, directory: &Path) -> Self {
+        match self.spill_directory {
+            Some(_) => self,
+            None => self.with_spill_directory(directory),
+        }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.bytes == usize::MAX
+    }
+
+    /// the part of the budget given to one of several maps
+    pub(crate) fn share(&self, divisor: usize) -> Self {
+        MemoryBudget {
+            bytes: self.bytes / divisor,
+            spill_directory: self.spill_directory.clone(),
+        }
+    }
+}
+
+impl Spill {
+    fn new(spill_directory: &Path) -> Result<Self> {
+        fs::create_dir_all(spill_directory)?;
+        let directory = tempfile::Builder::new().prefix("bakare-index-").tempdir_in(spill_directory)?;
+        let key: [u8; 32] = rand::random();
+        Ok(Spill {
+            directory,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            next_run: 0,
+        })
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_run += 1;
+        self.next_run
+    }
+
+    /// `entries` have to come in key order
+    fn write_run<K, V>(&self, id: u64, entries: impl Iterator<Item = Result<(K, V)>>) -> Result<Run<K>>
+    where
+        K: Clone + Serialize,
+        V: Serialize,
+    {
+        let path = self.directory.path().join(format!("{}.run", id));
+        let mut file = BufWriter::new(File::create(&path)?);
+        let mut pages = vec![];
+        let mut page = vec![];
+        let mut page_length = 0;
+        let mut offset = 0;
+        for entry in entries {
+            let (key, value) = entry?;
+            page_length += bincode::serialized_size(&(&key, &value))?;
+            page.push((key, value));
+            if page_length >= PAGE_LENGTH {
+                pages.push(self.write_page(id, pages.len(), &page, &mut file, &mut offset)?);
+                page.clear();
+                page_length = 0;
+            }
+        }
+        if !page.is_empty() {
+            pages.push(self.write_page(id, pages.len(), &page, &mut file, &mut offset)?);
+        }
+        file.flush()?;
+        Ok(Run {
+            id,
+            path,
+            pages,
+            length: offset,
+        })
+    }
+
+    fn write_page<K, V>(
+        &self,
+        run: u64,
+        page: usize,
+        entries: &[(K, V)],
+        file: &mut impl Write,
+        offset: &mut u64,
+    ) -> Result<PageRef<K>>
+    where
+        K: Clone + Serialize,
+        V: Serialize,
+    {
+        let first = entries
+            .first()
+            .map(|(key, _)| key.clone())
+            .ok_or_else(|| anyhow!("cannot write an empty page"))?;
+        let encrypted = self
+            .cipher
+            .encrypt(&Spill::nonce(run, page), bincode::serialize(entries)?.as_slice())
+            .map_err(|e| anyhow!("{}", e))?;
+        file.write_all(&encrypted)?;
+        let length = u64::try_from(encrypted.len())?;
+        let page = PageRef {
+            first,
+            offset: *offset,
+            length,
+        };
+        *offset += length;
+        Ok(page)
+    }
+
+    fn read_page<K, V>(&self, run: &Run<K>, page: usize) -> Result<Vec<(K, V)>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        let location = run
+            .pages
+            .get(page)
+            .ok_or_else(|| anyhow!("run {} has no page {}", run.id, page))?;
+        let mut file = File::open(&run.path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut encrypted = vec![0; usize::try_from(location.length)?];
+        file.read_exact(&mut encrypted)?;
+        let decrypted = self
+            .cipher
+            .decrypt(&Spill::nonce(run.id, page), encrypted.as_slice())
+            .map_err(|e| anyhow!("cannot read page {} of {}: {}", page, run.path.to_string_lossy(), e))?;
+        Ok(bincode::deserialize(&decrypted)?)
+    }
+
+    fn nonce(run: u64, page: usize) -> XNonce {
+        let mut nonce = [0; 24];
+        nonce[0..8].copy_from_slice(&run.to_le_bytes());
+        nonce[8..16].copy_from_slice(&(page as u64).to_le_bytes());
+        *XNonce::from_slice(&nonce)
+    }
+}
+
+impl<'a, K, V> Cursor<'a, K, V> {
+    fn new(source: Source<'a, K, V>) -> Self {
+        Cursor { head: None, source }
+    }
+
+    fn of_run(run: &'a Run<K>) -> Self {
+        Cursor::new(Source::Run {
+            run,
+            next_page: 0,
+            entries: vec![].into_iter(),
+        })
+    }
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+where
+    K: Clone + DeserializeOwned,
+    V: Clone + DeserializeOwned,
+{
+    fn advance(&mut self, spill: Option<&Spill>) -> Result<()> {
+        self.head = match &mut self.source {
+            Source::Memory(iterator) => iterator.next().map(|(key, value)| (key.clone(), value.clone())),
+            Source::Run { run, next_page, entries } => loop {
+                if let Some(entry) = entries.next() {
+                    break Some(entry);
+                }
+                if *next_page == run.pages.len() {
+                    break None;
+                }
+                let spill = spill.ok_or_else(|| anyhow!("no spill directory"))?;
+                *entries = spill.read_page(run, *next_page)?.into_iter();
+                *next_page += 1;
+            },
+        };
+        Ok(())
+    }
+}
+
+impl<'a, K, V> PagedIter<'a, K, V>
+where
+    K: Ord + Clone + DeserializeOwned,
+    V: Clone + DeserializeOwned,
+{
+    fn advance(&mut self) -> Result<Option<(K, V)>> {
+        if !self.started {
+            self.started = true;
+            for cursor in &mut self.cursors {
+                cursor.advance(self.spill)?;
+            }
+        }
+        let smallest = self
+            .cursors
+            .iter()
+            .filter_map(|cursor| cursor.head.as_ref().map(|(key, _)| key))
+            .min()
+            .cloned();
+        let key = match smallest {
+            None => return Ok(None),
+            Some(key) => key,
+        };
+        let mut found = None;
+        for cursor in &mut self.cursors {
+            if cursor.head.as_ref().map_or(false, |(candidate, _)| *candidate == key) {
+                let entry = cursor.head.take();
+                cursor.advance(self.spill)?;
+                if found.is_none() {
+                    found = entry;
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl<'a, K, V> Iterator for PagedIter<'a, K, V>
+where
+    K: Ord + Clone + DeserializeOwned,
+    V: Clone + DeserializeOwned,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.advance() {
+            Result::Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<K, V> Default for PagedMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    fn default() -> Self {
+        PagedMap::new(UNBOUNDED)
+    }
+}
+
+impl<K, V> PartialEq for PagedMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: PartialEq + Clone + Serialize + DeserializeOwned,
+{
+    fn eq(&self, other: &Self) -> bool {
+        let mut ours = self.iter();
+        let mut theirs = other.iter();
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return true,
+                (Some(Result::Ok(a)), Some(Result::Ok(b))) if a == b => continue,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<K, V> Debug for PagedMap<K, V>
+where
+    K: Ord + Clone + Debug + Serialize + DeserializeOwned,
+    V: Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter().filter_map(|entry| entry.ok())).finish()
+    }
+}
+
+/// written as a plain map, which is what JSON indexes hold
+impl<K, V> Serialize for PagedMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for entry in self.iter() {
+            let (key, value) = entry.map_err(|e| <S::Error as serde::ser::Error>::custom(format!("{:#}", e)))?;
+            map.serialize_entry(&key, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// read whole into memory, the way JSON indexes always were
+impl<'de, K, V> Deserialize<'de> for PagedMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = BTreeMap::<K, V>::deserialize(deserializer)?;
+        let mut map = PagedMap::default();
+        for (key, value) in entries {
+            map.insert(key, value)
+                .map_err(|e| <D::Error as serde::de::Error>::custom(format!("{:#}", e)))?;
+        }
+        std::result::Result::Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::collections::BTreeMap;
+
+    use super::{MemoryBudget, PagedMap};
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn find_entries_written_to_disk_past_its_budget() -> Result<()> {
+        let spill_directory = tempdir()?;
+        let mut map = PagedMap::new(MemoryBudget::new(16 * 1024).with_spill_directory(spill_directory.path()));
+        let mut expected = BTreeMap::new();
+        for i in 0..20_000u32 {
+            let key = format!("/some/path/{}", i % 5_000);
+            map.insert(key.clone(), i)?;
+            expected.insert(key, i);
+        }
+
+        assert!(map.is_paged());
+        assert!(map.runs.len() <= super::MAX_RUNS);
+        for (key, value) in &expected {
+            assert_eq!(map.get(key)?, Some(*value));
+        }
+        assert_eq!(map.get(&"/some/other/path".to_string())?, None);
+        let entries = map.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+        drop(map);
+        assert_eq!(spill_directory.path().read_dir()?.count(), 0);
+        Ok(())
+    }
+}
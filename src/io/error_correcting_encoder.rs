@@ -1,48 +1,306 @@
-use std::io::Read;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 use anyhow::*;
 use reed_solomon::Encoder;
 use reed_solomon::{Buffer, Decoder};
+use serde::{Deserialize, Serialize};
 
-const BLOCK_SIZE: usize = u8::MAX as usize - ECC_LENGTH;
 const ECC_LENGTH: usize = 8;
+/// Reed-Solomon over bytes cannot have longer codewords than this
+const CODEWORD_LENGTH: usize = u8::MAX as usize;
+const MAX_ECC_LENGTH: usize = 128;
+const MAX_INTERLEAVE_DEPTH: usize = 65536;
 
-// TODO: make the API streaming friendly
-pub fn encode(bytes: &[u8]) -> Result<Vec<u8>> {
-    let encoder = Encoder::new(ECC_LENGTH);
+/// how data is split into codewords and how the codewords are arranged in the encoded data.
+///
+/// Data is encoded in stripes of `interleave_depth` codewords, each with `ecc_length` parity bytes.
+/// Within a stripe the codewords are written column by column: first byte of every codeword, then second byte
+/// of every codeword and so on, so a burst of damaged bytes is spread thin across all the codewords of a stripe
+/// instead of wiping out a few of them. Up to `ecc_length / 2` damaged bytes per codeword can be corrected,
+/// which makes for bursts of up to `interleave_depth * ecc_length / 2` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LayoutFields")]
+pub struct Layout {
+    ecc_length: usize,
+    interleave_depth: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct LayoutFields {
+    ecc_length: usize,
+    interleave_depth: usize,
+}
+
+/// encodes everything written to it stripe by stripe,
+/// `finish` has to be called to write out the last, possibly shorter, stripe
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    layout: Layout,
+    encoder: Encoder,
+    pending: Vec<u8>,
+}
 
-    let encoded_blocks = bytes
-        .chunks(BLOCK_SIZE)
-        .map(|chunk| encoder.encode(chunk))
-        .collect::<Vec<Buffer>>();
+/// decodes and corrects stripes as they are read,
+/// fails with `io::ErrorKind::InvalidData` on a codeword with more errors than can be corrected
+pub struct DecodingReader<R: Read> {
+    inner: R,
+    layout: Layout,
+    decoder: Decoder,
+    decoded: Vec<u8>,
+    /// offset of `decoded` in the original data
+    decoded_start: u64,
+    position: usize,
+    corrections: usize,
+}
 
-    let mut result = vec![];
+impl Layout {
+    /// codewords one after another, as used for the index and by repositories created before interleaving
+    pub const CONTIGUOUS: Layout = Layout {
+        ecc_length: ECC_LENGTH,
+        interleave_depth: 1,
+    };
 
-    for buffer in encoded_blocks {
-        for byte in buffer.bytes() {
-            result.push(byte?);
+    pub fn new(ecc_length: usize, interleave_depth: usize) -> Result<Self> {
+        if !(2..=MAX_ECC_LENGTH).contains(&ecc_length) {
+            return Err(anyhow!(
+                "parity length has to be between 2 and {}, got {}",
+                MAX_ECC_LENGTH,
+                ecc_length
+            ));
         }
+        if !(1..=MAX_INTERLEAVE_DEPTH).contains(&interleave_depth) {
+            return Err(anyhow!(
+                "interleave depth has to be between 1 and {}, got {}",
+                MAX_INTERLEAVE_DEPTH,
+                interleave_depth
+            ));
+        }
+        Ok(Layout {
+            ecc_length,
+            interleave_depth,
+        })
+    }
+
+    /// parity bytes in each codeword
+    pub fn ecc_length(&self) -> usize {
+        self.ecc_length
+    }
+
+    /// codewords interleaved with each other
+    pub fn interleave_depth(&self) -> usize {
+        self.interleave_depth
+    }
+
+    /// length of the original data given the length of its encoded form
+    pub fn decoded_len(&self, encoded_length: u64) -> u64 {
+        // every codeword but the very last one is full, whatever the interleaving
+        let codewords = (encoded_length + CODEWORD_LENGTH as u64 - 1) / CODEWORD_LENGTH as u64;
+        encoded_length - codewords * self.ecc_length as u64
+    }
+
+    fn data_length(&self) -> usize {
+        CODEWORD_LENGTH - self.ecc_length
+    }
+
+    fn stripe_data_length(&self) -> usize {
+        self.data_length() * self.interleave_depth
+    }
+
+    fn stripe_length(&self) -> usize {
+        CODEWORD_LENGTH * self.interleave_depth
     }
+}
 
-    Ok(result)
+impl Default for LayoutFields {
+    fn default() -> Self {
+        LayoutFields {
+            ecc_length: Layout::CONTIGUOUS.ecc_length,
+            interleave_depth: Layout::CONTIGUOUS.interleave_depth,
+        }
+    }
 }
 
-pub fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
-    let decoder = Decoder::new(ECC_LENGTH);
-    let decoded_blocks = bytes
-        .chunks(BLOCK_SIZE + ECC_LENGTH)
-        .map(|chunk| decoder.correct(chunk, None).map_err(|e| anyhow!(format!("{:#?}", e))))
-        .collect::<Result<Vec<Buffer>>>()?;
+impl TryFrom<LayoutFields> for Layout {
+    type Error = Error;
+
+    fn try_from(fields: LayoutFields) -> Result<Self> {
+        Layout::new(fields.ecc_length, fields.interleave_depth)
+    }
+}
 
-    let mut result = vec![];
+impl<W: Write> EncodingWriter<W> {
+    pub fn with_layout(inner: W, layout: Layout) -> Self {
+        EncodingWriter {
+            inner,
+            layout,
+            encoder: Encoder::new(layout.ecc_length),
+            pending: Vec::with_capacity(layout.stripe_data_length()),
+        }
+    }
 
-    for buffer in decoded_blocks {
-        for byte in buffer.data() {
-            result.push(*byte);
+    pub fn finish(mut self) -> Result<W> {
+        if !self.pending.is_empty() {
+            self.write_stripe()?;
         }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_stripe(&mut self) -> io::Result<()> {
+        let codewords = self
+            .pending
+            .chunks(self.layout.data_length())
+            .map(|chunk| self.encoder.encode(chunk))
+            .collect::<Vec<Buffer>>();
+        self.inner.write_all(&interleave(&codewords))?;
+        self.pending.clear();
+        io::Result::Ok(())
     }
+}
 
-    Ok(result)
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(self.layout.stripe_data_length() - self.pending.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+        if self.pending.len() == self.layout.stripe_data_length() {
+            self.write_stripe()?;
+        }
+        io::Result::Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn with_layout(inner: R, layout: Layout) -> Self {
+        DecodingReader {
+            inner,
+            layout,
+            decoder: Decoder::new(layout.ecc_length),
+            decoded: Vec::with_capacity(layout.stripe_data_length()),
+            decoded_start: 0,
+            position: 0,
+            corrections: 0,
+        }
+    }
+
+    /// bytes corrected so far
+    pub fn corrections(&self) -> usize {
+        self.corrections
+    }
+
+    fn read_stripe(&mut self) -> io::Result<()> {
+        let mut stripe = vec![0; self.layout.stripe_length()];
+        let mut filled = 0;
+        while filled < stripe.len() {
+            match self.inner.read(&mut stripe[filled..]) {
+                io::Result::Ok(0) => break,
+                io::Result::Ok(read) => filled += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.decoded_start += self.decoded.len() as u64;
+        self.decoded.clear();
+        self.position = 0;
+        for codeword in deinterleave(&stripe[..filled]) {
+            if codeword.len() <= self.layout.ecc_length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated codeword"));
+            }
+            let (buffer, corrected) = self
+                .decoder
+                .correct_err_count(&codeword, None)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("cannot correct block: {:?}", e)))?;
+            self.corrections += corrected;
+            self.decoded.extend_from_slice(buffer.data());
+        }
+        io::Result::Ok(())
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.decoded.len() {
+            self.read_stripe()?;
+        }
+        let available = &self.decoded[self.position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read;
+        io::Result::Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecodingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => {
+                let current = self.decoded_start + self.position as u64;
+                offset_by(current, delta)
+            }
+            SeekFrom::End(delta) => {
+                let end = self.layout.decoded_len(self.inner.seek(SeekFrom::End(0))?);
+                offset_by(end, delta)
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot seek before the start"))?;
+        let stripe = target / self.layout.stripe_data_length() as u64;
+        self.inner
+            .seek(SeekFrom::Start(stripe * self.layout.stripe_length() as u64))?;
+        self.decoded.clear();
+        self.decoded_start = stripe * self.layout.stripe_data_length() as u64;
+        self.read_stripe()?;
+        self.position = ((target - self.decoded_start) as usize).min(self.decoded.len());
+        io::Result::Ok(target)
+    }
+}
+
+/// writes the codewords column by column, so that neighbouring bytes of the result belong to different codewords
+fn interleave(codewords: &[Buffer]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(codewords.iter().map(|codeword| codeword.len()).sum());
+    for column in 0..CODEWORD_LENGTH {
+        for codeword in codewords {
+            if let Some(byte) = codeword.get(column) {
+                result.push(*byte);
+            }
+        }
+    }
+    result
+}
+
+/// reverses `interleave`, knowing that all codewords in a stripe are full except possibly the last one
+fn deinterleave(stripe: &[u8]) -> Vec<Vec<u8>> {
+    let count = (stripe.len() + CODEWORD_LENGTH - 1) / CODEWORD_LENGTH;
+    let lengths = (0..count)
+        .map(|i| (stripe.len() - i * CODEWORD_LENGTH).min(CODEWORD_LENGTH))
+        .collect::<Vec<_>>();
+    let mut codewords = lengths
+        .iter()
+        .map(|length| Vec::with_capacity(*length))
+        .collect::<Vec<Vec<u8>>>();
+    let mut bytes = stripe.iter();
+    for column in 0..CODEWORD_LENGTH {
+        for (codeword, length) in codewords.iter_mut().zip(&lengths) {
+            if column < *length {
+                if let Some(byte) = bytes.next() {
+                    codeword.push(*byte);
+                }
+            }
+        }
+    }
+    codewords
+}
+
+fn offset_by(position: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        position.checked_add(delta as u64)
+    } else {
+        position.checked_sub(delta.unsigned_abs())
+    }
 }
 
 #[cfg(test)]
@@ -51,18 +309,41 @@ mod must {
     use anyhow::Result;
     use rand::{thread_rng, Rng, RngCore};
 
-    use super::{decode, encode};
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::ops::Range;
+
+    use super::{DecodingReader, EncodingWriter, Layout, ECC_LENGTH};
 
     use pretty_assertions::assert_eq;
 
+    fn encode_with(layout: Layout, original: &[u8]) -> Result<Vec<u8>> {
+        let mut writer = EncodingWriter::with_layout(vec![], layout);
+        writer.write_all(original)?;
+        writer.finish()
+    }
+
+    fn decode_with(layout: Layout, encoded: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decoded = vec![];
+        DecodingReader::with_layout(encoded, layout).read_to_end(&mut decoded)?;
+        std::io::Result::Ok(decoded)
+    }
+
+    fn corrupt(encoded: &mut [u8], range: Range<usize>) {
+        for byte in &mut encoded[range] {
+            *byte = !*byte;
+        }
+    }
+
     #[test]
     fn encode_small_amounts_of_data() -> Result<()> {
         let mut original: [u8; 32] = [0; 32];
         thread_rng().fill_bytes(&mut original);
 
-        let decoded = decode(&encode(&original)?)?;
+        for layout in [Layout::CONTIGUOUS, Layout::new(16, 64)?] {
+            let decoded = decode_with(layout, &encode_with(layout, &original)?)?;
 
-        assert_eq!(decoded, original);
+            assert_eq!(decoded, original);
+        }
 
         Ok(())
     }
@@ -71,9 +352,11 @@ mod must {
         let mut original: [u8; 1024 * 1024] = [0; 1024 * 1024];
         thread_rng().fill_bytes(&mut original);
 
-        let decoded = decode(&encode(&original)?)?;
+        for layout in [Layout::CONTIGUOUS, Layout::new(16, 64)?] {
+            let decoded = decode_with(layout, &encode_with(layout, &original)?)?;
 
-        assert_eq!(decoded, original);
+            assert_eq!(decoded, original);
+        }
 
         Ok(())
     }
@@ -83,7 +366,7 @@ mod must {
         let mut original: [u8; 32] = [0; 32];
         thread_rng().fill_bytes(&mut original);
 
-        let encoded = encode(&original)?;
+        let encoded = encode_with(Layout::CONTIGUOUS, &original)?;
 
         let size = encoded.len();
         let corrupt_byte_index = rand::thread_rng().gen_range::<usize, _>(0..size);
@@ -91,10 +374,138 @@ mod must {
         let mut corrupted = encoded;
         corrupted[corrupt_byte_index] = rand::thread_rng().gen::<u8>();
 
-        let decoded = decode(&corrupted)?;
+        let decoded = decode_with(Layout::CONTIGUOUS, &corrupted)?;
+
+        assert_eq!(decoded, original);
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_corrected_bytes() -> Result<()> {
+        let original = [7; 32];
+        let mut corrupted = encode_with(Layout::CONTIGUOUS, &original)?;
+        corrupted[0] = !corrupted[0];
+        corrupted[5] = !corrupted[5];
+        let mut reader = DecodingReader::with_layout(&corrupted[..], Layout::CONTIGUOUS);
+        let mut decoded = vec![];
+
+        reader.read_to_end(&mut decoded)?;
+
+        assert_eq!(decoded, original);
+        assert_eq!(reader.corrections(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_data_written_in_chunks() -> Result<()> {
+        let mut original = vec![0; 1000];
+        thread_rng().fill_bytes(&mut original);
+
+        let mut writer = EncodingWriter::with_layout(vec![], Layout::CONTIGUOUS);
+        for chunk in original.chunks(77) {
+            writer.write_all(chunk)?;
+        }
+        let mut encoded = writer.finish()?;
+        encoded[300] = !encoded[300];
+        let mut reader = DecodingReader::with_layout(&encoded[..], Layout::CONTIGUOUS);
+        let mut decoded = vec![];
+        reader.read_to_end(&mut decoded)?;
+
+        assert_eq!(encoded.len(), original.len() + 5 * ECC_LENGTH);
+        assert_eq!(Layout::CONTIGUOUS.decoded_len(encoded.len() as u64), original.len() as u64);
+        assert_eq!(decoded, original);
+        assert_eq!(reader.corrections(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn survive_burst_errors_when_interleaved() -> Result<()> {
+        let layout = Layout::new(8, 64)?;
+        let mut original = vec![0; 3 * 64 * 247 + 1000];
+        thread_rng().fill_bytes(&mut original);
+        let mut encoded = encode_with(layout, &original)?;
+        // 4 bytes in every codeword of the first stripe, and a burst across the boundary of the next two
+        corrupt(&mut encoded, 100..356);
+        corrupt(&mut encoded, 2 * 64 * 255 - 128..2 * 64 * 255 + 128);
+        let mut reader = DecodingReader::with_layout(&encoded[..], layout);
+        let mut decoded = vec![];
+        reader.read_to_end(&mut decoded)?;
 
+        assert_eq!(encoded.len(), original.len() + (3 * 64 + 5) * 8);
+        assert_eq!(layout.decoded_len(encoded.len() as u64), original.len() as u64);
         assert_eq!(decoded, original);
+        assert_eq!(reader.corrections(), 512);
+        Ok(())
+    }
+
+    #[test]
+    fn survive_burst_errors_in_short_last_stripe() -> Result<()> {
+        let layout = Layout::new(16, 64)?;
+        let mut original = vec![0; 3 * 239 - 10];
+        thread_rng().fill_bytes(&mut original);
+        let mut encoded = encode_with(layout, &original)?;
+        corrupt(&mut encoded, 500..524);
+
+        assert_eq!(decode_with(layout, &encoded)?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn lose_data_to_burst_errors_when_contiguous() -> Result<()> {
+        let mut original = vec![0; 64 * 247];
+        thread_rng().fill_bytes(&mut original);
+        let mut encoded = encode_with(Layout::CONTIGUOUS, &original)?;
+        corrupt(&mut encoded, 100..356);
+
+        let error = decode_with(Layout::CONTIGUOUS, &encoded).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_layouts_without_room_for_data_or_parity() {
+        assert!(Layout::new(0, 1).is_err());
+        assert!(Layout::new(8, 0).is_err());
+        assert!(Layout::new(255, 1).is_err());
+        assert!(Layout::new(32, 1024).is_ok());
+    }
+
+    #[test]
+    fn seek_to_any_position_when_interleaved() -> Result<()> {
+        let layout = Layout::new(32, 4)?;
+        let original = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let encoded = encode_with(layout, &original)?;
+        let mut reader = DecodingReader::with_layout(Cursor::new(encoded), layout);
+        let mut byte = [0];
+
+        for position in [2000, 892, 891, 0, 2999] {
+            reader.seek(SeekFrom::Start(position))?;
+            reader.read_exact(&mut byte)?;
+            assert_eq!(byte[0], original[position as usize]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(-1000))?, 2000);
+        Ok(())
+    }
+
+    #[test]
+    fn seek_to_any_position() -> Result<()> {
+        let original = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let encoded = encode_with(Layout::CONTIGUOUS, &original)?;
+        let mut reader = DecodingReader::with_layout(Cursor::new(encoded), Layout::CONTIGUOUS);
+        let mut byte = [0];
 
+        reader.seek(SeekFrom::Start(500))?;
+        reader.read_exact(&mut byte)?;
+        assert_eq!(byte[0], original[500]);
+        reader.seek(SeekFrom::Current(-300))?;
+        reader.read_exact(&mut byte)?;
+        assert_eq!(byte[0], original[201]);
+        assert_eq!(reader.seek(SeekFrom::End(-1))?, 999);
+        reader.read_exact(&mut byte)?;
+        assert_eq!(byte[0], original[999]);
+        assert_eq!(reader.read(&mut byte)?, 0);
         Ok(())
     }
 }
@@ -1,4 +1,7 @@
 pub mod backup;
+pub mod cancellation;
+pub mod export;
+pub mod progress;
 pub mod repository;
 pub mod restore;
 pub mod test;
@@ -1,13 +1,738 @@
-use seahorse::App;
-use std::env;
+use std::{
+    env, fs,
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use anyhow::Result;
+use anyhow::*;
+use bakare::{
+    backup,
+    cancellation::{CancellationToken, Cancelled},
+    export,
+    progress::{JsonLines, NoProgress, Observer, ProgressBar},
+    repository::{
+        check::CheckOptions,
+        config::{self, Config, Layout},
+        parity::ParityScheme,
+        tree::TreeEntry,
+        IndexFormat, ItemId, MemoryBudget, Repository,
+    },
+    restore::{
+        self,
+        destination::{Destination, OverwritePolicy},
+        selector::Selector,
+        Problem,
+    },
+};
+use nix::unistd::isatty;
+use seahorse::{App, Command, Context, Flag, FlagType};
+use time::OffsetDateTime;
+
+const SECRET_VARIABLE: &str = "BAKARE_SECRET";
+/// mebibytes of the index held in memory, the rest is paged from disk, unlimited when not set
+const MEMORY_BUDGET_VARIABLE: &str = "BAKARE_MEMORY_BUDGET";
+/// directory the index is paged out to past the memory budget, e.g. a local cache for a repository on a slow disk.
+/// A directory in the repository when not set
+const SPILL_DIRECTORY_VARIABLE: &str = "BAKARE_SPILL_DIRECTORY";
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CANCELLED: i32 = 130;
 
 fn main() {
+    femme::with_level(log::LevelFilter::Info);
     let args: Vec<String> = env::args().collect();
     let app = App::new(env!("CARGO_PKG_NAME"))
         .description(env!("CARGO_PKG_DESCRIPTION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .version(env!("CARGO_PKG_VERSION"))
-        .action(|c| println!("Hello, {:?}", c.args));
+        .usage(format!("{} [command] [arguments]", env!("CARGO_PKG_NAME")))
+        .command(
+            Command::new("init")
+                .description("create a new repository")
+                .usage("bakare init --repository <path> [--parity-length <bytes>] [--interleave-depth <codewords>]")
+                .flag(repository_flag())
+                .flag(
+                    Flag::new("parity-length", FlagType::Int)
+                        .description("error correction bytes in every 255 bytes stored, up to half of them can be corrected"),
+                )
+                .flag(Flag::new("interleave-depth", FlagType::Int).description(
+                    "codewords spread across each other, longer bursts of damaged bytes can be corrected with more",
+                ))
+                .action(|c| exit_with(init(c))),
+        )
+        .command(
+            Command::new("backup")
+                .description(
+                    "back up a directory, resuming the previous backup of it if it did not finish, \
+                         or a stream stored under the given path",
+                )
+                .usage(
+                    "bakare backup --repository <path> [--workers <count>] [--json-progress] \
+                         [--stdin | --tar | --command <shell command>] <source path>",
+                )
+                .flag(repository_flag())
+                .flag(Flag::new("stdin", FlagType::Bool).description("back up stdin as a single file"))
+                .flag(
+                    Flag::new("tar", FlagType::Bool)
+                        .description("back up files from a tar stream on stdin, under the source path"),
+                )
+                .flag(
+                    Flag::new("command", FlagType::String)
+                        .description("back up the output of a shell command as a single file, unless it fails"),
+                )
+                .flag(json_progress_flag())
+                .flag(workers_flag(
+                    "number of threads hashing file contents, defaults to the number of CPUs",
+                ))
+                .action(|c| exit_with(backup(c))),
+        )
+        .command(
+            Command::new("restore")
+                .description("restore newest versions of all or only selected files into a directory")
+                .usage(
+                    "bakare restore --repository <path> [--prefix <path>] [--glob <pattern>] [--regex <pattern>] \
+                     [--strip-prefix <path>] [--remap <from>=<to>[,<from>=<to>...]] [--overwrite <policy>] \
+                     [--best-effort] [--dry-run] [--workers <count>] [--json-progress] (<target path> | --in-place)",
+                )
+                .flag(repository_flag())
+                .flag(Flag::new("prefix", FlagType::String).description("restore only this path and everything under it"))
+                .flag(Flag::new("glob", FlagType::String).description(
+                    "restore only files matching this pattern, matched against file names unless it contains a '/'",
+                ))
+                .flag(
+                    Flag::new("regex", FlagType::String)
+                        .description("restore only files with paths matching this regular expression"),
+                )
+                .flag(
+                    Flag::new("in-place", FlagType::Bool)
+                        .description("restore files to their original locations instead of into a target path"),
+                )
+                .flag(
+                    Flag::new("strip-prefix", FlagType::String)
+                        .description("drop this prefix from original paths before recreating them in the target path"),
+                )
+                .flag(Flag::new("remap", FlagType::String).description(
+                    "restore everything under <from> into <to> instead, more than one remap can be separated by ','",
+                ))
+                .flag(Flag::new("overwrite", FlagType::String).description(
+                    "what to do with files already present: never, always, if-newer, if-different (default) or rename",
+                ))
+                .flag(
+                    Flag::new("best-effort", FlagType::Bool)
+                        .description("keep going past files with missing or corrupted contents and list them at the end"),
+                )
+                .flag(
+                    Flag::new("dry-run", FlagType::Bool)
+                        .description("list files that would be restored without writing anything"),
+                )
+                .flag(json_progress_flag())
+                .flag(workers_flag(
+                    "number of files restored at the same time, defaults to the number of CPUs",
+                ))
+                .action(|c| exit_with(restore(c))),
+        )
+        .command(
+            Command::new("export")
+                .description("write newest versions of all or only selected files into a tar or zip archive")
+                .usage(
+                    "bakare export --repository <path> [--format tar|tar.zst|zip] [--output <file>] \
+                     [--prefix <path>] [--glob <pattern>] [--regex <pattern>] [--strip-prefix <path>]",
+                )
+                .flag(repository_flag())
+                .flag(
+                    Flag::new("format", FlagType::String)
+                        .description("archive format: tar, tar.zst or zip, guessed from the output file name, tar otherwise"),
+                )
+                .flag(
+                    Flag::new("output", FlagType::String)
+                        .description("file to write the archive to instead of stdout, required for zip")
+                        .alias("o"),
+                )
+                .flag(Flag::new("prefix", FlagType::String).description("export only this path and everything under it"))
+                .flag(Flag::new("glob", FlagType::String).description(
+                    "export only files matching this pattern, matched against file names unless it contains a '/'",
+                ))
+                .flag(
+                    Flag::new("regex", FlagType::String)
+                        .description("export only files with paths matching this regular expression"),
+                )
+                .flag(
+                    Flag::new("strip-prefix", FlagType::String)
+                        .description("drop this prefix from original paths before putting them in the archive"),
+                )
+                .action(|c| exit_with(export(c))),
+        )
+        .command(
+            Command::new("check")
+                .description("check that the index is readable and consistent and that all stored contents are present")
+                .usage("bakare check --repository <path> [--read-data | --read-data-subset <fraction>]")
+                .flag(repository_flag())
+                .flag(
+                    Flag::new("read-data", FlagType::Bool)
+                        .description("also read back all stored contents and check them against their ids"),
+                )
+                .flag(Flag::new("read-data-subset", FlagType::String).description(
+                    "read back only a random part of stored contents, given as a fraction like 0.1 or a percentage like 10%",
+                ))
+                .action(|c| exit_with(check(c))),
+        )
+        .command(
+            Command::new("scrub")
+                .description("read everything back, rewrite what error correction had to fix and list what is beyond repair")
+                .usage("bakare scrub --repository <path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(scrub(c))),
+        )
+        .command(
+            Command::new("prune")
+                .description("remove stored contents the index does not know of, left behind by interrupted backups")
+                .usage("bakare prune --repository <path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(prune(c))),
+        )
+        .command(
+            Command::new("snapshots")
+                .description("list snapshots taken at the end of each backup of a directory, oldest first")
+                .usage("bakare snapshots --repository <path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(snapshots(c))),
+        )
+        .command(
+            Command::new("ls")
+                .description(
+                    "list a directory as it was in a snapshot, the backed up directory itself unless a path in it is given",
+                )
+                .usage("bakare ls --repository <path> <snapshot id> [<path in snapshot>]")
+                .flag(repository_flag())
+                .action(|c| exit_with(ls(c))),
+        )
+        .command(
+            Command::new("diff")
+                .description("list files added (+), removed (-) and modified (M) between two snapshots")
+                .usage("bakare diff --repository <path> <old snapshot id> <new snapshot id>")
+                .flag(repository_flag())
+                .action(|c| exit_with(diff(c))),
+        )
+        .command(
+            Command::new("history")
+                .description("list every backed up version of a file, oldest first")
+                .usage("bakare history --repository <path> <original path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(history(c))),
+        )
+        .command(
+            Command::new("conflicts")
+                .description(
+                    "list files backed up from more than one host without either knowing of the other, \
+                         the newest version first, backing a file up again resolves its conflict",
+                )
+                .usage("bakare conflicts --repository <path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(conflicts(c))),
+        )
+        .command(
+            Command::new("compact")
+                .description(
+                    "combine the index segments written by each backup into a single index file, \
+                         migrating indexes written as JSON by older versions to the binary format",
+                )
+                .usage("bakare compact --repository <path>")
+                .flag(repository_flag())
+                .action(|c| exit_with(compact(c))),
+        )
+        .command(
+            Command::new("locks")
+                .description("list locks held on the repository, stale ones are broken automatically when waiting on them")
+                .usage("bakare locks --repository <path> [--stale] [--remove]")
+                .flag(repository_flag())
+                .flag(Flag::new("stale", FlagType::Bool).description("only list locks whose owner is gone"))
+                .flag(Flag::new("remove", FlagType::Bool).description("remove the listed locks, even if their owner is alive"))
+                .action(|c| exit_with(locks(c))),
+        )
+        .command(
+            Command::new("parity")
+                .description(
+                    "add parity blobs for all stored contents that have none yet, \
+                         check uses them to rebuild contents that went missing",
+                )
+                .usage("bakare parity --repository <path> [--data-blobs <count>] [--parity-blobs <count>]")
+                .flag(repository_flag())
+                .flag(Flag::new("data-blobs", FlagType::Int).description("stored contents in each parity group, 10 by default"))
+                .flag(
+                    Flag::new("parity-blobs", FlagType::Int)
+                        .description("parity blobs in each group, as many of the group's files can be lost, 2 by default"),
+                )
+                .action(|c| exit_with(parity(c))),
+        )
+        .command(
+            Command::new("cat")
+                .description("write contents of a backed up file to stdout, the newest version unless an id is given")
+                .usage("bakare cat --repository <path> [--id <item id>] <original path>")
+                .flag(repository_flag())
+                .flag(Flag::new("id", FlagType::String).description("hex id of the version to write"))
+                .action(|c| exit_with(cat(c))),
+        );
 
     app.run(args);
 }
+
+fn repository_flag() -> Flag {
+    Flag::new("repository", FlagType::String)
+        .description("path to the repository")
+        .alias("r")
+}
+
+fn workers_flag(description: &str) -> Flag {
+    Flag::new("workers", FlagType::Int).description(description).alias("w")
+}
+
+fn json_progress_flag() -> Flag {
+    Flag::new("json-progress", FlagType::Bool).description("print progress events to stdout as newline-delimited JSON")
+}
+
+fn init(c: &Context) -> Result<()> {
+    let parity_length = match c.int_flag("parity-length") {
+        Result::Ok(length) => usize::try_from(length)?,
+        Err(_) => config::DEFAULT_PARITY_LENGTH,
+    };
+    let interleave_depth = match c.int_flag("interleave-depth") {
+        Result::Ok(depth) => usize::try_from(depth)?,
+        Err(_) => config::DEFAULT_INTERLEAVE_DEPTH,
+    };
+    let config = Config::with_layout(Layout::new(parity_length, interleave_depth)?);
+    Repository::init_with_config(&repository_path(c)?, &secret()?, config)?;
+    Ok(())
+}
+
+fn backup(c: &Context) -> Result<()> {
+    let command = c.string_flag("command").ok();
+    if c.bool_flag("stdin") || c.bool_flag("tar") || command.is_some() {
+        return backup_stream(c, command);
+    }
+    let source_path = fs::canonicalize(single_argument(c, "source path")?)?;
+    let mut concurrency = backup::Concurrency::default();
+    if let Result::Ok(workers) = c.int_flag("workers") {
+        concurrency.workers = usize::try_from(workers)?;
+    }
+    let mut repository = open_repository(c)?;
+    with_progress(c, |observer| {
+        let mut engine = backup::Engine::new(&source_path, &mut repository)?
+            .with_cancellation(cancellation()?)
+            .with_concurrency(concurrency)
+            .with_observer(observer);
+        engine.backup()
+    })
+}
+
+/// streams have no file to canonicalize the path of, relative paths are taken as relative to the current directory
+fn backup_stream(c: &Context, command: Option<String>) -> Result<()> {
+    let source_path = env::current_dir()?.join(single_argument(c, "source path")?);
+    let mut repository = open_repository(c)?;
+    with_progress(c, |observer| {
+        let mut engine = backup::Engine::new(&source_path, &mut repository)?
+            .with_cancellation(cancellation()?)
+            .with_observer(observer);
+        match command {
+            Some(command) => engine.backup_command_output(process::Command::new("sh").arg("-c").arg(command)),
+            None if c.bool_flag("tar") => engine.backup_tar(io::stdin().lock()),
+            None => engine.backup_stream(io::stdin().lock()),
+        }
+    })
+}
+
+fn restore(c: &Context) -> Result<()> {
+    let destination = destination(c)?;
+    let overwrite_policy = match c.string_flag("overwrite") {
+        Result::Ok(policy) => policy.parse()?,
+        Err(_) => OverwritePolicy::default(),
+    };
+    let selectors = selectors(c)?;
+    let mut repository = open_repository(c)?;
+    if c.bool_flag("dry-run") {
+        let engine = restore::Engine::new(&mut repository, destination)?
+            .with_overwrite_policy(overwrite_policy)
+            .with_selectors(selectors);
+        for planned in engine.plan()? {
+            println!(
+                "{} ({} bytes, version {}) -> {}",
+                planned.original_source_path,
+                planned.size,
+                planned.version,
+                planned.destination.to_string_lossy()
+            );
+        }
+        return Ok(());
+    }
+    with_progress(c, |observer| {
+        let mut engine = restore::Engine::new(&mut repository, destination)?
+            .with_overwrite_policy(overwrite_policy)
+            .with_cancellation(cancellation()?)
+            .with_observer(observer)
+            .with_selectors(selectors);
+        if let Result::Ok(workers) = c.int_flag("workers") {
+            engine = engine.with_workers(usize::try_from(workers)?);
+        }
+        if !c.bool_flag("best-effort") {
+            return engine.restore_all();
+        }
+        let report = engine.restore_all_best_effort()?;
+        for failed in &report.failed {
+            let problem = match &failed.problem {
+                Problem::Damaged(damage) => damage.to_string(),
+                Problem::Other(message) => message.clone(),
+            };
+            eprintln!("{} (version {}): {}", failed.original_source_path, failed.version, problem);
+        }
+        if !report.is_complete() {
+            return Err(anyhow!(
+                "restored {} files, {} could not be restored",
+                report.restored,
+                report.failed.len()
+            ));
+        }
+        Ok(())
+    })
+}
+
+fn export(c: &Context) -> Result<()> {
+    if !c.args.is_empty() {
+        return Err(anyhow!(
+            "export does not take arguments, use --output <file> to write to a file"
+        ));
+    }
+    let output = c.string_flag("output").ok().map(PathBuf::from);
+    let format = match c.string_flag("format") {
+        Result::Ok(format) => format.parse()?,
+        Err(_) => output
+            .as_deref()
+            .and_then(export::Format::from_file_name)
+            .unwrap_or(export::Format::Tar),
+    };
+    let repository = open_repository(c)?;
+    let mut engine = export::Engine::new(&repository, format)?
+        .with_selectors(selectors(c)?)
+        .with_cancellation(cancellation()?);
+    if let Result::Ok(prefix) = c.string_flag("strip-prefix") {
+        engine = engine.with_stripped_prefix(Path::new(&prefix));
+    }
+    match output {
+        Some(output) => {
+            engine.export(fs::File::create(output)?)?.sync_all()?;
+        }
+        None => {
+            engine.export_stream(io::stdout().lock())?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn selectors(c: &Context) -> Result<Vec<Selector>> {
+    let mut selectors = vec![];
+    if let Result::Ok(prefix) = c.string_flag("prefix") {
+        selectors.push(Selector::prefix(Path::new(&prefix)));
+    }
+    if let Result::Ok(pattern) = c.string_flag("glob") {
+        selectors.push(Selector::glob(&pattern)?);
+    }
+    if let Result::Ok(pattern) = c.string_flag("regex") {
+        selectors.push(Selector::regex(&pattern)?);
+    }
+    Ok(selectors)
+}
+
+fn destination(c: &Context) -> Result<Destination> {
+    let mut destination = if c.bool_flag("in-place") {
+        if !c.args.is_empty() {
+            return Err(anyhow!("--in-place does not take a target path"));
+        }
+        Destination::in_place()
+    } else {
+        Destination::under(Path::new(&single_argument(c, "target path")?))
+    };
+    if let Result::Ok(prefix) = c.string_flag("strip-prefix") {
+        destination = destination.with_stripped_prefix(Path::new(&prefix));
+    }
+    if let Result::Ok(remaps) = c.string_flag("remap") {
+        for remap in remaps.split(',') {
+            let (from, to) = remap
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected --remap <from>=<to>, got '{}'", remap))?;
+            destination = destination.with_remap(Path::new(from), Path::new(to));
+        }
+    }
+    Ok(destination)
+}
+
+fn check(c: &Context) -> Result<()> {
+    let read_data = match c.string_flag("read-data-subset") {
+        Result::Ok(subset) => Some(fraction(&subset)?),
+        Err(_) if c.bool_flag("read-data") => Some(1.0),
+        Err(_) => None,
+    };
+    let repository = open_repository(c)?;
+    let report = repository.check(CheckOptions { read_data })?;
+    println!(
+        "{} items, {} blobs read back, {} index bytes corrected",
+        report.items, report.blobs_read, report.index_corrections
+    );
+    for id in &report.reconstructed {
+        println!("rebuilt {} from parity", id);
+    }
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if !report.is_healthy() {
+        return Err(anyhow!("found {} problems", report.problems.len()));
+    }
+    Ok(())
+}
+
+fn scrub(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let report = repository.scrub()?;
+    println!(
+        "{} blobs read, {} index bytes corrected",
+        report.blobs, report.index_corrections
+    );
+    for repaired in &report.repaired {
+        println!("repaired {} bytes in {}", repaired.corrections, repaired.relative_path);
+    }
+    for damaged in &report.beyond_repair {
+        println!("{} beyond repair: {}", damaged.relative_path, damaged.damage);
+    }
+    if !report.is_healthy() {
+        return Err(anyhow!("{} blobs are beyond repair", report.beyond_repair.len()));
+    }
+    Ok(())
+}
+
+fn prune(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let report = repository.prune()?;
+    println!("removed {} files, {} bytes", report.files, report.bytes);
+    Ok(())
+}
+
+fn snapshots(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    for snapshot in repository.snapshots()? {
+        println!(
+            "{} {} of {} by {}",
+            snapshot.id,
+            OffsetDateTime::from(snapshot.created).replace_nanosecond(0)?,
+            snapshot.source_path,
+            snapshot.writer
+        );
+    }
+    Ok(())
+}
+
+fn ls(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let (snapshot, path) = match c.args.as_slice() {
+        [snapshot] => (snapshot, ""),
+        [snapshot, path] => (snapshot, path.as_str()),
+        _ => return Err(anyhow!("expected a snapshot id and optionally a path in the snapshot")),
+    };
+    let snapshot = repository.find_snapshot(snapshot)?;
+    for (name, entry) in repository.list_snapshot_directory(&snapshot, Path::new(path))? {
+        match entry {
+            TreeEntry::File { metadata, .. } => println!("{} {}", name, metadata.size),
+            TreeEntry::Directory { .. } => println!("{}/", name),
+        }
+    }
+    Ok(())
+}
+
+fn diff(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let (old, new) = match c.args.as_slice() {
+        [old, new] => (repository.find_snapshot(old)?, repository.find_snapshot(new)?),
+        _ => return Err(anyhow!("expected two snapshot ids")),
+    };
+    for change in repository.diff_snapshots(&old, &new)? {
+        println!("{} {}", change.kind, change.path.to_string_lossy());
+    }
+    Ok(())
+}
+
+fn history(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let path = env::current_dir()?.join(single_argument(c, "original path")?);
+    let history = repository.history(&path)?;
+    if history.is_empty() {
+        return Err(anyhow!("no such file in the repository"));
+    }
+    for item in history {
+        let stored = match item.stored() {
+            Some(stored) => OffsetDateTime::from(stored).replace_nanosecond(0)?.to_string(),
+            None => "unknown time".to_string(),
+        };
+        println!(
+            "version {}, stored {}, {} bytes, id {}",
+            item.version(),
+            stored,
+            item.metadata().size,
+            hex::encode(item.id())
+        );
+    }
+    Ok(())
+}
+
+fn conflicts(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    for versions in repository.conflicts()? {
+        for item in versions {
+            println!("{} version {}", item, item.version());
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn compact(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let format = repository.index_format()?;
+    let segments = repository.compact_index()?;
+    println!("combined {} index segments", segments);
+    if format == IndexFormat::Json {
+        println!("migrated index from JSON to the binary format");
+    }
+    Ok(())
+}
+
+fn locks(c: &Context) -> Result<()> {
+    let locks = Repository::locks(&repository_path(c)?)?;
+    for lock in locks.iter().filter(|lock| lock.stale || !c.bool_flag("stale")) {
+        if c.bool_flag("remove") {
+            lock.remove()?;
+            println!("removed {}", lock);
+        } else {
+            println!("{}", lock);
+        }
+    }
+    Ok(())
+}
+
+fn parity(c: &Context) -> Result<()> {
+    let default = ParityScheme::default();
+    let data_blobs = match c.int_flag("data-blobs") {
+        Result::Ok(count) => usize::try_from(count)?,
+        Err(_) => default.data_blobs(),
+    };
+    let parity_blobs = match c.int_flag("parity-blobs") {
+        Result::Ok(count) => usize::try_from(count)?,
+        Err(_) => default.parity_blobs(),
+    };
+    let repository = open_repository(c)?;
+    let report = repository.add_parity(ParityScheme::new(data_blobs, parity_blobs)?)?;
+    println!("added {} parity groups for {} blobs", report.groups, report.blobs);
+    Ok(())
+}
+
+fn fraction(text: &str) -> Result<f64> {
+    let fraction = match text.strip_suffix('%') {
+        Some(percentage) => percentage.parse::<f64>()? / 100.0,
+        None => text.parse::<f64>()?,
+    };
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(anyhow!("expected a fraction between 0 and 1 or a percentage, got '{}'", text));
+    }
+    Ok(fraction)
+}
+
+fn cat(c: &Context) -> Result<()> {
+    let repository = open_repository(c)?;
+    let item = match c.string_flag("id") {
+        Result::Ok(id) => repository.item_by_id(&ItemId::from(&hex::decode(&id)?[..]))?,
+        Err(_) => {
+            let path = env::current_dir()?.join(single_argument(c, "original path")?);
+            repository.newest_item_by_source_path(&path)?
+        }
+    };
+    let item = item.ok_or_else(|| anyhow!("no such file in the repository"))?;
+    let mut reader = item.reader()?;
+    match io::copy(&mut reader, &mut io::stdout().lock()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map(|_| ()).context(item.to_string()),
+    }
+}
+
+fn open_repository(c: &Context) -> Result<Repository> {
+    match memory_budget()? {
+        Some(memory_budget) => Repository::open_with_memory_budget(&repository_path(c)?, &secret()?, memory_budget),
+        None => Repository::open(&repository_path(c)?, &secret()?),
+    }
+}
+
+fn repository_path(c: &Context) -> Result<PathBuf> {
+    let path = c
+        .string_flag("repository")
+        .map_err(|_| anyhow!("--repository <path> is required"))?;
+    Ok(PathBuf::from(path))
+}
+
+fn secret() -> Result<String> {
+    env::var(SECRET_VARIABLE).map_err(|_| anyhow!("{} environment variable is not set", SECRET_VARIABLE))
+}
+
+fn memory_budget() -> Result<Option<MemoryBudget>> {
+    match env::var(MEMORY_BUDGET_VARIABLE) {
+        Result::Ok(mebibytes) => {
+            let mebibytes = mebibytes
+                .parse::<usize>()
+                .map_err(|_| anyhow!("{} has to be a number of mebibytes", MEMORY_BUDGET_VARIABLE))?;
+            let mut memory_budget = MemoryBudget::new(mebibytes.saturating_mul(1024 * 1024));
+            if let Some(directory) = env::var_os(SPILL_DIRECTORY_VARIABLE) {
+                memory_budget = memory_budget.with_spill_directory(Path::new(&directory));
+            }
+            Ok(Some(memory_budget))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+fn single_argument(c: &Context, name: &str) -> Result<String> {
+    match c.args.as_slice() {
+        [argument] => Ok(argument.clone()),
+        _ => Err(anyhow!("expected exactly one argument: {}", name)),
+    }
+}
+
+/// cancels on SIGINT and SIGTERM so that engines can checkpoint and release their locks before exiting
+fn cancellation() -> Result<CancellationToken> {
+    let cancellation = CancellationToken::new();
+    cancellation.cancel_on_signals()?;
+    Ok(cancellation)
+}
+
+/// reports progress as JSON lines when asked to, or on a progress bar when running in a terminal
+fn with_progress<F>(c: &Context, run: F) -> Result<()>
+where
+    F: FnOnce(Arc<dyn Observer>) -> Result<()>,
+{
+    if c.bool_flag("json-progress") {
+        return run(Arc::new(JsonLines::new(io::stdout())));
+    }
+    if !isatty(io::stderr().as_raw_fd()).unwrap_or(false) {
+        return run(Arc::new(NoProgress));
+    }
+    let progress_bar = Arc::new(ProgressBar::new());
+    let result = run(progress_bar.clone());
+    progress_bar.finish();
+    result
+}
+
+fn exit_with(result: Result<()>) {
+    if let Err(e) = result {
+        eprintln!("{:#}", e);
+        if e.is::<Cancelled>() {
+            process::exit(EXIT_CANCELLED);
+        }
+        process::exit(EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// what engines report while they run, paths are the original source paths of the files
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// all files to process are known, sent once per run
+    ScanFinished {
+        files: u64,
+        bytes: u64,
+    },
+    FileStarted {
+        path: String,
+    },
+    /// part of a file was read or written
+    BytesProcessed {
+        bytes: u64,
+    },
+    FileFinished {
+        path: String,
+        bytes: u64,
+    },
+    /// file contents were already in the repository and were not stored again
+    DeduplicationHit {
+        path: String,
+        bytes: u64,
+    },
+    Error {
+        path: String,
+        message: String,
+    },
+}
+
+/// receives events from the backup and restore engines, possibly from many threads at once
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// ignores all events
+pub struct NoProgress;
+
+impl Observer for NoProgress {
+    fn on_event(&self, _event: &Event) {}
+}
+
+/// writes every event as a line of JSON
+pub struct JsonLines<W: Write + Send> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLines {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> Observer for JsonLines<W> {
+    fn on_event(&self, event: &Event) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let mut writer = self.writer.lock().unwrap();
+            let _ = writeln!(writer, "{}", line);
+            let _ = writer.flush();
+        }
+    }
+}
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// single self-updating line on stderr with counts, throughput and estimated time left
+pub struct ProgressBar {
+    state: Mutex<ProgressBarState>,
+}
+
+struct ProgressBarState {
+    started: Instant,
+    last_drawn: Option<Instant>,
+    total_files: Option<u64>,
+    total_bytes: Option<u64>,
+    files: u64,
+    bytes: u64,
+    errors: u64,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        ProgressBar {
+            state: Mutex::new(ProgressBarState {
+                started: Instant::now(),
+                last_drawn: None,
+                total_files: None,
+                total_bytes: None,
+                files: 0,
+                bytes: 0,
+                errors: 0,
+            }),
+        }
+    }
+
+    /// draws the final state and moves to the next line
+    pub fn finish(&self) {
+        let state = self.state.lock().unwrap();
+        eprintln!("\r{}", state.line());
+    }
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Observer for ProgressBar {
+    fn on_event(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            Event::ScanFinished { files, bytes } => {
+                state.total_files = Some(*files);
+                state.total_bytes = Some(*bytes);
+            }
+            Event::BytesProcessed { bytes } => state.bytes += bytes,
+            Event::FileFinished { .. } => state.files += 1,
+            Event::Error { path, message } => {
+                state.errors += 1;
+                eprintln!("\r{}: {}", path, message);
+            }
+            Event::FileStarted { .. } | Event::DeduplicationHit { .. } => {}
+        }
+        if state.last_drawn.map_or(true, |drawn| drawn.elapsed() >= REDRAW_INTERVAL) {
+            state.last_drawn = Some(Instant::now());
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r{}", state.line());
+            let _ = stderr.flush();
+        }
+    }
+}
+
+impl ProgressBarState {
+    fn line(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 { self.bytes as f64 / elapsed } else { 0.0 };
+        let files = match self.total_files {
+            Some(total) => format!("{}/{} files", self.files, total),
+            None => format!("{} files", self.files),
+        };
+        let bytes = match self.total_bytes {
+            Some(total) => format!("{}/{}", human_bytes(self.bytes as f64), human_bytes(total as f64)),
+            None => human_bytes(self.bytes as f64),
+        };
+        let eta = match self.total_bytes {
+            Some(total) if throughput > 0.0 => {
+                let seconds = (total.saturating_sub(self.bytes) as f64 / throughput) as u64;
+                format!(", ETA {}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+            }
+            _ => String::new(),
+        };
+        let errors = if self.errors > 0 {
+            format!(", {} errors", self.errors)
+        } else {
+            String::new()
+        };
+        format!("{}, {} at {}/s{}{}   ", files, bytes, human_bytes(throughput), eta, errors)
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+#[cfg(test)]
+mod must {
+    use super::{Event, JsonLines, Observer};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn write_one_json_object_per_line() {
+        let observer = JsonLines::new(vec![]);
+
+        observer.on_event(&Event::ScanFinished { files: 2, bytes: 3 });
+        observer.on_event(&Event::FileStarted {
+            path: "/some/file".to_string(),
+        });
+
+        let output = String::from_utf8(observer.writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "{\"event\":\"scan_finished\",\"files\":2,\"bytes\":3}\n{\"event\":\"file_started\",\"path\":\"/some/file\"}\n"
+        );
+    }
+}
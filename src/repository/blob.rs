@@ -0,0 +1,93 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Result;
+
+use super::{config::DataEncoding, data, ItemId};
+use crate::io::error_correcting_encoder::{DecodingReader, EncodingWriter};
+
+/// reads the original contents back from a blob in the data directory
+pub struct BlobReader {
+    inner: Inner,
+}
+
+enum Inner {
+    Plain(BufReader<File>),
+    ReedSolomon(DecodingReader<BufReader<File>>),
+}
+
+impl BlobReader {
+    pub fn open(path: &Path, encoding: DataEncoding) -> Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let inner = match encoding {
+            DataEncoding::Plain => Inner::Plain(file),
+            DataEncoding::ReedSolomon(layout) => Inner::ReedSolomon(DecodingReader::with_layout(file, layout)),
+        };
+        Ok(BlobReader { inner })
+    }
+
+    /// bytes fixed by error correction so far
+    pub fn corrections(&self) -> usize {
+        match &self.inner {
+            Inner::Plain(_) => 0,
+            Inner::ReedSolomon(reader) => reader.corrections(),
+        }
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            Inner::Plain(reader) => reader.read(buf),
+            Inner::ReedSolomon(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.inner {
+            Inner::Plain(reader) => reader.seek(pos),
+            Inner::ReedSolomon(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// writes everything from `reader` into a new blob at `path`,
+/// returns the content id and the length of the original contents
+pub fn write<R: Read>(reader: R, path: &Path, encoding: DataEncoding) -> Result<(ItemId, u64)> {
+    let file = BufWriter::new(File::create(path)?);
+    let (id, size, file) = match encoding {
+        DataEncoding::Plain => {
+            let mut file = file;
+            let (id, size) = data::copy_and_hash(reader, &mut file)?;
+            (id, size, file)
+        }
+        DataEncoding::ReedSolomon(layout) => {
+            let mut writer = EncodingWriter::with_layout(file, layout);
+            let (id, size) = data::copy_and_hash(reader, &mut writer)?;
+            (id, size, writer.finish()?)
+        }
+    };
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok((id, size))
+}
+
+/// length of the original contents stored in the blob at `path`
+pub fn size(path: &Path, encoding: DataEncoding) -> Result<u64> {
+    let length = path.metadata()?.len();
+    Ok(match encoding {
+        DataEncoding::Plain => length,
+        DataEncoding::ReedSolomon(layout) => layout.decoded_len(length),
+    })
+}
+
+/// error correction gave up on a codeword, which means the blob is corrupted rather than unreadable
+pub fn is_beyond_correction(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::InvalidData)
+}
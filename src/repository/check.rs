@@ -0,0 +1,245 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use anyhow::Result;
+use rand::Rng;
+
+use super::{
+    blob::{self, BlobReader},
+    data, ItemId, Repository,
+};
+use crate::index::{lock::LockKind, Index};
+
+/// how thorough `Repository::check` is
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CheckOptions {
+    /// fraction of blobs, between 0 and 1, to read back and hash against their ids, none are read when `None`
+    pub read_data: Option<f64>,
+}
+
+/// what `Repository::check` found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// bytes of the index files fixed by error correction while reading them
+    pub index_corrections: usize,
+    /// distinct contents known to the index
+    pub items: u64,
+    /// blobs read back and hashed
+    pub blobs_read: u64,
+    /// blobs found missing or corrupted and rebuilt from parity groups, see `Repository::add_parity`
+    pub reconstructed: Vec<ItemId>,
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// the index on disk cannot be read, nothing else was checked
+    UnreadableIndex(String),
+    MissingBlob {
+        id: ItemId,
+        relative_path: String,
+    },
+    CorruptedBlob {
+        id: ItemId,
+        relative_path: String,
+    },
+    /// an item is filed under a different id than its own
+    MisfiledItem {
+        key: ItemId,
+        id: ItemId,
+    },
+    /// newest version of a path has no entry among all versions
+    UnknownNewestItem {
+        original_source_path: String,
+        id: ItemId,
+    },
+}
+
+impl CheckReport {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Problem::UnreadableIndex(message) => write!(f, "index cannot be read: {}", message),
+            Problem::MissingBlob { id, relative_path } => write!(f, "blob {} for {} is missing", relative_path, id),
+            Problem::CorruptedBlob { id, relative_path } => {
+                write!(f, "blob {} does not match its id {}", relative_path, id)
+            }
+            Problem::MisfiledItem { key, id } => write!(f, "item {} is filed under {}", id, key),
+            Problem::UnknownNewestItem {
+                original_source_path,
+                id,
+            } => write!(
+                f,
+                "newest version {} of {} is not among known versions",
+                id, original_source_path
+            ),
+        }
+    }
+}
+
+impl Repository {
+    /// reads the index back from disk and checks that it is consistent and that every item it knows of has a blob,
+    /// optionally also reading blobs back to check they still have the right contents.
+    /// Blobs found missing or corrupted are rebuilt from parity groups when there are any that cover them
+    pub fn check(&self, options: CheckOptions) -> Result<CheckReport> {
+        let _lock = self.operation_lock(LockKind::Shared)?;
+        let mut report = CheckReport::default();
+        let index = match Index::load_counting_corrections(
+            self.path(),
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+        ) {
+            Result::Ok((index, corrections)) => {
+                report.index_corrections = corrections;
+                index
+            }
+            Err(e) => {
+                report.problems.push(Problem::UnreadableIndex(format!("{:#}", e)));
+                return Ok(report);
+            }
+        };
+
+        for newest in index.newest_items() {
+            let newest = newest?;
+            if index.item_by_id(&newest.id())?.is_none() {
+                report.problems.push(Problem::UnknownNewestItem {
+                    original_source_path: newest.original_source_path().to_string(),
+                    id: newest.id(),
+                });
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        for entry in index.items_by_id() {
+            let (key, item) = entry?;
+            report.items += 1;
+            if key != item.id() {
+                report.problems.push(Problem::MisfiledItem { key, id: item.id() });
+            }
+            let relative_path = item.relative_path().to_string();
+            let blob_path = self.path().join(&relative_path);
+            if !blob_path.is_file() {
+                report.problems.push(Problem::MissingBlob {
+                    id: item.id(),
+                    relative_path,
+                });
+                continue;
+            }
+            let read = options
+                .read_data
+                .map_or(false, |fraction| fraction >= 1.0 || rng.gen_bool(fraction.max(0.0)));
+            if read {
+                report.blobs_read += 1;
+                let read_back = BlobReader::open(&blob_path, self.config.data_encoding)
+                    .and_then(|blob| data::copy_and_hash(blob, io::sink()));
+                let intact = match read_back {
+                    Result::Ok((id, _)) => id == item.id(),
+                    Err(e) if blob::is_beyond_correction(&e) => false,
+                    Err(e) => return Err(e),
+                };
+                if !intact {
+                    report.problems.push(Problem::CorruptedBlob {
+                        id: item.id(),
+                        relative_path,
+                    });
+                }
+            }
+        }
+
+        let lost = report
+            .problems
+            .iter()
+            .filter_map(|problem| match problem {
+                Problem::MissingBlob { id, .. } | Problem::CorruptedBlob { id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !lost.is_empty() {
+            report.reconstructed = self.reconstruct(&lost)?;
+            let reconstructed = &report.reconstructed;
+            report.problems.retain(|problem| match problem {
+                Problem::MissingBlob { id, .. } | Problem::CorruptedBlob { id, .. } => !reconstructed.contains(id),
+                _ => true,
+            });
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::fs;
+
+    use super::{CheckOptions, Problem};
+    use crate::index::Index;
+    use crate::repository::{ItemId, Repository};
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn find_missing_and_corrupted_blobs() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("corrupted", "corrupted")?;
+        source.write_text_to_file("fine", "fine")?;
+        source.write_text_to_file("missing", "missing")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let item = |name: &str| -> Result<(ItemId, String)> {
+            let item = repository.newest_item_by_source_path(&source.file_path(name)?)?.unwrap();
+            Ok((item.id().clone(), item.relative_path().to_string()))
+        };
+        let (corrupted_id, corrupted_path) = item("corrupted")?;
+        let (missing_id, missing_path) = item("missing")?;
+        fs::write(repository_path.path().join(&corrupted_path), "something else")?;
+        fs::remove_file(repository_path.path().join(&missing_path))?;
+
+        let quick = repository.check(CheckOptions::default())?;
+        let full = repository.check(CheckOptions { read_data: Some(1.0) })?;
+
+        assert_eq!(quick.items, 3);
+        assert_eq!(quick.blobs_read, 0);
+        assert_eq!(
+            quick.problems,
+            vec![Problem::MissingBlob {
+                id: missing_id.clone(),
+                relative_path: missing_path.clone()
+            }]
+        );
+        assert_eq!(full.blobs_read, 2);
+        assert_eq!(full.problems.len(), 2);
+        assert!(full.problems.contains(&Problem::CorruptedBlob {
+            id: corrupted_id,
+            relative_path: corrupted_path
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn count_corrections_in_index() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let index_path = Index::index_file_path_for_repository_path(repository_path.path())?;
+        let mut index = fs::read(&index_path)?;
+        index[3] = !index[3];
+        fs::write(&index_path, index)?;
+
+        let report = repository.check(CheckOptions::default())?;
+
+        assert!(report.is_healthy());
+        assert_eq!(report.index_corrections, 1);
+        Ok(())
+    }
+}
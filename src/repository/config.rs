@@ -0,0 +1,126 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+pub use crate::io::error_correcting_encoder::Layout;
+
+const CONFIG_FILE_NAME: &str = "config";
+/// corrects up to 8 damaged bytes in every 255
+pub const DEFAULT_PARITY_LENGTH: usize = 16;
+/// with the default parity length, survives a whole damaged 4 KiB sector per stripe of about 120 KiB
+pub const DEFAULT_INTERLEAVE_DEPTH: usize = 512;
+
+/// settings fixed when a repository is created, kept unencrypted next to the index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub data_encoding: DataEncoding,
+}
+
+/// how file contents are laid out in blobs in the data directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DataEncoding {
+    /// contents as they are, used by repositories created before there was a config
+    Plain,
+    /// contents with Reed-Solomon error correction, see `io::error_correcting_encoder`.
+    /// Repositories created before the layout was configurable have codewords one after another
+    ReedSolomon(Layout),
+}
+
+impl Config {
+    /// configuration for new repositories
+    pub fn new() -> Self {
+        Config {
+            data_encoding: DataEncoding::ReedSolomon(
+                Layout::new(DEFAULT_PARITY_LENGTH, DEFAULT_INTERLEAVE_DEPTH).expect("default layout is valid"),
+            ),
+        }
+    }
+
+    /// configuration for new repositories with error correction laid out differently than by default
+    pub fn with_layout(layout: Layout) -> Self {
+        Config {
+            data_encoding: DataEncoding::ReedSolomon(layout),
+        }
+    }
+
+    /// layout of error correction for the index, snapshots and trees, the same as for the data
+    /// or codewords one after another when the data has no error correction
+    pub fn index_layout(&self) -> Layout {
+        match self.data_encoding {
+            DataEncoding::Plain => Layout::CONTIGUOUS,
+            DataEncoding::ReedSolomon(layout) => layout,
+        }
+    }
+
+    pub fn load(repository_path: &Path) -> Result<Self> {
+        let path = repository_path.join(CONFIG_FILE_NAME);
+        if !path.exists() {
+            return Ok(Config {
+                data_encoding: DataEncoding::Plain,
+            });
+        }
+        let text = fs::read_to_string(&path)?;
+        serde_json::from_str(&text).context(format!("cannot read config from: {}", path.to_string_lossy()))
+    }
+
+    pub fn save(&self, repository_path: &Path) -> Result<()> {
+        fs::write(repository_path.join(CONFIG_FILE_NAME), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::fs;
+
+    use super::{Config, DataEncoding, Layout};
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn round_trip_layout() -> Result<()> {
+        let repository_path = tempdir()?;
+        let config = Config::with_layout(Layout::new(24, 100)?);
+
+        config.save(repository_path.path())?;
+
+        assert_eq!(Config::load(repository_path.path())?, config);
+        Ok(())
+    }
+
+    #[test]
+    fn read_codewords_as_contiguous_when_config_has_no_layout() -> Result<()> {
+        let repository_path = tempdir()?;
+        fs::write(
+            repository_path.path().join("config"),
+            r#"{ "data_encoding": { "type": "reed_solomon" } }"#,
+        )?;
+
+        let config = Config::load(repository_path.path())?;
+
+        assert_eq!(config.data_encoding, DataEncoding::ReedSolomon(Layout::CONTIGUOUS));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_invalid_layout() -> Result<()> {
+        let repository_path = tempdir()?;
+        fs::write(
+            repository_path.path().join("config"),
+            r#"{ "data_encoding": { "type": "reed_solomon", "ecc_length": 0, "interleave_depth": 1 } }"#,
+        )?;
+
+        assert!(Config::load(repository_path.path()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use anyhow::*;
+use sha2::{Digest, Sha512};
+use uuid::Uuid;
+
+use super::{blob, config::DataEncoding, metadata::Metadata, ItemId};
+
+const HASHING_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// handle to the data directory of a repository that can be shared between threads,
+/// it only puts file contents in place, remembering them in the index is up to the `Repository`
+#[derive(Clone, Debug)]
+pub struct DataStore {
+    repository_path: PathBuf,
+    data_dir: PathBuf,
+    encoding: DataEncoding,
+}
+
+/// file contents that are now present in the data directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredData {
+    pub source_path: PathBuf,
+    pub relative_path: String,
+    pub id: ItemId,
+    pub metadata: Metadata,
+    /// contents were already in the repository and were not written again
+    pub deduplicated: bool,
+}
+
+/// content id of a file, identical contents always get identical ids
+pub fn calculate_id(path: &Path) -> Result<ItemId> {
+    let (id, _) = copy_and_hash(File::open(path)?, io::sink())?;
+    Ok(id)
+}
+
+/// reader that calls `on_bytes_read` with the number of bytes of every read
+struct Progress<R: Read, F: FnMut(u64)> {
+    inner: R,
+    on_bytes_read: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for Progress<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            (self.on_bytes_read)(read as u64);
+        }
+        io::Result::Ok(read)
+    }
+}
+
+/// copies everything `source` gives to `target` and returns the content id of what was actually read,
+/// so the copy can be checked without reading it again
+pub fn copy_with_id<R: Read>(source: R, target: &Path) -> Result<ItemId> {
+    let mut target = File::create(target)?;
+    let (id, _) = copy_and_hash(source, &mut target)?;
+    target.sync_all()?;
+    Ok(id)
+}
+
+/// copies everything from `reader` to `writer`, returns the content id and the number of bytes copied
+pub(crate) fn copy_and_hash<R: Read, W: Write>(mut reader: R, mut writer: W) -> Result<(ItemId, u64)> {
+    let mut hasher = Sha512::new();
+    let mut buffer = vec![0; HASHING_CHUNK_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+
+    Ok((hasher.finalize()[..].into(), copied))
+}
+
+impl DataStore {
+    pub fn new(repository_path: &Path, data_dir: &Path, encoding: DataEncoding) -> Self {
+        DataStore {
+            repository_path: repository_path.to_path_buf(),
+            data_dir: data_dir.to_path_buf(),
+            encoding,
+        }
+    }
+
+    /// reads the whole file once, returns its content id along with the contents,
+    /// calls `on_bytes_read` after every chunk read from the file
+    pub fn read<F>(&self, source_path: &Path, on_bytes_read: F) -> Result<(ItemId, Vec<u8>)>
+    where
+        F: FnMut(u64),
+    {
+        let reader = Progress {
+            inner: File::open(source_path)?,
+            on_bytes_read,
+        };
+        let mut contents = vec![];
+        let (id, _) = copy_and_hash(reader, &mut contents)?;
+        Ok((id, contents))
+    }
+
+    /// writes `contents` read from `source_path` into the data directory unless contents with the same id are already there,
+    /// goes through a temporary file so that concurrent writers of the same contents never see a partial file,
+    /// which is removed again if writing fails
+    pub fn put(&self, source_path: &Path, id: ItemId, contents: &[u8]) -> Result<StoredData> {
+        let destination = self.data_dir.join(id.to_string());
+        let parent = destination
+            .parent()
+            .ok_or_else(|| anyhow!("cannot compute parent path for {}", &destination.to_string_lossy()))?;
+        fs::create_dir_all(parent)?;
+
+        let metadata = Metadata::from(&source_path.metadata()?);
+        let deduplicated = destination.exists();
+        if !deduplicated {
+            let temporary = parent.join(format!("{}.{}.tmp", id, Uuid::new_v4()));
+            let written =
+                blob::write(contents, &temporary, self.encoding).and_then(|_| Ok(fs::rename(&temporary, &destination)?));
+            if let Err(e) = written {
+                let _ = fs::remove_file(&temporary);
+                return Err(e);
+            }
+        }
+
+        let relative_path = destination.strip_prefix(&self.repository_path)?;
+        Ok(StoredData {
+            source_path: source_path.to_path_buf(),
+            relative_path: relative_path.to_string_lossy().to_string(),
+            id,
+            metadata: Metadata {
+                size: contents.len() as u64,
+                ..metadata
+            },
+            deduplicated,
+        })
+    }
+
+    /// streams contents into the data directory while hashing them, for data that only exists as a stream.
+    /// They are remembered under `source_path`, with the size in `metadata` set to the number of bytes read.
+    /// Nothing is left behind if reading fails midway
+    pub fn store_reader<R: Read>(&self, reader: R, source_path: &Path, metadata: Metadata) -> Result<StoredData> {
+        fs::create_dir_all(&self.data_dir)?;
+        let temporary = self.data_dir.join(format!("{}.tmp", Uuid::new_v4()));
+        let (id, size) = match blob::write(reader, &temporary, self.encoding) {
+            Result::Ok(copied) => copied,
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(e);
+            }
+        };
+
+        let destination = self.data_dir.join(id.to_string());
+        let deduplicated = destination.exists();
+        if deduplicated {
+            fs::remove_file(&temporary)?;
+        } else {
+            fs::rename(&temporary, &destination)?;
+        }
+
+        let relative_path = destination.strip_prefix(&self.repository_path)?;
+        Ok(StoredData {
+            source_path: source_path.to_path_buf(),
+            relative_path: relative_path.to_string_lossy().to_string(),
+            id,
+            metadata: Metadata { size, ..metadata },
+            deduplicated,
+        })
+    }
+
+    /// hashes the file while writing it into the data directory, so that it is read only once.
+    /// Contents already in the repository are written all the same and only then dropped
+    pub fn store_with_progress<F>(&self, source_path: &Path, on_bytes_read: F) -> Result<StoredData>
+    where
+        F: FnMut(u64),
+    {
+        let metadata = Metadata::from(&source_path.metadata()?);
+        let reader = Progress {
+            inner: File::open(source_path)?,
+            on_bytes_read,
+        };
+        self.store_reader(reader, source_path, metadata)
+    }
+
+    pub fn store(&self, source_path: &Path) -> Result<StoredData> {
+        self.store_with_progress(source_path, |_| {})
+    }
+}
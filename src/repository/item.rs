@@ -1,13 +1,52 @@
-use crate::{repository::ItemId, version::Version};
+use crate::{
+    repository::{
+        blob::{self, BlobReader},
+        config::DataEncoding,
+        data,
+        metadata::Metadata,
+        reader::ItemReader,
+        ItemId,
+    },
+    version::Version,
+};
 use anyhow::Result;
 use anyhow::*;
+use nix::sys::stat::utimes;
+use nix::sys::time::{TimeVal, TimeValLike};
 use nix::unistd::getpid;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fmt, path::PathBuf};
 use std::{
     fmt::{Display, Formatter},
     fs,
 };
+use uuid::Uuid;
+
+/// ends the name of a file being restored, which starts with the name of its target and a uuid of its own,
+/// so that items restored to the same target at the same time do not write into each other's files
+const PARTIAL_FILE_SUFFIX: &str = ".bakare-partial";
+
+/// what is wrong with the stored contents of an item that could not be restored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// contents are not in the data directory
+    Missing,
+    /// contents do not hash to the item id
+    Corrupted,
+}
+
+impl Display for Damage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Damage::Missing => write!(f, "stored contents are missing"),
+            Damage::Corrupted => write!(f, "stored contents are corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Damage {}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RepositoryItem {
@@ -16,6 +55,10 @@ pub struct RepositoryItem {
     original_source_path: String,
     id: ItemId,
     version: Version,
+    metadata: Metadata,
+    encoding: DataEncoding,
+    /// when this version was backed up, where known
+    stored: Option<SystemTime>,
 }
 
 impl PartialOrd for RepositoryItem {
@@ -25,35 +68,123 @@ impl PartialOrd for RepositoryItem {
 }
 
 impl RepositoryItem {
-    pub fn from(original_source_path: &str, absolute_path: &Path, relative_path: &str, id: ItemId, version: Version) -> Self {
+    pub fn from(
+        original_source_path: &str,
+        absolute_path: &Path,
+        relative_path: &str,
+        id: ItemId,
+        version: Version,
+        metadata: Metadata,
+        encoding: DataEncoding,
+    ) -> Self {
         RepositoryItem {
             relative_path: relative_path.to_string(),
             absolute_path: absolute_path.to_path_buf(),
             original_source_path: original_source_path.to_string(),
             id,
             version,
+            metadata,
+            encoding,
+            stored: None,
         }
     }
 
-    pub fn save(&self, save_to: &Path) -> Result<()> {
-        let original_source_path = Path::new(self.original_source_path());
-        let source_path_relative = original_source_path.strip_prefix("/")?;
+    pub fn with_stored(mut self, stored: Option<SystemTime>) -> Self {
+        self.stored = stored;
+        self
+    }
 
-        let target_path = save_to.join(&source_path_relative);
+    /// restores contents under `save_to`, skipping the write when the target already has the right contents.
+    /// Returns the path of the restored file
+    pub fn save(&self, save_to: &Path) -> Result<PathBuf> {
+        let target_path = self.target_path(save_to)?;
+        if self.is_saved_at(&target_path)? {
+            log::debug!("[{}] {} already restored", getpid(), target_path.to_string_lossy());
+            return Ok(target_path);
+        }
+        self.save_to_file(&target_path)?;
+        Ok(target_path)
+    }
+
+    /// writes contents to exactly `target_path` and reapplies the stored metadata.
+    /// Data is written to a temporary file first so an interrupted restore never leaves a half-written file behind.
+    /// Fails with `Damage` and leaves `target_path` alone if the stored contents are missing or do not match the id
+    pub fn save_to_file(&self, target_path: &Path) -> Result<()> {
         let parent = target_path
             .parent()
             .ok_or_else(|| anyhow!("cannot compute parent path for {}", &target_path.to_string_lossy()))?;
+        let file_name = target_path
+            .file_name()
+            .ok_or_else(|| anyhow!("cannot compute file name for {}", &target_path.to_string_lossy()))?;
         log::debug!("[{}] saving data to {}", getpid(), target_path.to_string_lossy());
         fs::create_dir_all(parent)?;
         if !self.absolute_path.exists() {
-            return Err(anyhow!("corrupted repository"));
+            return Err(Damage::Missing).context(self.to_string());
         }
-        fs::copy(&self.absolute_path, &target_path)?;
+        let partial_path = parent.join(format!(
+            ".{}.{}{}",
+            file_name.to_string_lossy(),
+            Uuid::new_v4(),
+            PARTIAL_FILE_SUFFIX
+        ));
+        let copied =
+            BlobReader::open(&self.absolute_path, self.encoding).and_then(|blob| data::copy_with_id(blob, &partial_path));
+        match copied {
+            Result::Ok(id) if id == self.id => {}
+            Result::Ok(_) => {
+                fs::remove_file(&partial_path)?;
+                return Err(Damage::Corrupted).context(self.to_string());
+            }
+            Err(e) if blob::is_beyond_correction(&e) => {
+                let _ = fs::remove_file(&partial_path);
+                return Err(Damage::Corrupted).context(self.to_string());
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                return Err(e);
+            }
+        }
+        self.apply_metadata(&partial_path)?;
+        fs::rename(&partial_path, target_path)?;
 
         log::debug!("[{}] saved data to {}", getpid(), &target_path.to_string_lossy());
         Ok(())
     }
 
+    /// stored contents, checked against the id when read through to the end, see `ItemReader`
+    pub fn reader(&self) -> Result<ItemReader> {
+        ItemReader::open(&self.absolute_path, &self.id, self.encoding).context(self.to_string())
+    }
+
+    /// whether a file with the same contents already exists at `target_path`
+    pub fn is_saved_at(&self, target_path: &Path) -> Result<bool> {
+        Ok(target_path.is_file() && data::calculate_id(target_path)? == self.id)
+    }
+
+    fn apply_metadata(&self, path: &Path) -> Result<()> {
+        if let Some(mode) = self.metadata.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if let Some(modified) = self.metadata.modified {
+            let since_epoch = modified.duration_since(UNIX_EPOCH)?;
+            let modified = TimeVal::microseconds(since_epoch.as_micros() as i64);
+            utimes(path, &modified, &modified)?;
+        }
+        Ok(())
+    }
+
+    /// where `save` puts the file: its original absolute path recreated under `save_to`
+    pub fn target_path(&self, save_to: &Path) -> Result<PathBuf> {
+        let original_source_path = Path::new(self.original_source_path());
+        let source_path_relative = original_source_path.strip_prefix("/")?;
+        Ok(save_to.join(source_path_relative))
+    }
+
+    /// size of the stored contents in bytes, as they were before encoding
+    pub fn size(&self) -> Result<u64> {
+        blob::size(&self.absolute_path, self.encoding)
+    }
+
     pub fn relative_path(&self) -> &str {
         &self.relative_path
     }
@@ -69,6 +200,14 @@ impl RepositoryItem {
     pub fn id(&self) -> &ItemId {
         &self.id
     }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn stored(&self) -> Option<SystemTime> {
+        self.stored
+    }
 }
 
 impl Display for RepositoryItem {
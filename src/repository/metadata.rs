@@ -0,0 +1,22 @@
+use std::{fs, os::unix::fs::PermissionsExt, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// file attributes remembered at backup time and reapplied on restore
+#[derive(Clone, Copy, Debug, Default, PartialOrd, PartialEq, Ord, Eq, Serialize, Deserialize, Hash)]
+pub struct Metadata {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// unix permission bits
+    pub mode: Option<u32>,
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Metadata {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode: Some(metadata.permissions().mode()),
+        }
+    }
+}
@@ -1,22 +1,37 @@
+pub mod blob;
+pub mod check;
+pub mod config;
+pub mod data;
 pub mod item;
-
-use std::{fmt, io};
+pub mod metadata;
+pub mod parity;
+pub mod prune;
+pub mod reader;
+pub mod scrub;
+pub mod tree;
+
+use std::fmt;
 use std::{
     fmt::{Debug, Formatter},
     path::PathBuf,
 };
 use std::{fs, path::Path};
-use std::{fs::File, io::BufReader};
 
-use crate::index::{Index, IndexItemIterator};
+use crate::index::{
+    lock::{self, Lock},
+    paged, Index, IndexItemIterator,
+};
 use anyhow::Result;
-use anyhow::*;
+use config::Config;
+use data::{DataStore, StoredData};
 use item::RepositoryItem;
 use serde::{Deserialize, Serialize};
-use sha2::Digest;
-use sha2::Sha512;
 use walkdir::WalkDir;
 
+pub use crate::index::lock::{LockEntry, LockKind, LockLost, LockOwner};
+pub use crate::index::paged::MemoryBudget;
+pub use crate::index::IndexFormat;
+
 /// represents a place where backup is stored an can be restored from.
 /// right now only on-disk directory storage is supported
 /// repository always knows the newest version of the index and is responsible for syncing the index to disk
@@ -27,9 +42,18 @@ pub struct Repository {
     path: PathBuf,
     index: Index,
     secret: String,
+    config: Config,
+    /// identity versions made through this repository are recorded under, the hostname unless set with `with_writer`
+    writer: String,
+    /// how much of the index is held in memory, past which the rest is paged from disk, see `open_with_memory_budget`
+    memory_budget: MemoryBudget,
 }
 
 const DATA_DIR_NAME: &str = "data";
+/// where the index is paged out to past the memory budget, unless the budget says otherwise
+const INDEX_PAGES_DIR_NAME: &str = "index_pages";
+/// locks of whole operations, apart from the locks of the index at the top of the repository
+pub(crate) const OPERATION_LOCKS_DIR_NAME: &str = "locks";
 
 #[derive(Clone, PartialOrd, PartialEq, Ord, Eq, Serialize, Deserialize, Hash)]
 pub struct ItemId(#[serde(with = "base64")] Vec<u8>);
@@ -45,31 +69,37 @@ mod base64 {
     use ::base64;
     use serde::{de, Deserialize, Deserializer, Serializer};
 
+    /// binary formats get the bytes as they are
     pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&base64::encode(bytes))
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s = <&str>::deserialize(deserializer)?;
-        base64::decode(s).map_err(de::Error::custom)
+        if deserializer.is_human_readable() {
+            let s = <&str>::deserialize(deserializer)?;
+            base64::decode(s).map_err(de::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
     }
 }
 
 impl<'a> Iterator for RepositoryItemIterator<'a> {
-    type Item = RepositoryItem;
+    type Item = Result<RepositoryItem>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let item = self.iterator.next();
-        match item {
-            None => None,
-            Some(item) => self.repository.repository_item(&item).ok(),
-        }
+        let item = self.iterator.next()?;
+        Some(item.and_then(|item| self.repository.repository_item(&item)))
     }
 }
 
@@ -97,53 +127,151 @@ impl Debug for ItemId {
     }
 }
 
-impl<'a> Repository {
+impl Repository {
     pub fn init(path: &Path, secret: &str) -> Result<Repository> {
+        Self::init_with_config(path, secret, Config::new())
+    }
+
+    /// same as `init`, with settings other than the defaults, they are ignored when the repository already exists
+    pub fn init_with_config(path: &Path, secret: &str, config: Config) -> Result<Repository> {
         fs::create_dir_all(path)?;
+        if !path.join(DATA_DIR_NAME).exists() {
+            config.save(path)?;
+        }
         let mut index = Index::new()?;
-        index.save(path, secret.as_bytes())?;
+        index.save(path, secret.as_bytes(), Config::load(path)?.index_layout(), &paged::UNBOUNDED)?;
         let repository = Repository::open(path, secret)?;
         fs::create_dir_all(repository.data_dir()?)?;
         Ok(repository)
     }
 
     pub fn open(path: &Path, secret: &str) -> Result<Repository> {
-        let index = Index::load(path, secret.as_bytes())?;
+        Self::open_with_memory_budget(path, secret, paged::UNBOUNDED)
+    }
+
+    /// same as `open`, keeping to `memory_budget` and paging the rest of the index from an encrypted lookup structure
+    /// in its spill directory, or in the repository when it has none, for indexes too big to be held whole.
+    /// Operations that read the index themselves, like `check` or `compact_index`, keep to the same budget
+    pub fn open_with_memory_budget(path: &Path, secret: &str, memory_budget: MemoryBudget) -> Result<Repository> {
+        let config = Config::load(path)?;
+        let memory_budget = memory_budget.with_default_spill_directory(&path.join(INDEX_PAGES_DIR_NAME));
+        let index = Index::load(path, secret.as_bytes(), config.index_layout(), &memory_budget)?;
         let repository = Repository {
             path: path.to_path_buf(),
             index,
             secret: secret.to_owned(),
+            config,
+            writer: lock::hostname(),
+            memory_budget,
         };
 
         Ok(repository)
     }
 
+    /// sets the identity versions are recorded under, writers sharing one are told apart only by version numbers
+    pub fn with_writer(mut self, writer: &str) -> Self {
+        self.writer = writer.to_string();
+        self
+    }
+
+    /// locks held on the repository at `path`, which does not need to be opened for this,
+    /// so that locks can be looked at and removed while they keep everyone else out
+    pub fn locks(path: &Path) -> Result<Vec<LockEntry>> {
+        let mut locks = Lock::list(path)?;
+        let operation_locks_dir = path.join(OPERATION_LOCKS_DIR_NAME);
+        if operation_locks_dir.exists() {
+            locks.extend(Lock::list(&operation_locks_dir)?);
+        }
+        Ok(locks)
+    }
+
+    /// held for the whole of an operation that writes to the data directory,
+    /// shared by everything that writes contents and exclusive for `prune`, which removes what the index does not know of
+    pub(crate) fn operation_lock(&self, kind: LockKind) -> Result<Lock> {
+        let directory = self.path().join(OPERATION_LOCKS_DIR_NAME);
+        fs::create_dir_all(&directory)?;
+        match kind {
+            LockKind::Shared => Lock::shared(&directory),
+            LockKind::Exclusive => Lock::lock(&directory),
+        }
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn save_index(&mut self) -> Result<()> {
-        self.index.save(&self.path, self.secret.as_bytes())
+        self.index.save(
+            &self.path,
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+        )
     }
 
-    pub fn store(&mut self, source_path: &Path) -> Result<()> {
-        let id = Repository::calculate_id(source_path)?;
-        let destination = self.data_dir()?;
-        let destination = destination.join(&id.to_string());
+    /// combines the index segments written by saves so far into a single file, returns how many there were.
+    /// The file is always written in the binary format, which makes compacting the way to migrate JSON indexes
+    pub fn compact_index(&self) -> Result<usize> {
+        Index::compact(
+            &self.path,
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+        )
+    }
+
+    /// whether part of the index went over the memory budget and is read from disk as needed
+    pub fn index_is_paged(&self) -> bool {
+        self.index.is_paged()
+    }
+
+    pub fn index_format(&self) -> Result<IndexFormat> {
+        Index::format(&self.path, self.secret.as_bytes(), self.config.index_layout())
+    }
+
+    /// records how far a backup of `backup_source_path` got and persists the index,
+    /// so that an interrupted backup can be resumed from there
+    pub fn checkpoint(&mut self, backup_source_path: &Path, last_stored_path: &Path) -> Result<()> {
+        self.index.remember_checkpoint(backup_source_path, last_stored_path);
+        self.save_index()
+    }
+
+    /// marks the backup of `backup_source_path` as complete, takes effect on the next index save
+    pub fn finish_checkpoint(&mut self, backup_source_path: &Path) {
+        self.index.forget_checkpoint(backup_source_path);
+    }
+
+    /// last path stored by an unfinished backup of `backup_source_path`, if there is one
+    pub fn checkpoint_for(&self, backup_source_path: &Path) -> Option<PathBuf> {
+        self.index.checkpoint(backup_source_path)
+    }
 
+    pub fn store(&mut self, source_path: &Path) -> Result<()> {
         if !source_path.metadata()?.is_file() {
             return Ok(());
         }
-        let parent = destination
-            .parent()
-            .ok_or_else(|| anyhow!("cannot compute parent path for {}", &destination.to_string_lossy()))?;
-        fs::create_dir_all(parent)?;
-        if !destination.exists() {
-            fs::copy(&source_path, &destination)?;
-        }
-        let relative_path = destination.strip_prefix(&self.path())?;
-        self.index.remember(source_path, &relative_path.to_string_lossy(), id);
-        Ok(())
+        let stored = self.data_store().store(source_path)?;
+        self.remember(&stored)
+    }
+
+    /// handle for putting file contents into the repository from other threads
+    pub fn data_store(&self) -> DataStore {
+        DataStore::new(self.path(), &self.path().join(DATA_DIR_NAME), self.config.data_encoding)
+    }
+
+    /// adds data previously put in place by a `DataStore` to the index
+    pub fn remember(&mut self, stored: &StoredData) -> Result<()> {
+        self.index.remember(
+            &self.writer,
+            &stored.source_path,
+            &stored.relative_path,
+            stored.id.clone(),
+            stored.metadata,
+        )
     }
 
     pub fn newest_item_by_source_path(&self, path: &Path) -> Result<Option<RepositoryItem>> {
@@ -162,18 +290,45 @@ impl<'a> Repository {
         }
     }
 
+    /// every version of `path` that was backed up, oldest first
+    pub fn history(&self, path: &Path) -> Result<Vec<RepositoryItem>> {
+        self.index
+            .history(path)?
+            .iter()
+            .map(|item| self.repository_item(item))
+            .collect()
+    }
+
+    /// versions of a path made by writers that did not know of each other, the newest one first,
+    /// for every path that has them. Backing the path up again resolves the conflict
+    pub fn conflicts(&self) -> Result<Vec<Vec<RepositoryItem>>> {
+        let mut paths = self.index.conflicting_paths().map(PathBuf::from).collect::<Vec<_>>();
+        paths.sort();
+        let mut conflicts = vec![];
+        for path in paths {
+            let mut versions = vec![];
+            if let Some(newest) = self.index.newest_item_by_source_path(&path)? {
+                versions.push(self.repository_item(&newest)?);
+            }
+            for item in self.index.conflicting_items(&path).iter().rev() {
+                versions.push(self.repository_item(item)?);
+            }
+            conflicts.push(versions);
+        }
+        Ok(conflicts)
+    }
+
     pub fn find_latest_by_path_fragment(&self, path_fragment: &str) -> Result<Option<RepositoryItem>> {
-        let index_item = self
-            .index
-            .newest_items()
-            .find(|item| item.original_source_path().contains(path_fragment));
-        match index_item {
-            None => Ok(None),
-            Some(item) => Ok(Some(self.repository_item(&item)?)),
+        for item in self.index.newest_items() {
+            let item = item?;
+            if item.original_source_path().contains(path_fragment) {
+                return Ok(Some(self.repository_item(&item)?));
+            }
         }
+        Ok(None)
     }
 
-    pub fn newest_items(&self) -> RepositoryItemIterator {
+    pub fn newest_items(&self) -> RepositoryItemIterator<'_> {
         RepositoryItemIterator {
             repository: self,
             iterator: self.index.newest_items(),
@@ -192,7 +347,10 @@ impl<'a> Repository {
             relative_path,
             index_item.id(),
             index_item.version(),
-        ))
+            index_item.metadata(),
+            self.config.data_encoding,
+        )
+        .with_stored(index_item.stored()))
     }
 
     pub fn data_weight(&self) -> Result<u64> {
@@ -209,21 +367,14 @@ impl<'a> Repository {
     fn data_dir(&self) -> Result<PathBuf> {
         Ok(self.path().join(DATA_DIR_NAME))
     }
-
-    fn calculate_id(source_path: &Path) -> Result<ItemId> {
-        let source_file = File::open(source_path)?;
-        let mut reader = BufReader::new(source_file);
-        let mut hasher = Sha512::new();
-
-        io::copy(&mut reader, &mut hasher)?;
-
-        Ok(hasher.finalize()[..].into())
-    }
 }
 
 #[cfg(test)]
 mod must {
-    use super::Repository;
+    use super::{
+        config::{Config, DataEncoding, Layout},
+        Index, Repository,
+    };
     use crate::test::source::TestSource;
     use anyhow::Result;
     use tempfile::tempdir;
@@ -235,7 +386,10 @@ mod must {
         let source = TestSource::new()?;
         let repository_path = tempdir()?;
         let secret = "some secret";
-        Repository::init(repository_path.path(), secret)?;
+        let config = Config {
+            data_encoding: DataEncoding::Plain,
+        };
+        Repository::init_with_config(repository_path.path(), secret, config)?;
 
         let mut backup_repository = Repository::open(repository_path.path(), secret)?;
         source.write_random_bytes_to_file("file1", file_size1)?;
@@ -248,4 +402,148 @@ mod must {
         assert_eq!(file_size1 + file_size2, backup_repository.data_weight()?);
         Ok(())
     }
+
+    #[test]
+    fn have_size_equal_to_sum_of_sizes_of_backed_up_files_with_error_correction() -> Result<()> {
+        let file_size1 = 13;
+        let file_size2 = 27;
+        let source = TestSource::new()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+
+        let mut backup_repository = Repository::open(repository_path.path(), secret)?;
+        source.write_random_bytes_to_file("file1", file_size1)?;
+        backup_repository.store(&source.file_path("file1")?)?;
+
+        source.write_random_bytes_to_file("file2", file_size2)?;
+
+        backup_repository.store(&source.file_path("file2")?)?;
+
+        let error_correction_per_small_file = 16;
+        assert_eq!(
+            file_size1 + file_size2 + 2 * error_correction_per_small_file,
+            backup_repository.data_weight()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keep_files_with_parity_length_it_was_created_with() -> Result<()> {
+        let source = TestSource::new()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        let config = Config::with_layout(Layout::new(32, 4)?);
+        Repository::init_with_config(repository_path.path(), secret, config)?;
+
+        let mut backup_repository = Repository::open(repository_path.path(), secret)?;
+        source.write_random_bytes_to_file("file", 300)?;
+        backup_repository.store(&source.file_path("file")?)?;
+
+        assert_eq!(backup_repository.config(), &config);
+        assert_eq!(backup_repository.data_weight()?, 300 + 2 * 32);
+        Ok(())
+    }
+
+    #[test]
+    fn protect_index_with_layout_it_was_created_with() -> Result<()> {
+        let source = TestSource::new()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        let layout = Layout::new(32, 4)?;
+        Repository::init_with_config(repository_path.path(), secret, Config::with_layout(layout))?;
+        let mut backup_repository = Repository::open(repository_path.path(), secret)?;
+        source.write_text_to_file("file", "contents")?;
+        backup_repository.store(&source.file_path("file")?)?;
+        backup_repository.save_index()?;
+        backup_repository.compact_index()?;
+
+        let reopened = Repository::open(repository_path.path(), secret)?;
+        assert!(reopened.newest_item_by_source_path(&source.file_path("file")?)?.is_some());
+        assert!(Index::format(repository_path.path(), secret.as_bytes(), layout).is_ok());
+        assert!(Index::format(repository_path.path(), secret.as_bytes(), Layout::CONTIGUOUS).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_history_of_each_path_apart_from_others_with_same_contents() -> Result<()> {
+        let source = TestSource::new()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        let config_path = source.file_path("config.yaml")?;
+        for contents in ["first", "second", "first"] {
+            source.write_text_to_file("config.yaml", contents)?;
+            repository.store(&config_path)?;
+            repository.save_index()?;
+        }
+        source.write_text_to_file("copy.yaml", "first")?;
+        repository.store(&source.file_path("copy.yaml")?)?;
+        repository.save_index()?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let history = repository.history(&config_path)?;
+
+        let versions = history
+            .iter()
+            .map(|item| (item.version().to_string(), item.metadata().size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            vec![("1".to_string(), 5), ("2".to_string(), 6), ("3".to_string(), 5)]
+        );
+        assert_eq!(history[0].id(), history[2].id());
+        assert!(history.iter().all(|item| item.stored().is_some()));
+        assert_eq!(repository.history(&source.file_path("copy.yaml")?)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_both_versions_made_without_knowing_of_each_other() -> Result<()> {
+        let source = TestSource::new()?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let file_path = source.file_path("file")?;
+        source.write_text_to_file("file", "common")?;
+        let mut common = Repository::open(repository_path.path(), secret)?.with_writer("common host");
+        common.store(&file_path)?;
+        common.save_index()?;
+
+        let mut first = Repository::open(repository_path.path(), secret)?.with_writer("first host");
+        let mut second = Repository::open(repository_path.path(), secret)?.with_writer("second host");
+        source.write_text_to_file("file", "first")?;
+        first.store(&file_path)?;
+        first.save_index()?;
+        source.write_text_to_file("file", "second")?;
+        second.store(&file_path)?;
+        second.save_index()?;
+
+        let mut repository = Repository::open(repository_path.path(), secret)?.with_writer("common host");
+        let conflicts = repository.conflicts()?;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].len(), 2);
+        assert!(conflicts[0].iter().all(|item| item.version().to_string() == "2"));
+        assert_ne!(conflicts[0][0].id(), conflicts[0][1].id());
+        assert_eq!(
+            repository.newest_item_by_source_path(&file_path)?.unwrap().id(),
+            conflicts[0][0].id()
+        );
+
+        repository.store(&file_path)?;
+        repository.save_index()?;
+
+        let repository = Repository::open(repository_path.path(), secret)?;
+        assert!(repository.conflicts()?.is_empty());
+        assert_eq!(
+            repository
+                .newest_item_by_source_path(&file_path)?
+                .unwrap()
+                .version()
+                .to_string(),
+            "3"
+        );
+        Ok(())
+    }
 }
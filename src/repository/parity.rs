@@ -0,0 +1,508 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use anyhow::*;
+use reed_solomon::{Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::index::lock::LockKind;
+
+use super::{
+    blob::{self, BlobReader},
+    data, ItemId, Repository,
+};
+
+const PARITY_DIR_NAME: &str = "parity";
+const CHUNK_SIZE: usize = 64 * 1024;
+/// bytes at the same position in all blobs of a group make up one Reed-Solomon codeword, which cannot be longer
+const MAX_GROUP_SIZE: usize = u8::MAX as usize;
+const MAX_HEADER_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// how many blobs go into a parity group and how many parity blobs are added to it,
+/// any `parity_blobs` files of a group can be lost and rebuilt from the others
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParityScheme {
+    data_blobs: usize,
+    parity_blobs: usize,
+}
+
+/// what `Repository::add_parity` did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParityReport {
+    /// groups created
+    pub groups: u64,
+    /// blobs in the groups created
+    pub blobs: u64,
+}
+
+/// describes a parity group, a copy starts every parity blob of the group so that any one of them is enough
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct GroupHeader {
+    group: String,
+    members: Vec<Member>,
+    parity_blobs: usize,
+    /// which of the parity blobs of the group this is
+    index: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Member {
+    id: ItemId,
+    relative_path: String,
+    /// length of the blob file as it is on disk
+    length: u64,
+}
+
+/// a parity group as found on disk, with whichever of its parity blobs are still there
+struct Group {
+    members: Vec<Member>,
+    parity_blobs: usize,
+    parity_paths: BTreeMap<usize, PathBuf>,
+}
+
+impl ParityScheme {
+    pub fn new(data_blobs: usize, parity_blobs: usize) -> Result<Self> {
+        if data_blobs < 1 || parity_blobs < 1 || data_blobs + parity_blobs > MAX_GROUP_SIZE {
+            return Err(anyhow!(
+                "parity groups need at least one data and one parity blob and at most {} blobs in total, got {} and {}",
+                MAX_GROUP_SIZE,
+                data_blobs,
+                parity_blobs
+            ));
+        }
+        Ok(ParityScheme {
+            data_blobs,
+            parity_blobs,
+        })
+    }
+
+    pub fn data_blobs(&self) -> usize {
+        self.data_blobs
+    }
+
+    pub fn parity_blobs(&self) -> usize {
+        self.parity_blobs
+    }
+}
+
+impl Default for ParityScheme {
+    /// survives losing any 2 of 12 files
+    fn default() -> Self {
+        ParityScheme {
+            data_blobs: 10,
+            parity_blobs: 2,
+        }
+    }
+}
+
+impl Repository {
+    /// puts all blobs not yet in a parity group into new groups and writes parity blobs for them,
+    /// `check` uses those to rebuild blobs that went missing
+    pub fn add_parity(&self, scheme: ParityScheme) -> Result<ParityReport> {
+        let _lock = self.operation_lock(LockKind::Shared)?;
+        let covered = self
+            .parity_groups()?
+            .into_iter()
+            .flat_map(|group| group.members.into_iter().map(|member| member.id))
+            .collect::<HashSet<_>>();
+        let mut members = vec![];
+        for entry in self.index.items_by_id() {
+            let (id, item) = entry?;
+            if covered.contains(&id) {
+                continue;
+            }
+            let relative_path = item.relative_path().to_string();
+            match self.path().join(&relative_path).metadata() {
+                Result::Ok(metadata) => members.push(Member {
+                    id,
+                    relative_path,
+                    length: metadata.len(),
+                }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    log::warn!("not adding missing blob {} to a parity group", relative_path)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut report = ParityReport::default();
+        for group in members.chunks(scheme.data_blobs) {
+            self.write_parity_group(group, scheme.parity_blobs)?;
+            report.groups += 1;
+            report.blobs += group.len() as u64;
+        }
+        Ok(report)
+    }
+
+    /// rebuilds lost blobs from the parity groups they are in, every rebuilt blob is checked against its id
+    /// before it replaces whatever is left of the lost one. Returns ids of the blobs rebuilt
+    pub(super) fn reconstruct(&self, lost: &[ItemId]) -> Result<Vec<ItemId>> {
+        let lost = lost.iter().collect::<HashSet<_>>();
+        let mut rebuilt = vec![];
+        for group in self.parity_groups()? {
+            let wanted = group
+                .members
+                .iter()
+                .enumerate()
+                .filter(|(_, member)| lost.contains(&member.id))
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>();
+            if wanted.is_empty() {
+                continue;
+            }
+            match self.rebuild(&group, &wanted, &lost) {
+                Result::Ok(ids) => rebuilt.extend(ids),
+                Err(e) => log::warn!("cannot rebuild blobs from parity group: {:#}", e),
+            }
+        }
+        Ok(rebuilt)
+    }
+
+    fn write_parity_group(&self, members: &[Member], parity_blobs: usize) -> Result<()> {
+        let group = Uuid::new_v4().to_string();
+        let parity_dir = self.parity_dir();
+        fs::create_dir_all(&parity_dir)?;
+        let mut readers = members
+            .iter()
+            .map(|member| Ok(Some(BufReader::new(File::open(self.path().join(&member.relative_path))?))))
+            .collect::<Result<Vec<_>>>()?;
+        let mut writers = vec![];
+        for index in 0..parity_blobs {
+            let temporary = parity_dir.join(format!("{}.{}.tmp", group, index));
+            let mut writer = BufWriter::new(File::create(&temporary)?);
+            write_header(
+                &mut writer,
+                &GroupHeader {
+                    group: group.clone(),
+                    members: members.to_vec(),
+                    parity_blobs,
+                    index,
+                },
+            )?;
+            writers.push((writer, temporary, parity_dir.join(format!("{}.{}", group, index))));
+        }
+
+        let encoder = Encoder::new(parity_blobs);
+        let lengths = members.iter().map(|member| member.length).collect::<Vec<_>>();
+        let mut parity = vec![vec![0; CHUNK_SIZE]; parity_blobs];
+        for_each_chunk(&mut readers, &lengths, |codewords, chunk| {
+            for (position, codeword) in codewords.iter().take(chunk).enumerate() {
+                for (parity, byte) in parity.iter_mut().zip(encoder.encode(codeword).ecc()) {
+                    parity[position] = *byte;
+                }
+            }
+            for ((writer, _, _), parity) in writers.iter_mut().zip(&parity) {
+                writer.write_all(&parity[..chunk])?;
+            }
+            Ok(())
+        })?;
+
+        for (writer, temporary, path) in writers {
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(temporary, path)?;
+        }
+        Ok(())
+    }
+
+    /// rebuilds members at positions `wanted`, treating every member in `lost` or missing from disk,
+    /// and every missing parity blob, as erased
+    fn rebuild(&self, group: &Group, wanted: &[usize], lost: &HashSet<&ItemId>) -> Result<Vec<ItemId>> {
+        let data_blobs = group.members.len();
+        let mut readers = vec![];
+        let mut erasures = vec![];
+        for (position, member) in group.members.iter().enumerate() {
+            let path = self.path().join(&member.relative_path);
+            if lost.contains(&member.id) || !path.is_file() {
+                readers.push(None);
+                erasures.push(position as u8);
+            } else {
+                readers.push(Some(BufReader::new(File::open(path)?)));
+            }
+        }
+        for index in 0..group.parity_blobs {
+            match group.parity_paths.get(&index) {
+                Some(path) => {
+                    let mut reader = BufReader::new(File::open(path)?);
+                    read_header(&mut reader)?;
+                    readers.push(Some(reader));
+                }
+                None => {
+                    readers.push(None);
+                    erasures.push((data_blobs + index) as u8);
+                }
+            }
+        }
+        if erasures.len() > group.parity_blobs {
+            return Err(anyhow!(
+                "{} of {} blobs are gone, at most {} can be rebuilt",
+                erasures.len(),
+                data_blobs + group.parity_blobs,
+                group.parity_blobs
+            ));
+        }
+
+        let mut writers = wanted
+            .iter()
+            .map(|position| {
+                let member = &group.members[*position];
+                let temporary =
+                    self.path()
+                        .join(&member.relative_path)
+                        .with_file_name(format!("{}.{}.tmp", member.id, Uuid::new_v4()));
+                Ok((BufWriter::new(File::create(&temporary)?), temporary, *position))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let parity_length = group.members.iter().map(|member| member.length).max().unwrap_or(0);
+        let lengths = group
+            .members
+            .iter()
+            .map(|member| member.length)
+            .chain((0..group.parity_blobs).map(|_| parity_length))
+            .collect::<Vec<_>>();
+        let decoder = Decoder::new(group.parity_blobs);
+        let mut done = 0;
+        let decoded = for_each_chunk(&mut readers, &lengths, |codewords, chunk| {
+            let corrected = codewords
+                .iter()
+                .take(chunk)
+                .map(|codeword| {
+                    decoder
+                        .correct(codeword, Some(&erasures))
+                        .map_err(|e| anyhow!("cannot correct codeword: {:?}", e))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for (writer, _, position) in writers.iter_mut() {
+                let available = group.members[*position].length.saturating_sub(done).min(chunk as u64) as usize;
+                let bytes = corrected[..available]
+                    .iter()
+                    .map(|codeword| codeword.data()[*position])
+                    .collect::<Vec<_>>();
+                writer.write_all(&bytes)?;
+            }
+            done += chunk as u64;
+            Ok(())
+        });
+        if let Err(e) = decoded {
+            for (_, temporary, _) in writers {
+                let _ = fs::remove_file(temporary);
+            }
+            return Err(e);
+        }
+
+        let mut rebuilt = vec![];
+        for (writer, temporary, position) in writers {
+            let member = &group.members[position];
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            if self.has_id(&temporary, &member.id)? {
+                fs::rename(&temporary, self.path().join(&member.relative_path))?;
+                log::debug!("rebuilt {} from parity", member.relative_path);
+                rebuilt.push(member.id.clone());
+            } else {
+                log::warn!("rebuilt {} does not match its id", member.relative_path);
+                fs::remove_file(&temporary)?;
+            }
+        }
+        Ok(rebuilt)
+    }
+
+    fn has_id(&self, blob_path: &Path, id: &ItemId) -> Result<bool> {
+        let read_back =
+            BlobReader::open(blob_path, self.config.data_encoding).and_then(|blob| data::copy_and_hash(blob, io::sink()));
+        match read_back {
+            Result::Ok((read_id, _)) => Ok(read_id == *id),
+            Err(e) if blob::is_beyond_correction(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// all parity groups with at least one parity blob left, parity blobs that cannot be read are skipped
+    fn parity_groups(&self) -> Result<Vec<Group>> {
+        let parity_dir = self.parity_dir();
+        if !parity_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut groups = BTreeMap::new();
+        for entry in fs::read_dir(&parity_dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |extension| extension == "tmp") {
+                continue;
+            }
+            let header = match File::open(&path)
+                .map_err(Error::from)
+                .and_then(|file| read_header(&mut BufReader::new(file)))
+            {
+                Result::Ok(header) => header,
+                Err(e) => {
+                    log::warn!("skipping unreadable parity blob {}: {:#}", path.to_string_lossy(), e);
+                    continue;
+                }
+            };
+            let group = groups.entry(header.group.clone()).or_insert_with(|| Group {
+                members: header.members.clone(),
+                parity_blobs: header.parity_blobs,
+                parity_paths: BTreeMap::new(),
+            });
+            group.parity_paths.insert(header.index, path);
+        }
+        Ok(groups.into_values().collect())
+    }
+
+    fn parity_dir(&self) -> PathBuf {
+        self.path().join(PARITY_DIR_NAME)
+    }
+}
+
+/// reads all `readers` side by side in chunks and gives `f` one codeword per byte position in the chunk,
+/// with zeros in place of readers that are `None` or past the end of their length
+fn for_each_chunk<R, F>(readers: &mut [Option<R>], lengths: &[u64], mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&[Vec<u8>], usize) -> Result<()>,
+{
+    let total = lengths.iter().copied().max().unwrap_or(0);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut codewords = vec![vec![0; readers.len()]; CHUNK_SIZE];
+    let mut done = 0;
+    while done < total {
+        let chunk = (total - done).min(CHUNK_SIZE as u64) as usize;
+        for (column, (reader, length)) in readers.iter_mut().zip(lengths).enumerate() {
+            let available = length.saturating_sub(done).min(chunk as u64) as usize;
+            match reader {
+                Some(reader) => reader.read_exact(&mut buffer[..available])?,
+                None => buffer[..available].fill(0),
+            }
+            buffer[available..chunk].fill(0);
+            for (codeword, byte) in codewords.iter_mut().zip(&buffer[..chunk]) {
+                codeword[column] = *byte;
+            }
+        }
+        f(&codewords, chunk)?;
+        done += chunk as u64;
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(writer: &mut W, header: &GroupHeader) -> Result<()> {
+    let header = serde_json::to_vec(header)?;
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(&header)?;
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<GroupHeader> {
+    let mut length = [0; 8];
+    reader.read_exact(&mut length)?;
+    let length = u64::from_le_bytes(length);
+    if length > MAX_HEADER_LENGTH {
+        return Err(anyhow!("parity group header of {} bytes is too long", length));
+    }
+    let mut header = vec![0; length as usize];
+    reader.read_exact(&mut header)?;
+    Ok(serde_json::from_slice(&header)?)
+}
+
+#[cfg(test)]
+mod must {
+    use std::{fs, io::Read, path::Path};
+
+    use super::{ParityReport, ParityScheme};
+    use crate::repository::{check::CheckOptions, Repository};
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn backed_up_repository(repository_path: &Path, sizes: &[u64]) -> Result<(Repository, TestSource)> {
+        let source = TestSource::new()?;
+        for (i, size) in sizes.iter().enumerate() {
+            source.write_random_bytes_to_file(&format!("file{}", i), *size)?;
+        }
+        let secret = "some secret";
+        Repository::init(repository_path, secret)?;
+        let mut repository = Repository::open(repository_path, secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        Ok((repository, source))
+    }
+
+    fn blob_path(repository: &Repository, source: &TestSource, name: &str) -> Result<std::path::PathBuf> {
+        let item = repository.newest_item_by_source_path(&source.file_path(name)?)?.unwrap();
+        Ok(repository.path().join(item.relative_path()))
+    }
+
+    fn contents(repository: &Repository, source: &TestSource, name: &str) -> Result<Vec<u8>> {
+        let item = repository.newest_item_by_source_path(&source.file_path(name)?)?.unwrap();
+        let mut contents = vec![];
+        item.reader()?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn rebuild_lost_blobs_when_checking() -> Result<()> {
+        let repository_path = tempdir()?;
+        let (repository, source) = backed_up_repository(repository_path.path(), &[1000, 70000, 10, 300, 5000])?;
+        let report = repository.add_parity(ParityScheme::new(3, 2)?)?;
+        fs::remove_file(blob_path(&repository, &source, "file1")?)?;
+        fs::write(blob_path(&repository, &source, "file4")?, "something else")?;
+
+        let check = repository.check(CheckOptions { read_data: Some(1.0) })?;
+
+        assert_eq!(report, ParityReport { groups: 2, blobs: 5 });
+        assert!(check.is_healthy(), "{:?}", check.problems);
+        assert_eq!(check.reconstructed.len(), 2);
+        assert_eq!(
+            contents(&repository, &source, "file1")?,
+            fs::read(source.file_path("file1")?)?
+        );
+        assert_eq!(
+            contents(&repository, &source, "file4")?,
+            fs::read(source.file_path("file4")?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_blobs_that_cannot_be_rebuilt() -> Result<()> {
+        let repository_path = tempdir()?;
+        let (repository, source) = backed_up_repository(repository_path.path(), &[100, 200, 300, 400])?;
+        repository.add_parity(ParityScheme::new(4, 1)?)?;
+        fs::remove_file(blob_path(&repository, &source, "file0")?)?;
+        fs::remove_file(blob_path(&repository, &source, "file3")?)?;
+
+        let check = repository.check(CheckOptions::default())?;
+
+        assert!(check.reconstructed.is_empty());
+        assert_eq!(check.problems.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn put_only_blobs_without_parity_into_new_groups() -> Result<()> {
+        let repository_path = tempdir()?;
+        let (mut repository, source) = backed_up_repository(repository_path.path(), &[100, 200])?;
+        let first = repository.add_parity(ParityScheme::default())?;
+        let second = repository.add_parity(ParityScheme::default())?;
+        source.write_random_bytes_to_file("new", 50)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let third = repository.add_parity(ParityScheme::default())?;
+
+        assert_eq!(first, ParityReport { groups: 1, blobs: 2 });
+        assert_eq!(second, ParityReport::default());
+        assert_eq!(third, ParityReport { groups: 1, blobs: 1 });
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_schemes_longer_than_a_codeword() {
+        assert!(ParityScheme::new(0, 1).is_err());
+        assert!(ParityScheme::new(1, 0).is_err());
+        assert!(ParityScheme::new(250, 6).is_err());
+        assert!(ParityScheme::new(250, 5).is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fs, os::unix::prelude::OsStrExt, path::Path};
+
+use anyhow::Result;
+use walkdir::WalkDir;
+
+use super::{ItemId, LockKind, Repository, DATA_DIR_NAME};
+use crate::index::Index;
+
+/// what `Repository::prune` removed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Repository {
+    /// removes files from the data directory that the index does not know of, left behind by interrupted backups.
+    /// Holds an exclusive lock for the whole time, waiting for running backups to finish and keeping new ones out.
+    /// Backups that held on to their lock past its expiry have it broken, and what they save afterwards is ignored
+    pub fn prune(&self) -> Result<PruneReport> {
+        let lock = self.operation_lock(LockKind::Exclusive)?;
+        Index::compact_fencing(
+            self.path(),
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+            lock.token(),
+        )?;
+        let index = Index::load(
+            self.path(),
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+        )?;
+
+        let mut report = PruneReport::default();
+        for entry in WalkDir::new(self.path().join(DATA_DIR_NAME)) {
+            let entry = entry?;
+            if !entry.file_type().is_file() || self.is_known(&index, entry.path())? {
+                continue;
+            }
+            let length = entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            log::debug!("removed {}", entry.path().to_string_lossy());
+            report.files += 1;
+            report.bytes += length;
+        }
+        Ok(report)
+    }
+
+    /// files of the data directory are named after the id of their contents,
+    /// so each is looked up on its own rather than gathering every known path in memory
+    fn is_known(&self, index: &Index, path: &Path) -> Result<bool> {
+        let id = match path.file_name().and_then(|name| hex::decode(name.as_bytes()).ok()) {
+            Some(id) => ItemId::from(&id[..]),
+            None => return Ok(false),
+        };
+        Ok(index
+            .item_by_id(&id)?
+            .map_or(false, |item| self.path().join(item.relative_path()) == path))
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::{fs, path::Path};
+
+    use super::PruneReport;
+    use crate::repository::{check::CheckOptions, LockKind, Repository};
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn remove_only_contents_the_index_does_not_know_of() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("first", "first")?;
+        source.write_text_to_file("second", "second")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let data = repository_path.path().join("data");
+        fs::write(data.join("abcdef"), "left over")?;
+        fs::write(data.join("abcdef.1234.tmp"), "partial")?;
+
+        let report = repository.prune()?;
+
+        assert_eq!(report, PruneReport { files: 2, bytes: 16 });
+        assert!(repository.check(CheckOptions { read_data: Some(1.0) })?.is_healthy());
+        assert_eq!(fs::read_dir(&data)?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn find_running_backups_holding_shared_locks() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+
+        let engine = crate::backup::Engine::new(Path::new("/some/source"), &mut repository)?;
+
+        let locks = Repository::locks(repository_path.path())?;
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].owner.as_ref().unwrap().kind, LockKind::Shared);
+        drop(engine);
+        assert!(Repository::locks(repository_path.path())?.is_empty());
+        Ok(())
+    }
+}
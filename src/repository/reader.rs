@@ -0,0 +1,116 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha512};
+
+use super::{blob::BlobReader, config::DataEncoding, item::Damage, ItemId};
+
+/// reads stored contents of an item.
+/// Contents read from start to end in one go are checked against the item id,
+/// the read that reaches the end fails with `Damage::Corrupted` wrapped in an `io::Error` if they do not match.
+/// Seeking anywhere but the start turns the check off
+pub struct ItemReader {
+    blob: BlobReader,
+    id: ItemId,
+    hasher: Option<Sha512>,
+}
+
+impl ItemReader {
+    pub(crate) fn open(stored_path: &Path, id: &ItemId, encoding: DataEncoding) -> Result<Self> {
+        if !stored_path.exists() {
+            return Err(Damage::Missing.into());
+        }
+        Ok(ItemReader {
+            blob: BlobReader::open(stored_path, encoding)?,
+            id: id.clone(),
+            hasher: Some(Sha512::new()),
+        })
+    }
+}
+
+impl Read for ItemReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.blob.read(buf)?;
+        if read > 0 {
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&buf[..read]);
+            }
+        } else if !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                let id: ItemId = hasher.finalize()[..].into();
+                if id != self.id {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, Damage::Corrupted));
+                }
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Seek for ItemReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.blob.seek(pos)?;
+        self.hasher = if position == 0 { Some(Sha512::new()) } else { None };
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::{
+        fs,
+        io::{self, Read, Seek, SeekFrom},
+    };
+
+    use super::ItemReader;
+    use crate::repository::{
+        blob,
+        config::{Config, DataEncoding},
+        data::calculate_id,
+        item::Damage,
+    };
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_contents_matching_id() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stored");
+        let encoding = Config::new().data_encoding;
+        let (id, _) = blob::write("some contents".as_bytes(), &path, encoding)?;
+        let mut reader = ItemReader::open(&path, &id, encoding)?;
+
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        reader.seek(SeekFrom::Start(5))?;
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest)?;
+
+        assert_eq!(contents, "some contents");
+        assert_eq!(rest, "contents");
+        Ok(())
+    }
+
+    #[test]
+    fn fail_at_the_end_when_contents_do_not_match_id() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stored");
+        fs::write(&path, "some contents")?;
+        let id = calculate_id(&path)?;
+        fs::write(&path, "other contents")?;
+        let mut reader = ItemReader::open(&path, &id, DataEncoding::Plain)?;
+
+        let error = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.into_inner().unwrap().downcast::<Damage>().ok().map(|d| *d),
+            Some(Damage::Corrupted)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use std::{fs, io, path::Path};
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use super::{
+    blob::{self, BlobReader},
+    data,
+    item::Damage,
+    ItemId, Repository,
+};
+use crate::index::{lock::LockKind, Index};
+
+/// what `Repository::scrub` found and fixed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// bytes of the index files fixed by error correction, the index is compacted when there are any
+    pub index_corrections: usize,
+    /// blobs read
+    pub blobs: u64,
+    /// blobs that error correction had to fix, all of them rewritten with the fixed contents
+    pub repaired: Vec<RepairedBlob>,
+    /// blobs that are gone or have more errors than can be corrected
+    pub beyond_repair: Vec<DamagedBlob>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepairedBlob {
+    pub id: ItemId,
+    pub relative_path: String,
+    /// bytes fixed by error correction
+    pub corrections: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamagedBlob {
+    pub id: ItemId,
+    pub relative_path: String,
+    pub damage: Damage,
+}
+
+impl ScrubReport {
+    pub fn is_healthy(&self) -> bool {
+        self.beyond_repair.is_empty()
+    }
+}
+
+/// outcome of reading a single blob through
+enum Scrubbed {
+    Intact { corrections: usize },
+    Damaged(Damage),
+}
+
+impl Repository {
+    /// reads the index and every blob it knows of, checking contents against their ids.
+    /// Whatever error correction had to fix is written back, each blob atomically, so that errors do not pile up
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let _lock = self.operation_lock(LockKind::Shared)?;
+        let mut report = ScrubReport::default();
+        let (index, corrections) = Index::load_counting_corrections(
+            self.path(),
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            &self.memory_budget,
+        )?;
+        report.index_corrections = corrections;
+        if corrections > 0 {
+            self.compact_index()?;
+        }
+
+        for entry in index.items_by_id() {
+            let (id, item) = entry?;
+            let relative_path = item.relative_path().to_string();
+            report.blobs += 1;
+            let blob_path = self.path().join(&relative_path);
+            let scrubbed = if blob_path.is_file() {
+                self.scrub_blob(&blob_path, &id)?
+            } else {
+                Scrubbed::Damaged(Damage::Missing)
+            };
+            match scrubbed {
+                Scrubbed::Intact { corrections: 0 } => {}
+                Scrubbed::Intact { corrections } => {
+                    log::debug!("repaired {} bytes in {}", corrections, relative_path);
+                    report.repaired.push(RepairedBlob {
+                        id,
+                        relative_path,
+                        corrections,
+                    });
+                }
+                Scrubbed::Damaged(damage) => report.beyond_repair.push(DamagedBlob {
+                    id,
+                    relative_path,
+                    damage,
+                }),
+            }
+        }
+        Ok(report)
+    }
+
+    fn scrub_blob(&self, blob_path: &Path, id: &ItemId) -> Result<Scrubbed> {
+        let encoding = self.config.data_encoding;
+        let mut blob = BlobReader::open(blob_path, encoding)?;
+        match data::copy_and_hash(&mut blob, io::sink()) {
+            Result::Ok((read_id, _)) if read_id == *id => {}
+            Result::Ok(_) => return Ok(Scrubbed::Damaged(Damage::Corrupted)),
+            Err(e) if blob::is_beyond_correction(&e) => return Ok(Scrubbed::Damaged(Damage::Corrupted)),
+            Err(e) => return Err(e),
+        }
+        let corrections = blob.corrections();
+        if corrections == 0 {
+            return Ok(Scrubbed::Intact { corrections });
+        }
+
+        let temporary = blob_path.with_file_name(format!("{}.{}.tmp", id, Uuid::new_v4()));
+        let rewritten = BlobReader::open(blob_path, encoding).and_then(|blob| blob::write(blob, &temporary, encoding));
+        match rewritten {
+            Result::Ok((rewritten_id, _)) if rewritten_id == *id => {
+                fs::rename(&temporary, blob_path)?;
+                Ok(Scrubbed::Intact { corrections })
+            }
+            Result::Ok(_) => {
+                fs::remove_file(&temporary)?;
+                Ok(Scrubbed::Damaged(Damage::Corrupted))
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::fs;
+
+    use super::DamagedBlob;
+    use crate::index::Index;
+    use crate::repository::{check::CheckOptions, item::Damage, ItemId, Repository};
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn rewrite_repaired_blobs_and_report_lost_ones() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_random_bytes_to_file("damaged", 1000)?;
+        source.write_random_bytes_to_file("fine", 1000)?;
+        source.write_random_bytes_to_file("missing", 1000)?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let stored = |name: &str| -> Result<(ItemId, String)> {
+            let item = repository.newest_item_by_source_path(&source.file_path(name)?)?.unwrap();
+            Ok((item.id().clone(), item.relative_path().to_string()))
+        };
+        let (damaged_id, damaged_path) = stored("damaged")?;
+        let (missing_id, missing_path) = stored("missing")?;
+        let damaged_blob = repository_path.path().join(&damaged_path);
+        let mut bytes = fs::read(&damaged_blob)?;
+        for i in [0, 1, 300, 900] {
+            bytes[i] = !bytes[i];
+        }
+        fs::write(&damaged_blob, bytes)?;
+        fs::remove_file(repository_path.path().join(&missing_path))?;
+
+        let first = repository.scrub()?;
+        let second = repository.scrub()?;
+
+        assert_eq!(first.blobs, 3);
+        assert_eq!(first.repaired.len(), 1);
+        assert_eq!(first.repaired[0].id, damaged_id);
+        assert_eq!(first.repaired[0].corrections, 4);
+        assert_eq!(
+            first.beyond_repair,
+            vec![DamagedBlob {
+                id: missing_id,
+                relative_path: missing_path,
+                damage: Damage::Missing
+            }]
+        );
+        assert!(second.repaired.is_empty());
+        let check = repository.check(CheckOptions { read_data: Some(1.0) })?;
+        assert_eq!(check.problems.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_corrected_index() -> Result<()> {
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let repository = Repository::open(repository_path.path(), secret)?;
+        let index_path = Index::index_file_path_for_repository_path(repository_path.path())?;
+        let mut index = fs::read(&index_path)?;
+        index[3] = !index[3];
+        fs::write(&index_path, index)?;
+
+        let report = repository.scrub()?;
+
+        assert_eq!(report.index_corrections, 1);
+        assert_eq!(repository.check(CheckOptions::default())?.index_corrections, 0);
+        Ok(())
+    }
+}
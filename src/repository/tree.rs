@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use uuid::Uuid;
+
+use super::{metadata::Metadata, ItemId, Repository};
+use crate::index::{format, Index};
+
+const TREES_DIR_NAME: &str = "trees";
+const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+
+/// a backed up directory as it was at the end of a backup, see `Repository::snapshot`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: Uuid,
+    pub source_path: String,
+    /// tree of the source path itself, or holding just the source path under its name when it is a file
+    pub root: ItemId,
+    pub created: SystemTime,
+    pub writer: String,
+}
+
+/// entries of a directory by name, identified by the hash of its encoding so that
+/// identical directories are stored once and shared between snapshots
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Tree {
+    entries: BTreeMap<String, TreeEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TreeEntry {
+    File { id: ItemId, metadata: Metadata },
+    Directory { tree: ItemId },
+}
+
+/// a file that differs between two snapshots, see `Repository::diff_snapshots`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// relative to the source path of the snapshots
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// directory being put together before it is written as a tree
+#[derive(Default)]
+struct PendingTree {
+    files: BTreeMap<String, TreeEntry>,
+    directories: BTreeMap<String, PendingTree>,
+}
+
+impl Repository {
+    /// records the newest versions of `files` under `source_path` as a snapshot,
+    /// writing only the trees of directories that are not in the repository yet
+    pub fn snapshot(&self, source_path: &Path, files: &[PathBuf]) -> Result<Snapshot> {
+        let mut root = PendingTree::default();
+        for file in files {
+            let item = match self.index.newest_item_by_source_path(file)? {
+                Some(item) => item,
+                None => return Err(anyhow!("{} is not in the repository", file.to_string_lossy())),
+            };
+            let relative = match file.strip_prefix(source_path) {
+                Result::Ok(relative) if relative.as_os_str().is_empty() => file.file_name().map(Path::new),
+                Result::Ok(relative) => Some(relative),
+                Err(_) => None,
+            };
+            if let Some(relative) = relative {
+                let entry = TreeEntry::File {
+                    id: item.id(),
+                    metadata: item.metadata(),
+                };
+                root.add(relative, entry);
+            }
+        }
+        let snapshot = Snapshot {
+            id: Uuid::new_v4(),
+            source_path: source_path.to_string_lossy().to_string(),
+            root: self.write_tree(root)?,
+            created: SystemTime::now(),
+            writer: self.writer.clone(),
+        };
+        let snapshots_dir = self.path().join(SNAPSHOTS_DIR_NAME);
+        let created = snapshot.created.duration_since(UNIX_EPOCH)?.as_nanos();
+        let snapshot_path = snapshots_dir.join(format!("{:020}-{}", created, snapshot.id));
+        Index::write_encrypted(
+            &snapshot_path,
+            &format::encode(&snapshot)?,
+            self.secret.as_bytes(),
+            self.config.index_layout(),
+            None,
+        )?;
+        Ok(snapshot)
+    }
+
+    /// all snapshots, oldest first
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let snapshots_dir = self.path().join(SNAPSHOTS_DIR_NAME);
+        if !snapshots_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut paths = vec![];
+        for entry in fs::read_dir(snapshots_dir)? {
+            let path = entry?.path();
+            // still being written
+            if path.extension().map_or(true, |extension| extension != "tmp") {
+                paths.push(path);
+            }
+        }
+        // names start with the time they were created at
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| {
+                let (decrypted, _) = Index::read_encrypted(path, self.secret.as_bytes(), self.config.index_layout())?;
+                let (snapshot, _) = format::decode::<Snapshot>(&decrypted)
+                    .with_context(|| format!("cannot read snapshot from {}", path.to_string_lossy()))?;
+                Ok(snapshot)
+            })
+            .collect()
+    }
+
+    /// the snapshot whose id starts with `prefix`, which has to tell it apart from all others
+    pub fn find_snapshot(&self, prefix: &str) -> Result<Snapshot> {
+        let mut matching = self
+            .snapshots()?
+            .into_iter()
+            .filter(|snapshot| snapshot.id.to_string().starts_with(prefix));
+        match (matching.next(), matching.next()) {
+            (Some(snapshot), None) => Ok(snapshot),
+            (None, _) => Err(anyhow!("no snapshot with id {}", prefix)),
+            (Some(_), Some(_)) => Err(anyhow!("more than one snapshot with id starting with {}", prefix)),
+        }
+    }
+
+    /// entries of the directory at `path` relative to the source path of the snapshot, by name,
+    /// reading only the trees on the way there
+    pub fn list_snapshot_directory(&self, snapshot: &Snapshot, path: &Path) -> Result<Vec<(String, TreeEntry)>> {
+        let mut tree = self.read_tree(&snapshot.root)?;
+        for component in path.components() {
+            let name = component.as_os_str().to_string_lossy();
+            tree = match tree.entries.get(name.as_ref()) {
+                Some(TreeEntry::Directory { tree }) => self.read_tree(tree)?,
+                Some(TreeEntry::File { .. }) => return Err(anyhow!("{} is a file", path.to_string_lossy())),
+                None => return Err(anyhow!("no {} in snapshot {}", path.to_string_lossy(), snapshot.id)),
+            };
+        }
+        Ok(tree.entries.into_iter().collect())
+    }
+
+    /// files added, removed or changed between `old` and `new`, in path order.
+    /// Directories with the same tree in both are skipped without being read
+    pub fn diff_snapshots(&self, old: &Snapshot, new: &Snapshot) -> Result<Vec<Change>> {
+        let mut changes = vec![];
+        self.diff_trees(Path::new(""), Some(&old.root), Some(&new.root), &mut changes)?;
+        Ok(changes)
+    }
+
+    fn diff_trees(&self, path: &Path, old: Option<&ItemId>, new: Option<&ItemId>, changes: &mut Vec<Change>) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        let old = old.map(|id| self.read_tree(id)).transpose()?.unwrap_or_default();
+        let new = new.map(|id| self.read_tree(id)).transpose()?.unwrap_or_default();
+        let mut names = old.entries.keys().chain(new.entries.keys()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = path.join(name);
+            let subtree = |entry: Option<&TreeEntry>| match entry {
+                Some(TreeEntry::Directory { tree }) => Some(tree.clone()),
+                _ => None,
+            };
+            let (old_entry, new_entry) = (old.entries.get(name), new.entries.get(name));
+            let (old_tree, new_tree) = (subtree(old_entry), subtree(new_entry));
+            let kind = match (old_entry, new_entry) {
+                (Some(TreeEntry::File { .. }), None) => Some(ChangeKind::Removed),
+                (None, Some(TreeEntry::File { .. })) => Some(ChangeKind::Added),
+                (Some(old_file @ TreeEntry::File { .. }), Some(new_file @ TreeEntry::File { .. })) => {
+                    Some(ChangeKind::Modified).filter(|_| old_file != new_file)
+                }
+                (Some(TreeEntry::File { .. }), Some(TreeEntry::Directory { .. })) => Some(ChangeKind::Removed),
+                (Some(TreeEntry::Directory { .. }), Some(TreeEntry::File { .. })) => Some(ChangeKind::Added),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                changes.push(Change {
+                    path: path.clone(),
+                    kind,
+                });
+            }
+            if old_tree.is_some() || new_tree.is_some() {
+                self.diff_trees(&path, old_tree.as_ref(), new_tree.as_ref(), changes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// writes subtrees first, as a tree is identified by the ids of its entries
+    fn write_tree(&self, pending: PendingTree) -> Result<ItemId> {
+        let mut tree = Tree { entries: pending.files };
+        for (name, directory) in pending.directories {
+            let id = self.write_tree(directory)?;
+            tree.entries.insert(name, TreeEntry::Directory { tree: id });
+        }
+        let encoded = format::encode(&tree)?;
+        let id: ItemId = Sha512::digest(&encoded)[..].into();
+        let tree_path = self.tree_path(&id);
+        if !tree_path.exists() {
+            Index::write_encrypted(&tree_path, &encoded, self.secret.as_bytes(), self.config.index_layout(), None)?;
+        }
+        Ok(id)
+    }
+
+    fn read_tree(&self, id: &ItemId) -> Result<Tree> {
+        let tree_path = self.tree_path(id);
+        let (decrypted, _) = Index::read_encrypted(&tree_path, self.secret.as_bytes(), self.config.index_layout())
+            .with_context(|| format!("cannot read tree {}", id))?;
+        let (tree, _) = format::decode::<Tree>(&decrypted)?;
+        Ok(tree)
+    }
+
+    fn tree_path(&self, id: &ItemId) -> PathBuf {
+        self.path().join(TREES_DIR_NAME).join(id.to_string())
+    }
+}
+
+impl PendingTree {
+    fn add(&mut self, relative_path: &Path, entry: TreeEntry) {
+        let mut names = relative_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let file_name = match names.pop() {
+            Some(file_name) => file_name,
+            None => return,
+        };
+        let mut directory = self;
+        for name in names {
+            directory = directory.directories.entry(name).or_default();
+        }
+        directory.files.insert(file_name, entry);
+    }
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "+"),
+            ChangeKind::Removed => write!(f, "-"),
+            ChangeKind::Modified => write!(f, "M"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{Change, ChangeKind, TreeEntry};
+    use crate::repository::Repository;
+    use crate::test::source::TestSource;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn keep_single_file_source_in_its_snapshots() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "a")?;
+        let file_path = source.file_path("a")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(&file_path, &mut repository)?.backup()?;
+        source.write_text_to_file("a", "changed a")?;
+        crate::backup::Engine::new(&file_path, &mut repository)?.backup()?;
+
+        let snapshots = repository.snapshots()?;
+        let listed = repository.list_snapshot_directory(&snapshots[1], Path::new(""))?;
+        let changes = repository.diff_snapshots(&snapshots[0], &snapshots[1])?;
+
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(listed.len(), 1);
+        assert!(matches!(&listed[0], (name, TreeEntry::File { .. }) if name == "a"));
+        assert_eq!(
+            changes,
+            vec![Change {
+                path: PathBuf::from("a"),
+                kind: ChangeKind::Modified
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn share_unchanged_directories_between_snapshots() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("unchanged/a", "a")?;
+        source.write_text_to_file("changed/b", "b")?;
+        source.write_text_to_file("changed/c", "c")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let trees = || -> Result<usize> { Ok(fs::read_dir(repository_path.path().join("trees"))?.count()) };
+        let trees_in_first = trees()?;
+        source.write_text_to_file("changed/b", "changed b")?;
+        source.write_text_to_file("changed/d", "d")?;
+        source.write_text_to_file("changed/e", "e")?;
+        fs::remove_file(source.file_path("changed/c")?)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+
+        let snapshots = repository.snapshots()?;
+        assert_eq!(snapshots.len(), 2);
+        // only the changed directory and the root are new
+        assert_eq!(trees()?, trees_in_first + 2);
+        let unchanged = repository.list_snapshot_directory(&snapshots[0], Path::new(""))?;
+        let unchanged_tree = match &unchanged[1] {
+            (name, TreeEntry::Directory { tree }) if name == "unchanged" => tree.clone(),
+            entry => panic!("unexpected entry {:?}", entry),
+        };
+        // diff does not need trees that are the same in both snapshots
+        fs::remove_file(repository_path.path().join("trees").join(unchanged_tree.to_string()))?;
+
+        let changes = repository.diff_snapshots(&snapshots[0], &snapshots[1])?;
+        let listed = repository.list_snapshot_directory(&snapshots[1], Path::new("changed"))?;
+
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    path: PathBuf::from("changed/b"),
+                    kind: ChangeKind::Modified
+                },
+                Change {
+                    path: PathBuf::from("changed/c"),
+                    kind: ChangeKind::Removed
+                },
+                Change {
+                    path: PathBuf::from("changed/d"),
+                    kind: ChangeKind::Added
+                },
+                Change {
+                    path: PathBuf::from("changed/e"),
+                    kind: ChangeKind::Added
+                },
+            ]
+        );
+        let names = listed.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["b", "d", "e"]);
+        Ok(())
+    }
+}
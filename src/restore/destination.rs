@@ -0,0 +1,197 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Result;
+use anyhow::*;
+
+use crate::repository::item::RepositoryItem;
+
+/// decides where restored files end up, based on their original source paths
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    /// `None` restores files in place, at their original locations
+    root: Option<PathBuf>,
+    strip_prefix: Option<PathBuf>,
+    remaps: Vec<(PathBuf, PathBuf)>,
+}
+
+/// what to do when a file is already present where a restored file should go
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// keep the existing file
+    Never,
+    /// always replace the existing file
+    Always,
+    /// replace the existing file if the backed up one was modified later
+    IfNewer,
+    /// replace the existing file if its contents are different
+    #[default]
+    IfDifferent,
+    /// keep the existing file and restore next to it under a numbered name, unless contents are the same
+    RenameWithSuffix,
+}
+
+const RENAME_SUFFIX: &str = ".restored-";
+
+impl Destination {
+    /// original absolute paths recreated under `root`
+    pub fn under(root: &Path) -> Self {
+        Destination {
+            root: Some(root.to_path_buf()),
+            strip_prefix: None,
+            remaps: vec![],
+        }
+    }
+
+    /// original locations
+    pub fn in_place() -> Self {
+        Destination {
+            root: None,
+            strip_prefix: None,
+            remaps: vec![],
+        }
+    }
+
+    /// drops `prefix` from original paths before recreating them under the root
+    pub fn with_stripped_prefix(mut self, prefix: &Path) -> Self {
+        self.strip_prefix = Some(prefix.to_path_buf());
+        self
+    }
+
+    /// puts everything under `from` into `to` instead, the longest matching `from` wins
+    pub fn with_remap(mut self, from: &Path, to: &Path) -> Self {
+        self.remaps.push((from.to_path_buf(), to.to_path_buf()));
+        self
+    }
+
+    pub fn resolve(&self, original_source_path: &str) -> Result<PathBuf> {
+        let original = Path::new(original_source_path);
+        let remap = self
+            .remaps
+            .iter()
+            .filter(|(from, _)| original.starts_with(from))
+            .max_by_key(|(from, _)| from.components().count());
+        if let Some((from, to)) = remap {
+            return Ok(to.join(original.strip_prefix(from)?));
+        }
+        match &self.root {
+            None => Ok(original.to_path_buf()),
+            Some(root) => {
+                let stripped = match &self.strip_prefix {
+                    Some(prefix) => original.strip_prefix(prefix).unwrap_or(original),
+                    None => original,
+                };
+                Ok(root.join(stripped.strip_prefix("/").unwrap_or(stripped)))
+            }
+        }
+    }
+}
+
+impl OverwritePolicy {
+    /// path to write `item` to when `target_path` is where it should go, `None` if nothing should be written
+    pub fn resolve(&self, item: &RepositoryItem, target_path: &Path) -> Result<Option<PathBuf>> {
+        if !target_path.exists() {
+            return Ok(Some(target_path.to_path_buf()));
+        }
+        let write_to_target = match self {
+            OverwritePolicy::Never => false,
+            OverwritePolicy::Always => true,
+            OverwritePolicy::IfDifferent => !item.is_saved_at(target_path)?,
+            OverwritePolicy::IfNewer => {
+                let existing_modified = target_path.metadata()?.modified()?;
+                item.metadata()
+                    .modified
+                    .map_or(false, |modified| modified > existing_modified)
+            }
+            OverwritePolicy::RenameWithSuffix => return free_path_with_suffix(item, target_path),
+        };
+        Ok(if write_to_target {
+            Some(target_path.to_path_buf())
+        } else {
+            None
+        })
+    }
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(OverwritePolicy::Never),
+            "always" => Ok(OverwritePolicy::Always),
+            "if-newer" => Ok(OverwritePolicy::IfNewer),
+            "if-different" => Ok(OverwritePolicy::IfDifferent),
+            "rename" => Ok(OverwritePolicy::RenameWithSuffix),
+            _ => Err(anyhow!(
+                "unknown overwrite policy '{}', expected one of: never, always, if-newer, if-different, rename",
+                s
+            )),
+        }
+    }
+}
+
+/// first numbered name next to `path` that is not taken, `None` if `path` or any of the numbered files next to it
+/// already has the contents of `item`, so that restoring again does not leave another copy behind
+fn free_path_with_suffix(item: &RepositoryItem, path: &Path) -> Result<Option<PathBuf>> {
+    if item.is_saved_at(path)? {
+        return Ok(None);
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("cannot compute file name for {}", path.to_string_lossy()))?
+        .to_string_lossy();
+    for n in 1u64.. {
+        let candidate = path.with_file_name(format!("{}{}{}", file_name, RENAME_SUFFIX, n));
+        if !candidate.exists() {
+            return Ok(Some(candidate));
+        }
+        if item.is_saved_at(&candidate)? {
+            return Ok(None);
+        }
+    }
+    Err(anyhow!("cannot find a free name for {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod must {
+    use std::path::Path;
+
+    use super::Destination;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn recreate_original_path_under_root() -> Result<()> {
+        let destination = Destination::under(Path::new("/target"));
+
+        assert_eq!(
+            destination.resolve("/home/alice/notes")?,
+            Path::new("/target/home/alice/notes")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strip_prefix_before_recreating_path() -> Result<()> {
+        let destination = Destination::under(Path::new("/target")).with_stripped_prefix(Path::new("/home/alice"));
+
+        assert_eq!(destination.resolve("/home/alice/notes")?, Path::new("/target/notes"));
+        assert_eq!(destination.resolve("/home/bob/notes")?, Path::new("/target/home/bob/notes"));
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_longest_remap() -> Result<()> {
+        let destination = Destination::in_place()
+            .with_remap(Path::new("/home"), Path::new("/srv/homes"))
+            .with_remap(Path::new("/home/alice"), Path::new("/srv/restore"));
+
+        assert_eq!(destination.resolve("/home/alice/notes")?, Path::new("/srv/restore/notes"));
+        assert_eq!(destination.resolve("/home/bob/notes")?, Path::new("/srv/homes/bob/notes"));
+        assert_eq!(destination.resolve("/etc/hosts")?, Path::new("/etc/hosts"));
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::cancellation::{CancellationToken, Cancelled};
+use crate::progress::{Event, NoProgress, Observer};
+use crate::repository::{
+    item::{Damage, RepositoryItem},
+    Repository,
+};
+use crate::version::Version;
+use anyhow::Result;
+use crossbeam_channel::unbounded;
+use destination::{Destination, OverwritePolicy};
+use selector::Selector;
+
+pub mod destination;
+pub mod selector;
+
+/// file that `restore_all` would write, as reported by a dry run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedFile {
+    pub original_source_path: String,
+    pub size: u64,
+    pub version: Version,
+    pub destination: PathBuf,
+}
+
+/// what a best effort restore could not do
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// files written or found already in place
+    pub restored: u64,
+    pub failed: Vec<FailedFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedFile {
+    pub original_source_path: String,
+    pub version: Version,
+    pub problem: Problem,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    Damaged(Damage),
+    /// anything else that stopped the file from being restored, e.g. the destination not being writable
+    Other(String),
+}
+
+impl Report {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+pub struct Engine<'a> {
+    repository: &'a mut Repository,
+    destination: Destination,
+    overwrite_policy: OverwritePolicy,
+    cancellation: CancellationToken,
+    workers: usize,
+    observer: Arc<dyn Observer>,
+    selectors: Vec<Selector>,
+}
+
+impl<'a> Engine<'a> {
+    /// restores files to where `destination` puts them
+    pub fn new(repository: &'a mut Repository, destination: Destination) -> Result<Self> {
+        Ok(Engine {
+            repository,
+            destination,
+            overwrite_policy: OverwritePolicy::default(),
+            cancellation: CancellationToken::new(),
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            observer: Arc::new(NoProgress),
+            selectors: vec![],
+        })
+    }
+
+    /// number of threads restoring files at the same time
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub fn with_overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = overwrite_policy;
+        self
+    }
+
+    /// restricts `restore_all` to files matching any of the selectors, all files are restored when there are none
+    pub fn with_selectors(mut self, selectors: Vec<Selector>) -> Self {
+        self.selectors = selectors;
+        self
+    }
+
+    /// lists what `restore_all` would write and where, files the overwrite policy leaves alone are not listed.
+    /// Reads files already present to compare them, but does not write anything
+    pub fn plan(&self) -> Result<Vec<PlannedFile>> {
+        let mut planned = vec![];
+        for item in self.selected_items() {
+            let item = item?;
+            let target_path = self.destination.resolve(item.original_source_path())?;
+            if let Some(write_to) = self.overwrite_policy.resolve(&item, &target_path)? {
+                planned.push(PlannedFile {
+                    original_source_path: item.original_source_path().to_string(),
+                    size: item.size()?,
+                    version: *item.version(),
+                    destination: write_to,
+                });
+            }
+        }
+        planned.sort_by(|a, b| a.destination.cmp(&b.destination));
+        Ok(planned)
+    }
+
+    fn selected_items(&self) -> impl Iterator<Item = Result<RepositoryItem>> + '_ {
+        self.repository.newest_items().filter(move |item| {
+            item.as_ref()
+                .map_or(true, |item| selector::selected(&self.selectors, item.original_source_path()))
+        })
+    }
+
+    /// restores newest versions of all selected items on a pool of threads, fails with `Cancelled` if cancelled midway.
+    /// Files that already have the right contents are left alone, so an interrupted restore can simply be run again.
+    /// Stops at the first file that cannot be restored
+    pub fn restore_all(&mut self) -> Result<()> {
+        self.run(false).map(|_| ())
+    }
+
+    /// like `restore_all` but keeps going past files that cannot be restored and reports them instead,
+    /// still fails with `Cancelled` if cancelled midway
+    pub fn restore_all_best_effort(&mut self) -> Result<Report> {
+        self.run(true)
+    }
+
+    fn run(&mut self, best_effort: bool) -> Result<Report> {
+        let (sender, receiver) = unbounded();
+        let mut files = 0;
+        let mut bytes = 0;
+        for item in self.selected_items() {
+            let item = item?;
+            files += 1;
+            bytes += item.metadata().size;
+            sender.send(item)?;
+        }
+        drop(sender);
+        self.observer.on_event(&Event::ScanFinished { files, bytes });
+
+        let first_error = Mutex::new(None);
+        let report = Mutex::new(Report::default());
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                let receiver = receiver.clone();
+                let first_error = &first_error;
+                let report = &report;
+                let engine = &*self;
+                scope.spawn(move || {
+                    for item in receiver {
+                        if first_error.lock().unwrap().is_some() {
+                            break;
+                        }
+                        if engine.cancellation.is_cancelled() {
+                            first_error.lock().unwrap().get_or_insert(Cancelled.into());
+                            break;
+                        }
+                        match engine.restore(&item) {
+                            Result::Ok(()) => report.lock().unwrap().restored += 1,
+                            Err(e) => {
+                                engine.observer.on_event(&Event::Error {
+                                    path: item.original_source_path().to_string(),
+                                    message: format!("{:#}", e),
+                                });
+                                if !best_effort {
+                                    first_error.lock().unwrap().get_or_insert(e);
+                                    break;
+                                }
+                                report.lock().unwrap().failed.push(FailedFile {
+                                    original_source_path: item.original_source_path().to_string(),
+                                    version: *item.version(),
+                                    problem: Problem::from(&e),
+                                });
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        if let Some(e) = first_error.into_inner().unwrap() {
+            return Err(e);
+        }
+
+        self.repository.save_index()?;
+        let mut report = report.into_inner().unwrap();
+        report
+            .failed
+            .sort_by(|a, b| a.original_source_path.cmp(&b.original_source_path));
+        Ok(report)
+    }
+
+    pub fn restore(&self, item: &RepositoryItem) -> Result<()> {
+        let path = item.original_source_path().to_string();
+        self.observer.on_event(&Event::FileStarted { path: path.clone() });
+        let target_path = self.destination.resolve(&path)?;
+        match self.overwrite_policy.resolve(item, &target_path)? {
+            Some(write_to) => item.save_to_file(&write_to)?,
+            None => log::debug!("leaving existing {} in place", target_path.to_string_lossy()),
+        }
+        let bytes = item.metadata().size;
+        self.observer.on_event(&Event::BytesProcessed { bytes });
+        self.observer.on_event(&Event::FileFinished { path, bytes });
+        Ok(())
+    }
+}
+
+impl From<&anyhow::Error> for Problem {
+    fn from(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<Damage>() {
+            Some(damage) => Problem::Damaged(*damage),
+            None => Problem::Other(format!("{:#}", error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use std::{fs, path::PathBuf, thread, time::Duration};
+
+    use super::{
+        destination::{Destination, OverwritePolicy},
+        selector::Selector,
+        Engine, Problem,
+    };
+    use crate::cancellation::{CancellationToken, Cancelled};
+    use crate::repository::item::Damage;
+    use crate::repository::Repository;
+    use crate::test::{assertions::in_memory::get_sorted_files_recursively, source::TestSource};
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn skip_files_that_are_already_restored() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "a")?;
+        source.write_text_to_file("b", "b")?;
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        Engine::new(&mut repository, Destination::under(restore_target.path()))?.restore_all()?;
+
+        fs::remove_dir_all(repository_path.path().join("data"))?;
+
+        Engine::new(&mut repository, Destination::under(restore_target.path()))?
+            .with_workers(2)
+            .restore_all()?;
+        Ok(())
+    }
+
+    #[test]
+    fn restore_one_of_the_files_remapped_onto_the_same_target() -> Result<()> {
+        let source = TestSource::new()?;
+        for i in 0..8 {
+            source.write_text_to_file(&format!("{}/x", i), &i.to_string().repeat(100_000))?;
+        }
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let mut destination = Destination::under(restore_target.path());
+        for i in 0..8 {
+            destination = destination.with_remap(&source.file_path(&i.to_string())?, restore_target.path());
+        }
+
+        Engine::new(&mut repository, destination)?
+            .with_workers(8)
+            .with_overwrite_policy(OverwritePolicy::Always)
+            .restore_all()?;
+
+        assert_eq!(
+            get_sorted_files_recursively(restore_target.path())?,
+            vec![restore_target.path().join("x")]
+        );
+        let restored = fs::read_to_string(restore_target.path().join("x"))?;
+        assert!((0..8).any(|i| restored == i.to_string().repeat(100_000)));
+        Ok(())
+    }
+
+    #[test]
+    fn restore_only_selected_files() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("nginx/nginx.conf", "a")?;
+        source.write_text_to_file("nginx/readme", "b")?;
+        source.write_text_to_file("php.conf", "c")?;
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let selectors = vec![Selector::prefix(&source.file_path("nginx")?), Selector::glob("*.conf")?];
+
+        Engine::new(&mut repository, Destination::under(restore_target.path()))?
+            .with_selectors(vec![Selector::glob("*.conf")?])
+            .restore_all()?;
+
+        let restored = get_sorted_files_recursively(restore_target.path())?;
+        let restored_names = restored
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(restored_names, vec!["nginx.conf", "php.conf"]);
+        let planned = Engine::new(&mut repository, Destination::under(restore_target.path()))?
+            .with_selectors(selectors)
+            .plan()?;
+        let planned_names = planned
+            .iter()
+            .map(|planned| planned.destination.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(planned_names, vec!["readme"]);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_restore_without_writing_anything() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "some contents")?;
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+
+        let planned = Engine::new(&mut repository, Destination::under(restore_target.path()))?.plan()?;
+
+        let source_path = source.file_path("a")?;
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].original_source_path, source_path.to_string_lossy());
+        assert_eq!(planned[0].size, 13);
+        assert_eq!(
+            planned[0].destination,
+            restore_target.path().join(source_path.strip_prefix("/")?)
+        );
+        assert_eq!(restore_target.path().read_dir()?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_only_files_the_overwrite_policy_would_write() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "backed up")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        source.write_text_to_file("a", "changed")?;
+        let mut plan_with = |policy| -> Result<Vec<PathBuf>> {
+            let planned = Engine::new(&mut repository, Destination::in_place())?
+                .with_overwrite_policy(policy)
+                .plan()?;
+            Ok(planned.into_iter().map(|planned| planned.destination).collect())
+        };
+
+        assert_eq!(plan_with(OverwritePolicy::Never)?, Vec::<PathBuf>::new());
+        assert_eq!(plan_with(OverwritePolicy::Always)?, vec![source.file_path("a")?]);
+        assert_eq!(
+            plan_with(OverwritePolicy::RenameWithSuffix)?,
+            vec![source.file_path("a.restored-1")?]
+        );
+        assert_eq!(fs::read_to_string(source.file_path("a")?)?, "changed");
+        Ok(())
+    }
+
+    #[test]
+    fn rename_only_once_when_restoring_again() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "backed up")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        source.write_text_to_file("a", "changed")?;
+
+        for _ in 0..2 {
+            Engine::new(&mut repository, Destination::in_place())?
+                .with_overwrite_policy(OverwritePolicy::RenameWithSuffix)
+                .restore_all()?;
+        }
+
+        let restored = get_sorted_files_recursively(source.path())?;
+        assert_eq!(restored, vec![source.file_path("a")?, source.file_path("a.restored-1")?]);
+        assert_eq!(fs::read_to_string(source.file_path("a.restored-1")?)?, "backed up");
+        Ok(())
+    }
+
+    #[test]
+    fn restore_in_place_according_to_overwrite_policy() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "backed up")?;
+        let repository_path = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        thread::sleep(Duration::from_millis(10));
+        source.write_text_to_file("a", "changed")?;
+        let restore_with = |repository: &mut Repository, policy| -> Result<()> {
+            Engine::new(repository, Destination::in_place())?
+                .with_overwrite_policy(policy)
+                .restore_all()
+        };
+
+        restore_with(&mut repository, OverwritePolicy::Never)?;
+        restore_with(&mut repository, OverwritePolicy::IfNewer)?;
+        assert_eq!(fs::read_to_string(source.file_path("a")?)?, "changed");
+
+        restore_with(&mut repository, OverwritePolicy::RenameWithSuffix)?;
+        assert_eq!(fs::read_to_string(source.file_path("a")?)?, "changed");
+        assert_eq!(fs::read_to_string(source.file_path("a.restored-1")?)?, "backed up");
+
+        restore_with(&mut repository, OverwritePolicy::Always)?;
+        assert_eq!(fs::read_to_string(source.file_path("a")?)?, "backed up");
+        Ok(())
+    }
+
+    #[test]
+    fn report_missing_and_corrupted_files_when_restoring_best_effort() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("corrupted", "corrupted")?;
+        source.write_text_to_file("fine", "fine")?;
+        source.write_text_to_file("missing", "missing")?;
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let stored_path = |name: &str| -> Result<PathBuf> {
+            let item = repository.newest_item_by_source_path(&source.file_path(name)?)?.unwrap();
+            Ok(repository_path.path().join(item.relative_path()))
+        };
+        fs::write(stored_path("corrupted")?, "something else")?;
+        fs::remove_file(stored_path("missing")?)?;
+
+        let strict = Engine::new(&mut repository, Destination::under(restore_target.path()))?.restore_all();
+        let report = Engine::new(&mut repository, Destination::under(restore_target.path()))?.restore_all_best_effort()?;
+
+        assert!(strict.is_err());
+        let problems = report.failed.iter().map(|failed| failed.problem.clone()).collect::<Vec<_>>();
+        assert_eq!(report.restored, 1);
+        assert_eq!(
+            problems,
+            vec![Problem::Damaged(Damage::Corrupted), Problem::Damaged(Damage::Missing)]
+        );
+        let restored = get_sorted_files_recursively(restore_target.path())?;
+        assert_eq!(restored.len(), 1);
+        assert!(restored[0].ends_with("fine"));
+        Ok(())
+    }
+
+    #[test]
+    fn not_restore_anything_when_cancelled() -> Result<()> {
+        let source = TestSource::new()?;
+        source.write_text_to_file("a", "a")?;
+        let repository_path = tempdir()?;
+        let restore_target = tempdir()?;
+        let secret = "some secret";
+        Repository::init(repository_path.path(), secret)?;
+        let mut repository = Repository::open(repository_path.path(), secret)?;
+        crate::backup::Engine::new(source.path(), &mut repository)?.backup()?;
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let result = Engine::new(&mut repository, Destination::under(restore_target.path()))?
+            .with_cancellation(cancellation)
+            .restore_all();
+
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert_eq!(restore_target.path().read_dir()?.count(), 0);
+        Ok(())
+    }
+}
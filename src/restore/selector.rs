@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+
+/// picks which backed up files get restored, matched against their original source paths
+#[derive(Clone, Debug)]
+pub enum Selector {
+    /// the path itself and everything under it, compared component by component
+    Prefix(PathBuf),
+    /// shell-style pattern, matched against the file name if it has no `/` in it
+    /// and against the whole path otherwise, where only `**` matches across `/`
+    Glob(Pattern),
+    /// regular expression found anywhere in the path
+    Regex(Regex),
+}
+
+impl Selector {
+    pub fn prefix(path: &Path) -> Self {
+        Selector::Prefix(path.to_path_buf())
+    }
+
+    pub fn glob(pattern: &str) -> Result<Self> {
+        Ok(Selector::Glob(Pattern::new(pattern)?))
+    }
+
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(Selector::Regex(Regex::new(pattern)?))
+    }
+
+    pub fn matches(&self, original_source_path: &str) -> bool {
+        let path = Path::new(original_source_path);
+        match self {
+            Selector::Prefix(prefix) => path.starts_with(prefix),
+            Selector::Glob(pattern) if pattern.as_str().contains('/') => pattern.matches_path_with(
+                path,
+                MatchOptions {
+                    require_literal_separator: true,
+                    ..Default::default()
+                },
+            ),
+            Selector::Glob(pattern) => path
+                .file_name()
+                .map_or(false, |name| pattern.matches(&name.to_string_lossy())),
+            Selector::Regex(regex) => regex.is_match(original_source_path),
+        }
+    }
+}
+
+/// whether a file is picked by any of the selectors, everything is picked when there are none
+pub fn selected(selectors: &[Selector], original_source_path: &str) -> bool {
+    selectors.is_empty() || selectors.iter().any(|selector| selector.matches(original_source_path))
+}
+
+#[cfg(test)]
+mod must {
+    use std::path::Path;
+
+    use super::Selector;
+    use anyhow::Result;
+
+    #[test]
+    fn match_prefix_by_whole_path_components() {
+        let selector = Selector::prefix(Path::new("/etc/nginx"));
+
+        assert!(selector.matches("/etc/nginx"));
+        assert!(selector.matches("/etc/nginx/sites/default"));
+        assert!(!selector.matches("/etc/nginx2/nginx.conf"));
+    }
+
+    #[test]
+    fn match_glob_without_separator_against_file_name() -> Result<()> {
+        let selector = Selector::glob("*.conf")?;
+
+        assert!(selector.matches("/etc/nginx/nginx.conf"));
+        assert!(!selector.matches("/etc/nginx.conf/readme"));
+        Ok(())
+    }
+
+    #[test]
+    fn match_glob_with_separator_against_whole_path() -> Result<()> {
+        let selector = Selector::glob("/etc/*/nginx.conf")?;
+
+        assert!(selector.matches("/etc/nginx/nginx.conf"));
+        assert!(!selector.matches("/srv/nginx/nginx.conf"));
+        Ok(())
+    }
+
+    #[test]
+    fn match_glob_wildcard_within_single_path_component() -> Result<()> {
+        let selector = Selector::glob("/home/*/x")?;
+        let recursive = Selector::glob("/home/**/x")?;
+
+        assert!(selector.matches("/home/a/x"));
+        assert!(!selector.matches("/home/a/b/x"));
+        assert!(recursive.matches("/home/a/b/x"));
+        Ok(())
+    }
+
+    #[test]
+    fn match_regex_anywhere_in_path() -> Result<()> {
+        let selector = Selector::regex(r"\.(conf|ini)$")?;
+
+        assert!(selector.matches("/etc/php/php.ini"));
+        assert!(!selector.matches("/etc/php/php.ini.bak"));
+        Ok(())
+    }
+}
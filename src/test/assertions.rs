@@ -8,7 +8,7 @@ pub mod in_memory {
     use crate::{
         backup,
         repository::{item::RepositoryItem, ItemId, Repository},
-        restore,
+        restore::{self, destination::Destination},
         test::source::TestSource,
     };
     use anyhow::Result;
@@ -29,7 +29,7 @@ pub mod in_memory {
         {
             let mut restore_repository = Repository::open(repository_path.path(), secret)?;
 
-            let mut restore_engine = restore::Engine::new(&mut restore_repository, restore_target.path())?;
+            let mut restore_engine = restore::Engine::new(&mut restore_repository, Destination::under(restore_target.path()))?;
             restore_engine.restore_all()?;
         }
 
@@ -46,11 +46,11 @@ pub mod in_memory {
         let mut restore_repository = Repository::open(repository_path, secret)?;
         let item = restore_repository.newest_item_by_source_path(source_file_full_path)?;
         let restore_target = tempdir()?;
-        let restore_engine = restore::Engine::new(&mut restore_repository, restore_target.path())?;
+        let restore_engine = restore::Engine::new(&mut restore_repository, Destination::under(restore_target.path()))?;
 
         restore_engine.restore(&item.unwrap())?;
         let source_file_relative_path = Path::new(source_file_full_path).strip_prefix("/")?;
-        let restored_file_path = restore_target.path().join(&source_file_relative_path);
+        let restored_file_path = restore_target.path().join(source_file_relative_path);
         assert_target_file_contents(&restored_file_path, contents)
     }
 
@@ -64,10 +64,10 @@ pub mod in_memory {
         let mut restore_repository = Repository::open(repository_path, secret)?;
         let old_item = restore_repository.item_by_id(old_id)?;
         let restore_target = tempdir()?;
-        let restore_engine = restore::Engine::new(&mut restore_repository, restore_target.path())?;
+        let restore_engine = restore::Engine::new(&mut restore_repository, Destination::under(restore_target.path()))?;
         restore_engine.restore(&old_item.unwrap())?;
         let source_file_relative_path = Path::new(source_file_full_path).strip_prefix("/")?;
-        let restored_file_path = restore_target.path().join(&source_file_relative_path);
+        let restored_file_path = restore_target.path().join(source_file_relative_path);
         assert_target_file_contents(&restored_file_path, old_contents)
     }
 
@@ -84,7 +84,7 @@ pub mod in_memory {
     pub fn restore_all_from_reloaded_repository(repository_path: &Path, secret: &str, restore_target: &Path) -> Result<()> {
         {
             let mut restore_repository = Repository::open(repository_path, secret)?;
-            let mut restore_engine = restore::Engine::new(&mut restore_repository, restore_target)?;
+            let mut restore_engine = restore::Engine::new(&mut restore_repository, Destination::under(restore_target))?;
             restore_engine.restore_all()?;
             Ok(())
         }
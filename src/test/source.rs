@@ -19,6 +19,9 @@ impl TestSource {
 
     pub fn write_bytes_to_file(&self, filename: &str, bytes: &[u8]) -> Result<()> {
         let path = self.file_path(filename)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
         let mut file = File::create(path)?;
         file.write_all(bytes)?;
         Ok(())
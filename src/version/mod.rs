@@ -1,9 +1,26 @@
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq, Serialize, Deserialize, Hash)]
 pub struct Version(u128);
 
+/// how many versions of a path each writer made, tells versions made one after another
+/// from versions made by writers that did not know of each other
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+/// how two versions relate to each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Causality {
+    Before,
+    Same,
+    After,
+    /// neither knew of the other when made
+    Concurrent,
+}
+
 impl Version {
     pub fn next(&self) -> Self {
         Version(self.0 + 1)
@@ -21,3 +38,56 @@ impl Display for Version {
         write!(f, "{}", self.0)
     }
 }
+
+impl VersionVector {
+    /// vector of a version made by `writer` after all versions of the given vectors
+    pub fn next<'a>(writer: &str, known: impl IntoIterator<Item = &'a VersionVector>) -> Self {
+        let mut next = VersionVector::default();
+        for vector in known {
+            for (other_writer, count) in &vector.0 {
+                let entry = next.0.entry(other_writer.clone()).or_insert(0);
+                *entry = (*entry).max(*count);
+            }
+        }
+        *next.0.entry(writer.to_string()).or_insert(0) += 1;
+        next
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut causality = Causality::Same;
+        let writers = self.0.keys().chain(other.0.keys());
+        for writer in writers {
+            let mine = self.0.get(writer).copied().unwrap_or(0);
+            let theirs = other.0.get(writer).copied().unwrap_or(0);
+            causality = match (mine.cmp(&theirs), causality) {
+                (Ordering::Equal, causality) => causality,
+                (Ordering::Less, Causality::Same | Causality::Before) => Causality::Before,
+                (Ordering::Greater, Causality::Same | Causality::After) => Causality::After,
+                _ => return Causality::Concurrent,
+            };
+        }
+        causality
+    }
+}
+
+#[cfg(test)]
+mod must {
+    use super::{Causality, VersionVector};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn tell_versions_made_in_turn_from_concurrent_ones() {
+        let first = VersionVector::next("a", []);
+        let second = VersionVector::next("b", [&first]);
+        let concurrent = VersionVector::next("a", [&first]);
+        let merged = VersionVector::next("b", [&second, &concurrent]);
+
+        assert_eq!(first.compare(&second), Causality::Before);
+        assert_eq!(second.compare(&first), Causality::After);
+        assert_eq!(second.compare(&second.clone()), Causality::Same);
+        assert_eq!(second.compare(&concurrent), Causality::Concurrent);
+        assert_eq!(merged.compare(&second), Causality::After);
+        assert_eq!(merged.compare(&concurrent), Causality::After);
+        assert_eq!(VersionVector::default().compare(&first), Causality::Before);
+    }
+}
@@ -7,7 +7,7 @@ mod must {
 
     use anyhow::Result;
     use bakare::test::source::TestSource;
-    use bakare::{backup, restore};
+    use bakare::{backup, restore, restore::destination::Destination};
     use bakare::{repository::Repository, test::assertions::in_memory::*};
     use nix::unistd::{fork, ForkResult};
     use nix::{
@@ -22,7 +22,7 @@ mod must {
 
         let dir = tempdir()?;
         let repository_path = dir.path();
-        let repository_path = repository_path.join(&format!("repository-{}", getpid()));
+        let repository_path = repository_path.join(format!("repository-{}", getpid()));
         let secret = "some secret";
         Repository::init(&repository_path, secret)?;
 
@@ -110,7 +110,7 @@ mod must {
 
     fn restore_all(repository_path: &Path, secret: &str, restore_target: &Path) -> Result<Vec<PathBuf>> {
         let mut restore_repository = Repository::open(repository_path, secret)?;
-        let mut restore_engine = restore::Engine::new(&mut restore_repository, restore_target)?;
+        let mut restore_engine = restore::Engine::new(&mut restore_repository, Destination::under(restore_target))?;
         restore_engine.restore_all()?;
         get_sorted_files_recursively(restore_target)
     }
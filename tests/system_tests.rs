@@ -4,8 +4,22 @@ mod must {
     use std::io::Read;
 
     use bakare::backup;
+    #[cfg(feature = "failpoints")]
+    use bakare::repository::{check::CheckOptions, LockKind, LockLost};
     use bakare::test::assertions::in_memory::*;
     use bakare::{repository::Repository, test::source::TestSource};
+    #[cfg(feature = "failpoints")]
+    use nix::{
+        sys::{
+            signal::{kill, Signal},
+            wait::{waitpid, WaitStatus},
+        },
+        unistd::{fork, ForkResult},
+    };
+    #[cfg(feature = "failpoints")]
+    use std::{process, thread, time::Duration};
+    #[cfg(feature = "failpoints")]
+    use two_rusty_forks::rusty_fork_test;
 
     use anyhow::Result;
     use proptest::prelude::*;
@@ -23,6 +37,16 @@ mod must {
         assert_same_after_restore(source.path())
     }
 
+    #[test]
+    fn restore_files_in_subdirectories() -> Result<()> {
+        let source = TestSource::new().unwrap();
+
+        source.write_text_to_file("first", "some contents").unwrap();
+        source.write_text_to_file("directory/second", "some other contents").unwrap();
+
+        assert_same_after_restore(source.path())
+    }
+
     #[test]
     fn restore_files_after_reopening_repository() -> Result<()> {
         let source = TestSource::new()?;
@@ -189,5 +213,97 @@ mod must {
         }
     }
     // TODO: encryption
-    // TODO: resume from sleep while backup in progress
+
+    #[cfg(feature = "failpoints")]
+    rusty_fork_test! {
+        #[test]
+        fn not_let_compaction_resumed_from_sleep_overwrite_newer_index() {
+            let dir = tempdir().unwrap();
+            let repository_path = dir.path().to_path_buf();
+            let secret = "some secret";
+            Repository::init(&repository_path, secret).unwrap();
+            let suspended_source = TestSource::new().unwrap();
+            suspended_source.write_text_to_file("suspended", "some contents").unwrap();
+            let mut repository = Repository::open(&repository_path, secret).unwrap();
+            backup::Engine::new(suspended_source.path(), &mut repository).unwrap().backup().unwrap();
+            fail::cfg("compact-index-before-write", "1*pause").unwrap();
+
+            let suspended = {
+                let repository_path = repository_path.clone();
+                thread::spawn(move || -> Result<usize> { Repository::open(&repository_path, secret)?.compact_index() })
+            };
+            // a suspended process would stop sending heartbeats and have its lock broken as stale after a while,
+            // the heartbeat thread of this one keeps running, so its index lock is broken by hand instead
+            let index_lock = loop {
+                let locks = Repository::locks(&repository_path).unwrap();
+                let exclusive = locks
+                    .into_iter()
+                    .find(|lock| lock.owner.as_ref().map_or(false, |owner| owner.kind == LockKind::Exclusive));
+                if let Some(lock) = exclusive {
+                    break lock;
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            index_lock.remove().unwrap();
+            let newer_source = TestSource::new().unwrap();
+            newer_source.write_text_to_file("newer", "some other contents").unwrap();
+            let mut repository = Repository::open(&repository_path, secret).unwrap();
+            backup::Engine::new(newer_source.path(), &mut repository).unwrap().backup().unwrap();
+            repository.compact_index().unwrap();
+
+            fail::remove("compact-index-before-write");
+            let resumed = suspended.join().unwrap();
+
+            assert!(resumed.unwrap_err().downcast_ref::<LockLost>().is_some());
+            let repository = Repository::open(&repository_path, secret).unwrap();
+            let newer = repository.newest_item_by_source_path(&newer_source.file_path("newer").unwrap()).unwrap();
+            assert!(newer.is_some());
+            let suspended = repository.newest_item_by_source_path(&suspended_source.file_path("suspended").unwrap()).unwrap();
+            assert!(suspended.is_some());
+            assert!(repository.check(CheckOptions::default()).unwrap().is_healthy());
+        }
+
+        #[test]
+        fn not_let_backup_resumed_from_sleep_save_contents_pruned_in_the_meantime() {
+            let dir = tempdir().unwrap();
+            let repository_path = dir.path().to_path_buf();
+            let secret = "some secret";
+            Repository::init(&repository_path, secret).unwrap();
+            let source = TestSource::new().unwrap();
+            source.write_text_to_file("suspended", "some contents").unwrap();
+
+            let child = match unsafe { fork() }.unwrap() {
+                ForkResult::Child => {
+                    // between storing the file and saving the index for long enough to be stopped there
+                    fail::cfg("backup-after-store", "1*sleep(2000)").unwrap();
+                    let backup = Repository::open(&repository_path, secret)
+                        .and_then(|mut repository| backup::Engine::new(source.path(), &mut repository)?.backup());
+                    let lost = backup.map_or_else(|e| e.downcast_ref::<LockLost>().is_some(), |_| false);
+                    process::exit(if lost { 0 } else { 1 });
+                }
+                ForkResult::Parent { child } => child,
+            };
+            while !WalkDir::new(repository_path.join("data"))
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_type().is_file() && entry.path().extension().map_or(true, |extension| extension != "tmp"))
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            // a stopped process sends no heartbeats, its operation lock expires like that of a suspended one,
+            // only sooner so that the test does not have to wait for the whole expiry
+            kill(child, Signal::SIGSTOP).unwrap();
+            fail::cfg("lock-heartbeat-expiry", "return(2)").unwrap();
+            let pruned = Repository::open(&repository_path, secret).unwrap().prune().unwrap();
+            kill(child, Signal::SIGCONT).unwrap();
+            let resumed = waitpid(child, None).unwrap();
+
+            assert_eq!(pruned.files, 1);
+            assert_eq!(resumed, WaitStatus::Exited(child, 0));
+            let repository = Repository::open(&repository_path, secret).unwrap();
+            let suspended = repository.newest_item_by_source_path(&source.file_path("suspended").unwrap()).unwrap();
+            assert!(suspended.is_none());
+            assert!(repository.check(CheckOptions::default()).unwrap().is_healthy());
+        }
+    }
 }